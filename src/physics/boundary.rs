@@ -0,0 +1,198 @@
+use std::fmt;
+use crate::physics::*;
+
+//  What happens to heat at one face of the simulation volume.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BoundaryCondition {
+    //  no heat crosses the boundary.
+    Insulated,
+    //  Dirichlet, the boundary is held at a temperature.
+    FixedTemperature(Temperature),
+    //  Neumann, a constant heat flux into the volume, negative flows out.
+    FixedFlux(HeatFlux),
+    //  heat exchanged with a fluid at the ambient temperature.
+    Convective { coefficient: HeatTransferCoefficient, ambient: Temperature },
+    //  the face wraps around to the opposite face, must be set on both faces of an axis.
+    Periodic,
+}
+
+//  Linear form of a non periodic boundary for one voxel,
+//  heat in = conductance * (temperature - voxel temperature) + flux.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BoundaryTerm {
+    pub conductance: Conductance,
+    pub temperature: Temperature,
+    pub flux: HeatTransferRate,
+}
+
+impl BoundaryTerm {
+    pub fn heat(&self, voxel_temperature: Temperature) -> HeatTransferRate {
+        self.conductance * (self.temperature - voxel_temperature) + self.flux
+    }
+}
+
+impl BoundaryCondition {
+    //  The boundary term for a voxel of this material touching the face.
+    //  The voxel center is half a voxel from the face, which is its thermal_resistance.
+    pub fn term(&self, mat: &VoxelMaterial, length: Length) -> BoundaryTerm {
+        if mat.mass == 0.0 {
+            return BoundaryTerm::default();
+        }
+        let area = length * length;
+        match *self {
+            BoundaryCondition::Insulated | BoundaryCondition::Periodic => BoundaryTerm::default(),
+            BoundaryCondition::FixedTemperature(temperature) => BoundaryTerm {
+                conductance: 1.0 / mat.thermal_resistance,
+                temperature,
+                flux: 0.0,
+            },
+            BoundaryCondition::FixedFlux(flux) => BoundaryTerm {
+                conductance: 0.0,
+                temperature: 0.0,
+                flux: flux * area,
+            },
+            BoundaryCondition::Convective { coefficient, ambient } => BoundaryTerm {
+                conductance: 1.0 / (mat.thermal_resistance + 1.0 / (coefficient * area)),
+                temperature: ambient,
+                flux: 0.0,
+            },
+        }
+    }
+}
+
+//  Periodic on one face of an axis but not the other, which would wrap heat one way only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnpairedPeriodic {
+    //  0 = x, 1 = y, 2 = z
+    pub axis: usize,
+}
+
+impl fmt::Display for UnpairedPeriodic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "periodic boundaries must be set on both {} faces", ["x", "y", "z"][self.axis])
+    }
+}
+
+impl std::error::Error for UnpairedPeriodic {}
+
+//  One BoundaryCondition for each of the six faces of a volume.
+//  Periodic faces always come in pairs, which every way of building one checks,
+//  so the solvers can rely on it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundaryConditions {
+    faces: [BoundaryCondition; 6],
+}
+
+impl Default for BoundaryConditions {
+    fn default() -> Self {
+        BoundaryConditions::uniform(BoundaryCondition::Insulated)
+    }
+}
+
+impl BoundaryConditions {
+    //  Faces in Face::ALL order.
+    pub fn new(faces: [BoundaryCondition; 6]) -> Result<Self, UnpairedPeriodic> {
+        for axis in 0 .. 3 {
+            let negative = faces[axis * 2] == BoundaryCondition::Periodic;
+            let positive = faces[axis * 2 + 1] == BoundaryCondition::Periodic;
+            if negative != positive {
+                return Err(UnpairedPeriodic { axis });
+            }
+        }
+        Ok(BoundaryConditions { faces })
+    }
+
+    pub fn uniform(condition: BoundaryCondition) -> Self {
+        BoundaryConditions { faces: [condition; 6] }
+    }
+
+    pub fn faces(&self) -> [BoundaryCondition; 6] {
+        self.faces
+    }
+
+    pub fn get(&self, face: Face) -> BoundaryCondition {
+        self.faces[face as usize]
+    }
+
+    //  Leaves the conditions unchanged on an error, use with_periodic for periodic axes.
+    pub fn set(&mut self, face: Face, condition: BoundaryCondition) -> Result<(), UnpairedPeriodic> {
+        *self = self.with(face, condition)?;
+        Ok(())
+    }
+
+    pub fn with(self, face: Face, condition: BoundaryCondition) -> Result<Self, UnpairedPeriodic> {
+        let mut faces = self.faces;
+        faces[face as usize] = condition;
+        BoundaryConditions::new(faces)
+    }
+
+    //  Makes both faces along an axis (0 = x, 1 = y, 2 = z) wrap around.
+    pub fn with_periodic(mut self, axis: usize) -> Self {
+        self.faces[axis * 2] = BoundaryCondition::Periodic;
+        self.faces[axis * 2 + 1] = BoundaryCondition::Periodic;
+        self
+    }
+
+    //  true for each axis whose faces wrap around.
+    pub fn periodic(&self) -> [bool; 3] {
+        [0, 1, 2].map(|axis| self.faces[axis * 2] == BoundaryCondition::Periodic)
+    }
+
+    pub fn is_insulated(&self) -> bool {
+        self.faces.iter().all(|c| *c == BoundaryCondition::Insulated)
+    }
+
+    //  Sum of the non periodic boundary terms for the voxel at coord.
+    pub fn term(&self, size: Size, coord: Coord, mat: &VoxelMaterial, length: Length) -> BoundaryTerm {
+        let mut total = BoundaryTerm::default();
+        let mut weighted_temperature = 0.0;
+        for face in Face::ALL {
+            if !face.on_boundary(size, coord) {
+                continue;
+            }
+            let term = self.get(face).term(mat, length);
+            total.conductance += term.conductance;
+            total.flux += term.flux;
+            weighted_temperature += term.conductance * term.temperature;
+        }
+        if total.conductance > 0.0 {
+            total.temperature = weighted_temperature / total.conductance;
+        }
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unpaired_periodic_faces_are_rejected() {
+        let mut faces = [BoundaryCondition::Insulated; 6];
+        faces[Face::PositiveY as usize] = BoundaryCondition::Periodic;
+        assert_eq!(BoundaryConditions::new(faces), Err(UnpairedPeriodic { axis: 1 }));
+        faces[Face::NegativeY as usize] = BoundaryCondition::Periodic;
+        assert_eq!(BoundaryConditions::new(faces).unwrap().periodic(), [false, true, false]);
+    }
+
+    #[test]
+    fn with_and_set_keep_periodic_faces_paired() {
+        let periodic = BoundaryConditions::default().with_periodic(2);
+        assert_eq!(periodic.periodic(), [false, false, true]);
+        assert_eq!(
+            periodic.with(Face::PositiveZ, BoundaryCondition::Insulated),
+            Err(UnpairedPeriodic { axis: 2 })
+        );
+        assert_eq!(
+            BoundaryConditions::default().with(Face::NegativeX, BoundaryCondition::Periodic),
+            Err(UnpairedPeriodic { axis: 0 })
+        );
+
+        let mut boundaries = periodic;
+        assert!(boundaries.set(Face::NegativeZ, BoundaryCondition::FixedTemperature(300.0)).is_err());
+        assert_eq!(boundaries, periodic);
+        boundaries.set(Face::PositiveX, BoundaryCondition::FixedFlux(10.0)).unwrap();
+        assert_eq!(boundaries.get(Face::PositiveX), BoundaryCondition::FixedFlux(10.0));
+        assert_eq!(boundaries.periodic(), [false, false, true]);
+    }
+}
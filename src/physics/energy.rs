@@ -0,0 +1,173 @@
+use std::fmt;
+use crate::physics::boundary::{BoundaryCondition, BoundaryConditions};
+use crate::physics::radiation::{radiating_coefficient, Radiation};
+use crate::physics::voxel_material_lookup::VoxelMaterialLookup;
+use crate::physics::*;
+
+//  Total thermal energy Σ heat_capacity * temperature in Joules, integrated over temperature
+//  for materials with property curves and including the latent heat of each phase, see
+//  VoxelMaterialLookup::enthalpy, so it is continuous when voxels change phase.
+//  Voxels with infinite heat capacity are excluded, they act as sources or sinks
+//  and show up in BoundaryHeatFlow::fixed_voxels instead.
+pub fn thermal_energy(material: &Volume<MaterialId>, temperature: &Volume<Temperature>, lookup: &VoxelMaterialLookup) -> f64 {
+    material.data.iter().zip(temperature.data.iter())
+        .map(|(&id, &t)| lookup.enthalpy(id, t))
+        .filter(|e| e.is_finite())
+        .sum()
+}
+
+//  Heat flowing into the volume through each face, out of infinite heat capacity voxels
+//  and from radiation to the surroundings.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BoundaryHeatFlow {
+    pub faces: [f64; 6],
+    pub fixed_voxels: f64,
+    pub radiation: f64,
+}
+
+impl BoundaryHeatFlow {
+    pub fn face(&self, face: Face) -> f64 {
+        self.faces[face as usize]
+    }
+
+    pub fn total(&self) -> f64 {
+        self.faces.iter().sum::<f64>() + self.fixed_voxels + self.radiation
+    }
+
+    //  a * self + b * other, used to weight the start and end of an implicit step.
+    pub fn blend(&self, a: f64, other: &BoundaryHeatFlow, b: f64) -> BoundaryHeatFlow {
+        let mut faces = [0.0; 6];
+        for i in 0 .. 6 {
+            faces[i] = a * self.faces[i] + b * other.faces[i];
+        }
+        BoundaryHeatFlow {
+            faces,
+            fixed_voxels: a * self.fixed_voxels + b * other.fixed_voxels,
+            radiation: a * self.radiation + b * other.radiation,
+        }
+    }
+}
+
+//  Net heat transfer rate into the finite heat capacity voxels from outside of them.
+//  Periodic faces are internal so they contribute nothing.
+//  Radiation is included when given, as calculate_heat_transfer_volume does.
+pub fn boundary_heat_flow(
+    material: &Volume<MaterialId>,
+    temperature: &Volume<Temperature>,
+    lookup: &VoxelMaterialLookup,
+    boundaries: &BoundaryConditions,
+    radiation: Option<&Radiation>,
+) -> BoundaryHeatFlow {
+    let mut flow = BoundaryHeatFlow::default();
+    for (coord, id) in material.iter_coords() {
+        let t = temperature.get(coord.x, coord.y, coord.z);
+        let mat = &lookup.at_temperature(*id, t);
+        if mat.heat_capacity.is_infinite() {
+            continue;
+        }
+        if let Some(radiation) = radiation {
+            flow.radiation += radiation.heat(radiating_coefficient(material, coord, mat, lookup, boundaries), t) as f64;
+        }
+        for face in Face::ALL {
+            match material.face_neighbor(coord, face) {
+                Some(neighbor) => {
+                    let other_t = temperature.get(neighbor.x, neighbor.y, neighbor.z);
+                    let other = &lookup.at_temperature(material.get(neighbor.x, neighbor.y, neighbor.z), other_t);
+                    if other.heat_capacity.is_infinite() && mat.mass != 0.0 && other.mass != 0.0 {
+                        flow.fixed_voxels += ((other_t - t) / (mat.thermal_resistance + other.thermal_resistance)) as f64;
+                    }
+                }
+                None => {
+                    let condition = boundaries.get(face);
+                    if condition != BoundaryCondition::Periodic {
+                        flow.faces[face as usize] += condition.term(mat, lookup.length).heat(t) as f64;
+                    }
+                }
+            }
+        }
+    }
+    flow
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EnergyReport {
+    pub steps: usize,
+    pub energy: f64,
+    //  initial energy plus all energy which came in through the boundaries.
+    pub expected_energy: f64,
+    //  cumulative energy in through each face and from fixed voxels.
+    pub boundary_energy: BoundaryHeatFlow,
+    pub drift: f64,
+    pub relative_drift: f64,
+    pub within_tolerance: bool,
+}
+
+impl fmt::Display for EnergyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "energy after {} steps: {:.6e} J, expected {:.6e} J", self.steps, self.energy, self.expected_energy)?;
+        write!(f, "boundary energy in:")?;
+        for face in Face::ALL {
+            write!(f, " {:?} {:.4e} J", face, self.boundary_energy.face(face))?;
+        }
+        writeln!(
+            f,
+            ", fixed voxels {:.4e} J, radiation {:.4e} J",
+            self.boundary_energy.fixed_voxels,
+            self.boundary_energy.radiation
+        )?;
+        write!(
+            f,
+            "drift {:.4e} J ({:.3e} relative){}",
+            self.drift,
+            self.relative_drift,
+            if self.within_tolerance { "" } else { " EXCEEDS TOLERANCE" }
+        )
+    }
+}
+
+//  Tracks whether the energy of a simulation matches what came in through its boundaries.
+//  Record the boundary energy for every (sub)step then check the total energy.
+#[derive(Debug, Clone)]
+pub struct EnergyDiagnostics {
+    //  allowed drift relative to the larger of the initial energy and the energy exchanged.
+    pub tolerance: f64,
+    pub initial_energy: f64,
+    pub boundary_energy: BoundaryHeatFlow,
+    pub steps: usize,
+}
+
+impl EnergyDiagnostics {
+    pub fn new(initial_energy: f64, tolerance: f64) -> Self {
+        EnergyDiagnostics { tolerance, initial_energy, boundary_energy: BoundaryHeatFlow::default(), steps: 0 }
+    }
+
+    //  Adds the energy which flowed in over a step of the given length.
+    pub fn add_step(&mut self, flow: &BoundaryHeatFlow, time: Time) {
+        self.boundary_energy = self.boundary_energy.blend(1.0, flow, time as f64);
+        self.steps += 1;
+    }
+
+    //  Adds energy which entered by radiation, negative when radiated away.
+    pub fn add_radiation(&mut self, energy: f64) {
+        self.boundary_energy.radiation += energy;
+    }
+
+    pub fn report(&self, energy: f64) -> EnergyReport {
+        let expected_energy = self.initial_energy + self.boundary_energy.total();
+        let drift = energy - expected_energy;
+        let exchanged: f64 = self.boundary_energy.faces.iter().map(|e| e.abs()).sum::<f64>()
+            + self.boundary_energy.fixed_voxels.abs()
+            + self.boundary_energy.radiation.abs();
+        let scale = self.initial_energy.abs().max(exchanged).max(f64::MIN_POSITIVE);
+        let relative_drift = drift / scale;
+        EnergyReport {
+            steps: self.steps,
+            energy,
+            expected_energy,
+            boundary_energy: self.boundary_energy,
+            drift,
+            relative_drift,
+            within_tolerance: relative_drift.abs() <= self.tolerance,
+        }
+    }
+}
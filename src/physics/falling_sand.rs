@@ -0,0 +1,296 @@
+use bevy::math::Vec3;
+use crate::physics::voxel_material_lookup::VoxelMaterialLookup;
+use crate::physics::*;
+
+//  sideways neighbors, the starting one rotates so no direction is favored.
+const SIDES: [(isize, isize); 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+
+#[derive(Debug, Clone, Copy)]
+pub struct FallingSandSettings {
+    //  voxels fall towards the face this mostly points at, nothing moves when it is zero.
+    pub gravity: Vec3,
+    //  steepest slope grains pile up to, in degrees from horizontal. A grain slides off a pile
+    //  when the drop beside it is at least tan(angle) voxels, rounded, so angles below 45
+    //  behave as 45.
+    pub angle_of_repose: f32,
+}
+
+impl Default for FallingSandSettings {
+    fn default() -> Self {
+        FallingSandSettings {
+            gravity: PhysicsWorld::default().gravity,
+            angle_of_repose: 45.0,
+        }
+    }
+}
+
+//  The axes of the volume as seen from gravity, down along one axis and sideways along the other two.
+#[derive(Debug, Clone, Copy)]
+struct GravityFrame {
+    size: Size,
+    down_axis: usize,
+    //  -1 when falling towards the negative face.
+    down_sign: isize,
+    side_axes: [usize; 2],
+}
+
+impl GravityFrame {
+    fn new(size: Size, down: Face) -> Self {
+        let down_axis = down.axis();
+        let (dx, dy, dz) = down.offset();
+        let down_sign = dx + dy + dz;
+        let mut side_axes = [0, 1, 2].into_iter().filter(|&a| a != down_axis);
+        let side_axes = [side_axes.next().unwrap(), side_axes.next().unwrap()];
+        GravityFrame { size, down_axis, down_sign, side_axes }
+    }
+
+    fn extent(&self, axis: usize) -> usize {
+        [self.size.x, self.size.y, self.size.z][axis]
+    }
+
+    //  layer 0 is the one against the face voxels fall towards.
+    fn coord(&self, layer: usize, side: usize, other_side: usize) -> Coord {
+        let mut c = [0; 3];
+        c[self.down_axis] = if self.down_sign < 0 { layer } else { self.extent(self.down_axis) - 1 - layer };
+        c[self.side_axes[0]] = side;
+        c[self.side_axes[1]] = other_side;
+        Coord::new(c[0], c[1], c[2])
+    }
+
+    //  coord moved down voxels along gravity and (side, other_side) across it.
+    fn offset(&self, coord: Coord, down: isize, (side, other_side): (isize, isize)) -> Option<Coord> {
+        let mut d = [0; 3];
+        d[self.down_axis] = down * self.down_sign;
+        d[self.side_axes[0]] = side;
+        d[self.side_axes[1]] = other_side;
+        self.size.offset(coord, d[0], d[1], d[2])
+    }
+}
+
+//  One cellular automaton step which moves Grain and Liquid voxels under gravity.
+//  Grains fall straight down, or diagonally down when blocked which piles them at the angle
+//  of repose. Liquids also fall then spread sideways into gas. Any Grain or Liquid voxel
+//  sinks through a lighter Liquid or Gas below it, comparing density through the voxel mass.
+//  Gravity is rounded to the nearest axis as structure::analyze_structure does.
+//  Voxels are visited from the bottom up in a fixed order that depends only on step, so results
+//  are deterministic, and each voxel moves at most once per step.
+//  Temperature is swapped along with the material. Returns the number of moves.
+pub fn falling_sand_step(
+    material: &mut Volume<MaterialId>,
+    temperature: &mut Volume<Temperature>,
+    lookup: &VoxelMaterialLookup,
+    settings: &FallingSandSettings,
+    step: u64,
+) -> usize {
+    let gravity = settings.gravity;
+    let size = material.size;
+    if gravity == Vec3::ZERO || size.product() == 0 {
+        return 0;
+    }
+    let frame = GravityFrame::new(size, Face::from_direction(gravity.x, gravity.y, gravity.z));
+    //  voxels beside a grain which must be open below the side before it slides.
+    let drop = (settings.angle_of_repose.to_radians().tan().round() as isize)
+        .clamp(1, frame.extent(frame.down_axis) as isize);
+    let mut moved = vec![false; size.product()];
+    let mut moves = 0;
+    //  alternate the sweep direction each step so piles stay symmetric.
+    let reverse = step % 2 == 1;
+    let sides = frame.extent(frame.side_axes[0]);
+    for layer in 0 .. frame.extent(frame.down_axis) {
+        for other_side in 0 .. frame.extent(frame.side_axes[1]) {
+            for i in 0 .. sides {
+                let side = if reverse { sides - 1 - i } else { i };
+                let coord = frame.coord(layer, side, other_side);
+                let index = size.index(coord);
+                if moved[index] {
+                    continue;
+                }
+                let rotation = (step as usize + side + other_side) % SIDES.len();
+                if let Some(target) = find_move(material, lookup, &frame, coord, rotation, drop) {
+                    let target = size.index(target);
+                    material.data.swap(index, target);
+                    temperature.data.swap(index, target);
+                    moved[index] = true;
+                    moved[target] = true;
+                    moves += 1;
+                }
+            }
+        }
+    }
+    moves
+}
+
+fn find_move(
+    material: &Volume<MaterialId>,
+    lookup: &VoxelMaterialLookup,
+    frame: &GravityFrame,
+    coord: Coord,
+    rotation: usize,
+    drop: isize,
+) -> Option<Coord> {
+    let mat = &lookup.materials[material.get(coord.x, coord.y, coord.z) as usize];
+    if !matches!(mat.phase, PhysicsPhase::Grain | PhysicsPhase::Liquid) {
+        return None;
+    }
+    //  true if this voxel can swap into the voxel at c.
+    let can_enter = |c: Option<Coord>| c.is_some_and(|c| {
+        let other = &lookup.materials[material.get(c.x, c.y, c.z) as usize];
+        matches!(other.phase, PhysicsPhase::Liquid | PhysicsPhase::Gas) && other.mass < mat.mass
+    });
+    let below = frame.offset(coord, 1, (0, 0));
+    if can_enter(below) {
+        return below;
+    }
+    //  liquids find their level, only grains hold a slope.
+    let drop = if matches!(mat.phase, PhysicsPhase::Liquid) { 1 } else { drop };
+    for i in 0 .. SIDES.len() {
+        let side = SIDES[(rotation + i) % SIDES.len()];
+        //  only slide diagonally if the side is open too, so voxels cannot pass through corners.
+        if (0 ..= drop).all(|down| can_enter(frame.offset(coord, down, side))) {
+            return frame.offset(coord, 1, side);
+        }
+    }
+    if matches!(mat.phase, PhysicsPhase::Liquid) {
+        for i in 0 .. SIDES.len() {
+            if let Some(side) = frame.offset(coord, 0, SIDES[(rotation + i) % SIDES.len()]) {
+                let other = &lookup.materials[material.get(side.x, side.y, side.z) as usize];
+                //  spread only into gas, lighter liquids are displaced from above instead.
+                if matches!(other.phase, PhysicsPhase::Gas) && other.mass < mat.mass {
+                    return Some(side);
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup() -> VoxelMaterialLookup {
+        let mut lookup = VoxelMaterialLookup::new(0.1);
+        lookup.add(materials::AIR);
+        lookup.add(materials::SAND);
+        lookup.add(materials::WATER);
+        lookup
+    }
+
+    fn settle(material: &mut Volume<MaterialId>, lookup: &VoxelMaterialLookup, settings: &FallingSandSettings) {
+        let mut temperature = Volume::new(material.size, 300.0);
+        for step in 0 .. 200 {
+            if falling_sand_step(material, &mut temperature, lookup, settings, step) == 0 {
+                return;
+            }
+        }
+        panic!("still moving after 200 steps");
+    }
+
+    //  height of the sand in each column along x of a volume one voxel deep in z.
+    fn heights(material: &Volume<MaterialId>, sand: MaterialId) -> Vec<usize> {
+        (0 .. material.size.x)
+            .map(|x| (0 .. material.size.y).filter(|&y| material.get(x, y, 0) == sand).count())
+            .collect()
+    }
+
+    #[test]
+    fn sand_falls_along_gravity() {
+        let lookup = lookup();
+        let sand = lookup.id("Sand");
+        let mut material = Volume::new(Size { x: 5, y: 1, z: 1 }, lookup.id("Air"));
+        material.set(1, 0, 0, sand);
+        let settings = FallingSandSettings { gravity: Vec3::new(9.8, 0.0, 0.0), ..Default::default() };
+        settle(&mut material, &lookup, &settings);
+        assert_eq!(material.get(4, 0, 0), sand);
+
+        let settings = FallingSandSettings { gravity: Vec3::new(0.0, 0.0, -9.8), ..Default::default() };
+        let mut material = Volume::new(Size { x: 1, y: 1, z: 4 }, lookup.id("Air"));
+        material.set(0, 0, 3, sand);
+        settle(&mut material, &lookup, &settings);
+        assert_eq!(material.get(0, 0, 0), sand);
+
+        let settings = FallingSandSettings { gravity: Vec3::ZERO, ..Default::default() };
+        let mut temperature = Volume::new(material.size, 300.0);
+        material.set(0, 0, 3, sand);
+        assert_eq!(falling_sand_step(&mut material, &mut temperature, &lookup, &settings, 0), 0);
+
+        for size in [Size { x: 0, y: 0, z: 0 }, Size { x: 3, y: 0, z: 2 }] {
+            let mut material = Volume::new(size, sand);
+            let mut temperature = Volume::new(size, 300.0);
+            assert_eq!(falling_sand_step(&mut material, &mut temperature, &lookup, &FallingSandSettings::default(), 0), 0);
+        }
+    }
+
+    #[test]
+    fn liquids_spread_sideways() {
+        let lookup = lookup();
+        let water = lookup.id("Water");
+        let mut material = Volume::new(Size { x: 9, y: 4, z: 1 }, lookup.id("Air"));
+        for y in 0 .. 4 {
+            material.set(4, y, 0, water);
+        }
+        let mut temperature = Volume::new(material.size, 300.0);
+        //  a liquid keeps wandering across gas, so this runs a fixed number of steps.
+        for step in 0 .. 50 {
+            falling_sand_step(&mut material, &mut temperature, &lookup, &FallingSandSettings::default(), step);
+        }
+        //  it finds its level, a column of four spreads into a single layer.
+        let heights = heights(&material, water);
+        assert_eq!(heights.iter().sum::<usize>(), 4);
+        assert!(heights.iter().all(|&h| h <= 1), "{:?}", heights);
+        assert!((0 .. 9).all(|x| material.get(x, 1, 0) != water));
+    }
+
+    #[test]
+    fn denser_voxels_sink_through_lighter_ones() {
+        let lookup = lookup();
+        let (sand, water) = (lookup.id("Sand"), lookup.id("Water"));
+        let mut material = Volume::new(Size { x: 1, y: 4, z: 1 }, water);
+        material.set(0, 3, 0, sand);
+        settle(&mut material, &lookup, &FallingSandSettings::default());
+        assert_eq!(material.get(0, 0, 0), sand);
+        assert!((1 .. 4).all(|y| material.get(0, y, 0) == water));
+    }
+
+    #[test]
+    fn temperature_moves_with_the_voxel() {
+        let lookup = lookup();
+        let sand = lookup.id("Sand");
+        let size = Size { x: 1, y: 3, z: 1 };
+        let mut material = Volume::new(size, lookup.id("Air"));
+        material.set(0, 2, 0, sand);
+        let mut temperature = Volume::new(size, 300.0);
+        temperature.set(0, 2, 0, 900.0);
+        let settings = FallingSandSettings::default();
+        while falling_sand_step(&mut material, &mut temperature, &lookup, &settings, 0) > 0 {}
+        assert_eq!(material.get(0, 0, 0), sand);
+        assert_eq!(temperature.get(0, 0, 0), 900.0);
+        assert_eq!(temperature.get(0, 2, 0), 300.0);
+    }
+
+    #[test]
+    fn piles_follow_the_angle_of_repose() {
+        let lookup = lookup();
+        let sand = lookup.id("Sand");
+        let size = Size { x: 15, y: 12, z: 1 };
+        let mut column = Volume::new(size, lookup.id("Air"));
+        for y in 0 .. 12 {
+            column.set(7, y, 0, sand);
+        }
+
+        let mut shallow = column.clone();
+        settle(&mut shallow, &lookup, &FallingSandSettings::default());
+        let shallow = heights(&shallow, sand);
+        let mut steep = column.clone();
+        settle(&mut steep, &lookup, &FallingSandSettings { angle_of_repose: 63.5, ..Default::default() });
+        let steep = heights(&steep, sand);
+
+        assert_eq!(shallow.iter().sum::<usize>(), 12);
+        assert_eq!(steep.iter().sum::<usize>(), 12);
+        for x in 0 .. 14 {
+            assert!(shallow[x].abs_diff(shallow[x + 1]) <= 1, "{:?}", shallow);
+            assert!(steep[x].abs_diff(steep[x + 1]) <= 2, "{:?}", steep);
+        }
+        assert!(steep[7] > shallow[7], "{:?} {:?}", steep, shallow);
+    }
+}
@@ -0,0 +1,376 @@
+use std::ops::{Add, Mul};
+use bevy::math::Vec3;
+use crate::physics::voxel_material_lookup::VoxelMaterialLookup;
+use crate::physics::*;
+
+#[derive(Debug, Clone, Copy)]
+pub struct FluidSettings {
+    pub gravity: Vec3,
+    //  temperature at which a fluid voxel is neutrally buoyant.
+    pub ambient_temperature: Temperature,
+    //  volumetric thermal expansion per kelvin for liquids, gases use the ideal gas 1 / T.
+    pub liquid_thermal_expansion: f32,
+    //  Gauss Seidel iterations for the viscosity and pressure solves.
+    pub iterations: usize,
+}
+
+impl Default for FluidSettings {
+    fn default() -> Self {
+        FluidSettings {
+            gravity: PhysicsWorld::default().gravity,
+            ambient_temperature: kelvin::ROOM_TEMPERATURE,
+            liquid_thermal_expansion: 2.1e-4,
+            iterations: 40,
+        }
+    }
+}
+
+//  Incompressible flow for Liquid and Gas voxels in the style of Stam's stable fluids.
+//  Each step adds buoyancy, diffuses velocity by the material viscosity, projects out
+//  divergence with a density weighted pressure solve and advects velocity semi Lagrangian.
+//  Solid and Grain voxels and the edges of the volume are no slip walls.
+//  Velocity and pressure are stored at voxel centers in meters / second and Pascals.
+//  Buoyancy uses the Boussinesq approximation, the hydrostatic part of gravity is assumed
+//  balanced by the pressure so only temperature differences drive the flow.
+pub struct FluidSolver {
+    pub size: Size,
+    pub length: Length,
+    pub settings: FluidSettings,
+    pub velocity: Volume<Vec3>,
+    pub pressure: Volume<Pressure>,
+    fluid: Vec<bool>,
+    //  kg / m3
+    density: Vec<f32>,
+    //  m2 / s
+    kinematic_viscosity: Vec<f32>,
+    liquid: Vec<bool>,
+}
+
+impl FluidSolver {
+    pub fn new(material: &Volume<MaterialId>, lookup: &VoxelMaterialLookup, settings: FluidSettings) -> Self {
+        let size = material.size;
+        let mut solver = FluidSolver {
+            size,
+            length: lookup.length,
+            settings,
+            velocity: Volume::new(size, Vec3::ZERO),
+            pressure: Volume::new(size, 0.0),
+            fluid: Vec::new(),
+            density: Vec::new(),
+            kinematic_viscosity: Vec::new(),
+            liquid: Vec::new(),
+        };
+        solver.material_changed(material, lookup);
+        solver
+    }
+
+    //  Must be called after changing the material volume.
+    pub fn material_changed(&mut self, material: &Volume<MaterialId>, lookup: &VoxelMaterialLookup) {
+        let voxel_volume = lookup.length * lookup.length * lookup.length;
+        self.fluid.clear();
+        self.density.clear();
+        self.kinematic_viscosity.clear();
+        self.liquid.clear();
+        for &id in material.data.iter() {
+            let mat = &lookup.materials[id as usize];
+            let fluid = matches!(mat.phase, PhysicsPhase::Liquid | PhysicsPhase::Gas) && mat.mass > 0.0;
+            let density = mat.mass / voxel_volume;
+            //  viscosity is in centipoise, 0.001 Pa s per centipoise.
+            let kinematic_viscosity = if fluid { mat.viscosity * 0.001 / density } else { 0.0 };
+            self.fluid.push(fluid);
+            self.density.push(density);
+            self.kinematic_viscosity.push(kinematic_viscosity);
+            self.liquid.push(matches!(mat.phase, PhysicsPhase::Liquid));
+        }
+        for (v, fluid) in self.velocity.data.iter_mut().zip(self.fluid.iter()) {
+            if !fluid {
+                *v = Vec3::ZERO;
+            }
+        }
+    }
+
+    pub fn is_fluid(&self, index: usize) -> bool {
+        self.fluid[index]
+    }
+
+    pub fn step(&mut self, temperature: &Volume<Temperature>, time: Time) {
+        self.add_buoyancy(temperature, time);
+        self.diffuse_velocity(time);
+        self.project(time);
+        self.velocity = self.advect(&self.velocity, time);
+        self.project(time);
+    }
+
+    fn add_buoyancy(&mut self, temperature: &Volume<Temperature>, time: Time) {
+        let ambient = self.settings.ambient_temperature;
+        for i in 0 .. self.velocity.data.len() {
+            if !self.fluid[i] {
+                continue;
+            }
+            let expansion = if self.liquid[i] { self.settings.liquid_thermal_expansion } else { 1.0 / ambient };
+            //  warmer fluid is lighter so it accelerates against gravity.
+            let buoyancy = -self.settings.gravity * expansion * (temperature.data[i] - ambient);
+            self.velocity.data[i] += buoyancy * time;
+        }
+    }
+
+    //  Implicit viscosity, (1 + 6a) v - a Σ v_neighbor = v0 with a = ν dt / h².
+    fn diffuse_velocity(&mut self, time: Time) {
+        let initial = self.velocity.clone();
+        let h2 = self.length * self.length;
+        for _ in 0 .. self.settings.iterations {
+            for coord in self.size.coords() {
+                let i = self.size.index(coord);
+                if !self.fluid[i] {
+                    continue;
+                }
+                let a = self.kinematic_viscosity[i] * time / h2;
+                if a == 0.0 {
+                    continue;
+                }
+                let mut sum = Vec3::ZERO;
+                for (_, n) in self.velocity.face_neighbors(coord) {
+                    //  walls are no slip so they contribute zero velocity.
+                    sum += self.velocity.data[self.size.index(n)];
+                }
+                self.velocity.data[i] = (initial.data[i] + sum * a) / (1.0 + 6.0 * a);
+            }
+        }
+    }
+
+    //  Index of the neighbor across face if it is fluid, None for walls and the volume edges.
+    fn fluid_neighbor(&self, coord: Coord, face: Face) -> Option<usize> {
+        self.velocity.face_neighbor(coord, face).map(|n| self.size.index(n)).filter(|&n| self.fluid[n])
+    }
+
+    //  ∇·u per voxel in 1 / second by central differences, zero outside of the fluid.
+    //  Walls reflect the velocity of the voxel next to them so nothing flows through the wall.
+    pub fn divergence(&self) -> Vec<f32> {
+        let velocity = &self.velocity.data;
+        self.size.coords().map(|coord| {
+            let i = self.size.index(coord);
+            if !self.fluid[i] {
+                return 0.0;
+            }
+            let mut sum = 0.0;
+            for (axis, unit) in [Vec3::X, Vec3::Y, Vec3::Z].into_iter().enumerate() {
+                let v = |face: Face| self.fluid_neighbor(coord, face).map_or(-velocity[i], |n| velocity[n]).dot(unit);
+                sum += v(Face::ALL[axis * 2 + 1]) - v(Face::ALL[axis * 2]);
+            }
+            sum / (2.0 * self.length)
+        }).collect()
+    }
+
+    //  ∂p/∂axis at a fluid voxel by central differences, walls mirror the pressure of the voxel.
+    fn pressure_gradient(&self, pressure: &[Pressure], coord: Coord, axis: usize) -> f32 {
+        let i = self.size.index(coord);
+        let p = |face: Face| self.fluid_neighbor(coord, face).map_or(pressure[i], |n| pressure[n]);
+        (p(Face::ALL[axis * 2 + 1]) - p(Face::ALL[axis * 2])) / (2.0 * self.length)
+    }
+
+    //  Solves D (1/ρ G p) = D u / dt and subtracts dt/ρ G p so the velocity is divergence free,
+    //  where D is divergence and G pressure_gradient. Using exactly these two operators,
+    //  rather than the usual compact Laplacian, is what makes the result divergence free
+    //  on a grid which stores velocity at voxel centers, and with the walls treated as they
+    //  are D is minus the transpose of G so Gauss Seidel converges.
+    fn project(&mut self, time: Time) {
+        let h = self.length;
+        let size = self.size;
+        let divergence = self.divergence();
+
+        let mut pressure = std::mem::replace(&mut self.pressure, Volume::new(size, 0.0));
+        for _ in 0 .. self.settings.iterations {
+            for coord in size.coords() {
+                let i = size.index(coord);
+                if !self.fluid[i] {
+                    continue;
+                }
+                //  D (1/ρ G p) at i, and the coefficient of p[i] in it.
+                let mut flow = 0.0;
+                let mut diagonal = 0.0;
+                for axis in 0 .. 3 {
+                    let own_gradient = || self.pressure_gradient(&pressure.data, coord, axis) / self.density[i];
+                    let mut walls = 0.0;
+                    match self.fluid_neighbor(coord, Face::ALL[axis * 2 + 1]) {
+                        Some(n) => {
+                            flow += self.pressure_gradient(&pressure.data, size.coord(n), axis) / self.density[n];
+                            diagonal -= 1.0 / (2.0 * h * self.density[n]);
+                        }
+                        None => {
+                            flow -= own_gradient();
+                            walls += 1.0;
+                        }
+                    }
+                    match self.fluid_neighbor(coord, Face::ALL[axis * 2]) {
+                        Some(n) => {
+                            flow -= self.pressure_gradient(&pressure.data, size.coord(n), axis) / self.density[n];
+                            diagonal -= 1.0 / (2.0 * h * self.density[n]);
+                        }
+                        None => {
+                            flow += own_gradient();
+                            walls -= 1.0;
+                        }
+                    }
+                    //  with one wall p[i] stands in for the missing neighbor in its own gradient.
+                    diagonal -= walls * walls / (2.0 * h * self.density[i]);
+                }
+                if diagonal < 0.0 {
+                    let residual = flow / (2.0 * h) - divergence[i] / time;
+                    pressure.data[i] -= residual / (diagonal / (2.0 * h));
+                } else {
+                    pressure.data[i] = 0.0;
+                }
+            }
+        }
+
+        for coord in size.coords() {
+            let i = size.index(coord);
+            if !self.fluid[i] {
+                continue;
+            }
+            let gradient = Vec3::new(
+                self.pressure_gradient(&pressure.data, coord, 0),
+                self.pressure_gradient(&pressure.data, coord, 1),
+                self.pressure_gradient(&pressure.data, coord, 2),
+            );
+            self.velocity.data[i] -= gradient * time / self.density[i];
+        }
+        self.pressure = pressure;
+    }
+
+    //  Semi Lagrangian advection of any field through the current velocity.
+    //  Only fluid voxels are updated, others keep their value. The traced back position
+    //  is sampled from the fluid voxels around it only, so walls never leak their value
+    //  into the flow, and a voxel whose trace lands among walls keeps its own value.
+    pub fn advect<T: Copy + Add<Output = T> + Mul<f32, Output = T>>(&self, field: &Volume<T>, time: Time) -> Volume<T> {
+        let mut result = field.clone();
+        for coord in self.size.coords() {
+            let i = self.size.index(coord);
+            if !self.fluid[i] {
+                continue;
+            }
+            let position = Vec3::new(coord.x as f32, coord.y as f32, coord.z as f32);
+            let back = position - self.velocity.data[i] * time / self.length;
+            result.data[i] = self.sample_fluid(field, back).unwrap_or(field.data[i]);
+        }
+        result
+    }
+
+    //  Trilinear interpolation like sample, weighted over the fluid corners only,
+    //  None when all of them are walls.
+    fn sample_fluid<T: Copy + Add<Output = T> + Mul<f32, Output = T>>(&self, field: &Volume<T>, position: Vec3) -> Option<T> {
+        let size = self.size;
+        let max = Vec3::new(size.x as f32 - 1.0, size.y as f32 - 1.0, size.z as f32 - 1.0);
+        let p = position.clamp(Vec3::ZERO, max);
+        let base = p.floor();
+        let f = p - base;
+        let mut total: Option<T> = None;
+        let mut weight_sum = 0.0;
+        for corner in 0 .. 8 {
+            let (dx, dy, dz) = (corner & 1, (corner >> 1) & 1, (corner >> 2) & 1);
+            let weight = |d: usize, f: f32| if d == 1 { f } else { 1.0 - f };
+            let w = weight(dx, f.x) * weight(dy, f.y) * weight(dz, f.z);
+            let coord = Coord::new(
+                (base.x as usize + dx).min(size.x - 1),
+                (base.y as usize + dy).min(size.y - 1),
+                (base.z as usize + dz).min(size.z - 1),
+            );
+            let i = size.index(coord);
+            if w <= 0.0 || !self.fluid[i] {
+                continue;
+            }
+            let value = field.data[i] * w;
+            weight_sum += w;
+            total = Some(match total {
+                Some(total) => total + value,
+                None => value,
+            });
+        }
+        total.map(|total| total * (1.0 / weight_sum))
+    }
+
+    //  Carries heat with the flow so convection happens.
+    pub fn advect_temperature(&self, temperature: &mut Volume<Temperature>, time: Time) {
+        *temperature = self.advect(temperature, time);
+    }
+}
+
+//  Trilinear interpolation at a position in voxel coordinates, clamped to the volume.
+pub fn sample<T: Copy + Add<Output = T> + Mul<f32, Output = T>>(field: &Volume<T>, position: Vec3) -> T {
+    let size = field.size;
+    let max = Vec3::new(size.x as f32 - 1.0, size.y as f32 - 1.0, size.z as f32 - 1.0);
+    let p = position.clamp(Vec3::ZERO, max);
+    let base = p.floor();
+    let f = p - base;
+    let x0 = base.x as usize;
+    let y0 = base.y as usize;
+    let z0 = base.z as usize;
+    let x1 = (x0 + 1).min(size.x - 1);
+    let y1 = (y0 + 1).min(size.y - 1);
+    let z1 = (z0 + 1).min(size.z - 1);
+    let lerp = |a: T, b: T, t: f32| a * (1.0 - t) + b * t;
+    let c00 = lerp(field.get(x0, y0, z0), field.get(x1, y0, z0), f.x);
+    let c10 = lerp(field.get(x0, y1, z0), field.get(x1, y1, z0), f.x);
+    let c01 = lerp(field.get(x0, y0, z1), field.get(x1, y0, z1), f.x);
+    let c11 = lerp(field.get(x0, y1, z1), field.get(x1, y1, z1), f.x);
+    lerp(lerp(c00, c10, f.y), lerp(c01, c11, f.y), f.z)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup() -> VoxelMaterialLookup {
+        let mut lookup = VoxelMaterialLookup::new(0.1);
+        lookup.add(materials::AIR);
+        lookup.add(materials::WATER);
+        lookup.add(materials::ROCK);
+        lookup
+    }
+
+    fn root_mean_square(values: &[f32]) -> f32 {
+        (values.iter().map(|v| v * v).sum::<f32>() / values.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn projection_removes_divergence() {
+        let lookup = lookup();
+        let size = Size { x: 10, y: 10, z: 4 };
+        let mut material = Volume::new(size, lookup.id("Water"));
+        for x in 3 .. 5 {
+            material.set(x, 4, 1, lookup.id("Rock"));
+        }
+        let mut solver = FluidSolver::new(&material, &lookup, FluidSettings::default());
+        for (coord, v) in solver.velocity.iter_mut_coords() {
+            if solver.fluid[size.index(coord)] {
+                let (x, y, z) = (coord.x as f32, coord.y as f32, coord.z as f32);
+                *v = Vec3::new((x * 0.7).sin() + y * 0.1, (y * 0.5).cos() - x * 0.05, (z + x * 0.3).sin());
+            }
+        }
+        let before = root_mean_square(&solver.divergence());
+        solver.project(0.1);
+        let after = root_mean_square(&solver.divergence());
+        assert!(after < before * 0.01, "divergence {} before and {} after", before, after);
+        assert!(solver.velocity.data.iter().all(|v| v.is_finite()));
+    }
+
+    #[test]
+    fn advection_does_not_sample_walls() {
+        let lookup = lookup();
+        let size = Size { x: 6, y: 3, z: 1 };
+        let mut material = Volume::new(size, lookup.id("Air"));
+        material.set(0, 1, 0, lookup.id("Rock"));
+        let mut solver = FluidSolver::new(&material, &lookup, FluidSettings::default());
+        let mut temperature = Volume::new(size, 300.0);
+        temperature.set(0, 1, 0, 1000.0);
+        //  flow in +x traces back into and beyond the hot rock.
+        solver.velocity.data.fill(Vec3::new(2.0, 0.0, 0.0));
+        solver.advect_temperature(&mut temperature, 0.1);
+        assert_eq!(temperature.get(0, 1, 0), 1000.0);
+        for (coord, &t) in temperature.iter_coords() {
+            if coord != Coord::new(0, 1, 0) {
+                assert_eq!(t, 300.0, "{:?}", coord);
+            }
+        }
+    }
+}
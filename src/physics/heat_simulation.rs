@@ -0,0 +1,525 @@
+use std::fmt;
+use crate::physics::boundary::BoundaryConditions;
+use crate::physics::energy::{boundary_heat_flow, thermal_energy, EnergyDiagnostics, EnergyReport};
+use crate::physics::heat_transfer::apply_heat_to_volume;
+use crate::physics::heat_transfer_kernel::HeatTransferKernel;
+use crate::physics::implicit_heat_transfer::{ConjugateGradientSettings, HeatIntegrator, ImplicitHeatSystem};
+use crate::physics::radiation::{apply_radiation, Radiation};
+use crate::physics::phase_change::{apply_phase_changes, PhaseChangeTable};
+use crate::physics::voxel_material_lookup::VoxelMaterialLookup;
+use crate::physics::*;
+
+//  Largest time step for which the explicit update stays stable.
+pub fn max_stable_time_step(
+    material: &Volume<MaterialId>,
+    lookup: &VoxelMaterialLookup,
+    boundaries: &BoundaryConditions,
+) -> Time {
+    HeatTransferKernel::new(material, lookup, boundaries).max_stable_time_step()
+}
+
+//  The requested step was split up to stay within the stability limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StabilityWarning {
+    pub requested: Time,
+    pub limit: Time,
+    pub substeps: usize,
+}
+
+impl fmt::Display for StabilityWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "heat step of {}s exceeds the stable limit of {}s, took {} substeps", self.requested, self.limit, self.substeps)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeatStepReport {
+    pub substeps: usize,
+    pub substep_time: Time,
+    //  true if the requested step was split up to stay stable.
+    pub stability_limited: bool,
+    //  set on the first stability limited step, and again after material_changed,
+    //  rather than on every step.
+    pub warning: Option<StabilityWarning>,
+    //  number of voxels which melted, froze, boiled or condensed.
+    pub phase_changes: usize,
+}
+
+//  A step which would need more than max_substeps explicit substeps, nothing was changed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TooManySubsteps {
+    pub requested: Time,
+    pub limit: Time,
+    //  None when the limit is zero or the time is not a number.
+    pub substeps: Option<usize>,
+    pub max_substeps: usize,
+}
+
+impl fmt::Display for TooManySubsteps {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "heat step of {}s with a stable limit of {}s ", self.requested, self.limit)?;
+        match self.substeps {
+            Some(substeps) => write!(f, "needs {} substeps", substeps)?,
+            None => write!(f, "can not be divided into substeps")?,
+        }
+        write!(f, ", more than the maximum of {}, use a smaller step or an implicit integrator", self.max_substeps)
+    }
+}
+
+impl std::error::Error for TooManySubsteps {}
+
+//  Owns the volumes for a thermal simulation and advances them,
+//  splitting explicit steps into substeps that stay within the stability limit.
+pub struct HeatSimulation<'a> {
+    //  change it with set_lookup so the phase change table and kernel follow.
+    pub lookup: &'a VoxelMaterialLookup,
+    pub material: Volume<MaterialId>,
+    pub temperature: Volume<Temperature>,
+    pub heat: Volume<HeatTransferRate>,
+    //  energy absorbed (positive) or released (negative) by voxels part way through a phase change.
+    pub latent: Volume<Energy>,
+    pub integrator: HeatIntegrator,
+    pub boundaries: BoundaryConditions,
+    //  radiative exchange of solid and liquid surfaces facing a gas or the domain boundary, off when None.
+    pub radiation: Option<Radiation>,
+    //  fraction of the stability limit actually used for each substep.
+    pub safety_factor: f32,
+    //  explicit steps which need more substeps than this fail instead of running for ever.
+    pub max_substeps: usize,
+    //  seconds simulated, f64 so that adding small steps to a long run does not stall.
+    pub time: f64,
+    //  energy accounting, off unless enabled as it costs a pass over the volume per substep.
+    pub energy: Option<EnergyDiagnostics>,
+    kernel: HeatTransferKernel,
+    //  kept between implicit steps, None until the first one and after material_changed.
+    implicit: Option<ImplicitHeatSystem>,
+    phase_changes: PhaseChangeTable,
+    max_time_step: Time,
+    warned: bool,
+}
+
+impl<'a> HeatSimulation<'a> {
+    pub fn new(
+        material: Volume<MaterialId>,
+        temperature: Volume<Temperature>,
+        lookup: &'a VoxelMaterialLookup,
+        integrator: HeatIntegrator,
+        boundaries: BoundaryConditions,
+    ) -> Self {
+        let heat = Volume::new(material.size, 0.0);
+        let latent = Volume::new(material.size, 0.0);
+        let kernel = HeatTransferKernel::at_temperature(&material, &temperature, lookup, &boundaries);
+        let max_time_step = kernel.max_stable_time_step();
+        HeatSimulation {
+            lookup,
+            material,
+            temperature,
+            heat,
+            latent,
+            integrator,
+            boundaries,
+            radiation: None,
+            safety_factor: 0.9,
+            max_substeps: 100_000,
+            time: 0.0,
+            energy: None,
+            kernel,
+            implicit: None,
+            phase_changes: PhaseChangeTable::new(lookup),
+            max_time_step,
+            warned: false,
+        }
+    }
+
+    pub fn enable_energy_diagnostics(&mut self, tolerance: f64) {
+        self.energy = Some(EnergyDiagnostics::new(self.total_energy(), tolerance));
+    }
+
+    //  Sensible and latent enthalpy of every voxel, plus the energy of voxels part way
+    //  through a phase change, so it only changes by what comes in through the boundaries.
+    pub fn total_energy(&self) -> f64 {
+        thermal_energy(&self.material, &self.temperature, self.lookup)
+            + self.latent.data.iter().map(|&e| e as f64).sum::<f64>()
+    }
+
+    pub fn energy_report(&self) -> Option<EnergyReport> {
+        self.energy.as_ref().map(|diagnostics| diagnostics.report(self.total_energy()))
+    }
+
+    pub fn max_time_step(&self) -> Time {
+        self.max_time_step
+    }
+
+    //  Switches to a lookup with different material properties, for example after
+    //  MaterialDatabase::reload_if_changed, and rebuilds everything derived from them.
+    //  Energy diagnostics start again from the new energy as the latent heats may differ.
+    pub fn set_lookup(&mut self, lookup: &'a VoxelMaterialLookup) {
+        self.lookup = lookup;
+        self.phase_changes = PhaseChangeTable::new(lookup);
+        self.material_changed();
+        if let Some(tolerance) = self.energy.as_ref().map(|energy| energy.tolerance) {
+            self.enable_energy_diagnostics(tolerance);
+        }
+    }
+
+    //  Must be called after changing the material volume or boundaries.
+    pub fn material_changed(&mut self) {
+        self.refresh_kernel();
+        self.warned = false;
+    }
+
+    fn refresh_kernel(&mut self) {
+        self.kernel = match &self.radiation {
+            Some(radiation) => HeatTransferKernel::with_radiation(&self.material, &self.temperature, self.lookup, &self.boundaries, radiation),
+            None => HeatTransferKernel::at_temperature(&self.material, &self.temperature, self.lookup, &self.boundaries),
+        };
+        self.max_time_step = self.kernel.max_stable_time_step();
+        self.implicit = None;
+    }
+
+    //  Follows property curves without rebuilding the parts of the kernel they leave alone.
+    fn update_kernel_temperature(&mut self) {
+        self.kernel.update_temperature(&self.material, &self.temperature, self.lookup, &self.boundaries);
+        self.max_time_step = self.kernel.max_stable_time_step();
+    }
+
+    //  Readies the implicit system for a step, it is assembled again only when the materials
+    //  changed or, with property curves, from the current temperatures.
+    fn prepare_implicit_system(&mut self, time: Time, theta: f64) {
+        match &mut self.implicit {
+            Some(system) => {
+                system.set_time_step(time, theta);
+                if self.lookup.has_temperature_dependence() {
+                    system.update_temperature(&self.material, &self.temperature, self.lookup, &self.boundaries);
+                }
+            }
+            None => {
+                self.implicit = Some(ImplicitHeatSystem::at_temperature(
+                    &self.material, &self.temperature, self.lookup, &self.boundaries, time, theta,
+                ));
+            }
+        }
+    }
+
+    //  The implicit integrators leave radiation out of the linear system and apply it after.
+    fn apply_radiation(&mut self, time: Time) {
+        if let Some(radiation) = self.radiation {
+            let energy = apply_radiation(&self.material, &mut self.temperature, self.lookup, &self.boundaries, &radiation, time);
+            if let Some(diagnostics) = self.energy.as_mut() {
+                diagnostics.add_radiation(energy);
+            }
+        }
+    }
+
+    pub fn step(&mut self, time: Time) -> Result<HeatStepReport, TooManySubsteps> {
+        let mut report = match self.integrator {
+            HeatIntegrator::Explicit => {
+                //  properties which follow temperature are refreshed every substep.
+                let temperature_dependent = self.lookup.has_temperature_dependence();
+                if self.kernel.radiation != self.radiation {
+                    self.refresh_kernel();
+                } else if temperature_dependent {
+                    self.update_kernel_temperature();
+                } else if self.radiation.is_some() {
+                    //  the radiative stability limit tightens as voxels get hotter.
+                    self.kernel.set_radiation_reference(&self.temperature);
+                    self.max_time_step = self.kernel.max_stable_time_step();
+                }
+                let limit = self.max_time_step * self.safety_factor;
+                let substeps = if time > limit { (time as f64 / limit as f64).ceil() } else { 1.0 };
+                if substeps > self.max_substeps as f64 {
+                    return Err(TooManySubsteps {
+                        requested: time,
+                        limit,
+                        substeps: substeps.is_finite().then_some(substeps as usize),
+                        max_substeps: self.max_substeps,
+                    });
+                }
+                let substeps = substeps as usize;
+                let substep_time = time / substeps as Time;
+                for substep in 0 .. substeps {
+                    if temperature_dependent && substep > 0 {
+                        self.update_kernel_temperature();
+                    }
+                    if let Some(energy) = self.energy.as_mut() {
+                        let flow = boundary_heat_flow(
+                            &self.material, &self.temperature, self.lookup, &self.boundaries, self.radiation.as_ref(),
+                        );
+                        energy.add_step(&flow, substep_time);
+                    }
+                    if temperature_dependent {
+                        self.kernel.calculate_heat(&self.temperature, &mut self.heat);
+                        apply_heat_to_volume(&self.material, &mut self.temperature, &self.heat, self.lookup, substep_time);
+                    } else {
+                        self.kernel.step(&mut self.temperature, &mut self.heat, substep_time);
+                    }
+                }
+                HeatStepReport { substeps, substep_time, stability_limited: substeps > 1, warning: None, phase_changes: 0 }
+            }
+            integrator => {
+                let theta = integrator.theta();
+                let start_flow = self.energy.as_ref()
+                    .map(|_| boundary_heat_flow(&self.material, &self.temperature, self.lookup, &self.boundaries, None));
+                let start_temperature = self.lookup.has_temperature_dependence().then(|| self.temperature.clone());
+                self.prepare_implicit_system(time, theta);
+                let system = self.implicit.as_ref().unwrap();
+                system.solve(&mut self.temperature, &mut self.heat, ConjugateGradientSettings::default());
+                if let Some(start_temperature) = start_temperature {
+                    //  the solve used start of step heat capacities, redo the update from the
+                    //  average heat through the integrated heat capacity so energy is conserved.
+                    self.temperature = start_temperature;
+                    apply_heat_to_volume(&self.material, &mut self.temperature, &self.heat, self.lookup, time);
+                }
+                if let (Some(energy), Some(start_flow)) = (self.energy.as_mut(), start_flow) {
+                    //  the implicit step uses this same weighting of start and end temperatures.
+                    let end_flow = boundary_heat_flow(&self.material, &self.temperature, self.lookup, &self.boundaries, None);
+                    energy.add_step(&start_flow.blend(1.0 - theta, &end_flow, theta), time);
+                }
+                self.apply_radiation(time);
+                HeatStepReport { substeps: 1, substep_time: time, stability_limited: false, warning: None, phase_changes: 0 }
+            }
+        };
+        if report.stability_limited && !self.warned {
+            self.warned = true;
+            report.warning = Some(StabilityWarning { requested: time, limit: self.max_time_step, substeps: report.substeps });
+        }
+        if !self.phase_changes.is_empty() {
+            report.phase_changes = apply_phase_changes(
+                &self.phase_changes,
+                &mut self.material,
+                &mut self.temperature,
+                &mut self.latent,
+                self.lookup,
+            );
+            //  total_energy counts the latent heat of each phase so the accounting carries on.
+            if report.phase_changes > 0 {
+                self.material_changed();
+            }
+        }
+        self.time += time as f64;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iron_block(lookup: &VoxelMaterialLookup) -> (Volume<MaterialId>, Volume<Temperature>) {
+        let size = Size { x: 4, y: 3, z: 2 };
+        let material = Volume::new(size, lookup.id("Iron"));
+        let mut temperature = Volume::new(size, 300.0);
+        temperature.set(0, 0, 0, 900.0);
+        (material, temperature)
+    }
+
+    fn iron_lookup() -> VoxelMaterialLookup {
+        let mut lookup = VoxelMaterialLookup::new(0.01);
+        lookup.add(materials::IRON);
+        lookup
+    }
+
+    #[test]
+    fn large_steps_are_split_and_warned_about_once() {
+        let lookup = iron_lookup();
+        let (material, temperature) = iron_block(&lookup);
+        let mut simulation = HeatSimulation::new(material, temperature, &lookup, HeatIntegrator::Explicit, BoundaryConditions::default());
+        let limit = simulation.max_time_step();
+        let report = simulation.step(limit * 10.0).unwrap();
+        assert!(report.stability_limited);
+        assert!(report.substep_time <= limit);
+        let warning = report.warning.expect("the first limited step should warn");
+        assert_eq!(warning.substeps, report.substeps);
+        assert!(simulation.step(limit * 10.0).unwrap().warning.is_none());
+        let small = simulation.step(limit * 0.5).unwrap();
+        assert_eq!(small.substeps, 1);
+        assert!(!small.stability_limited);
+    }
+
+    #[test]
+    fn too_many_substeps_is_an_error() {
+        let lookup = iron_lookup();
+        let (material, temperature) = iron_block(&lookup);
+        let mut simulation = HeatSimulation::new(material, temperature, &lookup, HeatIntegrator::Explicit, BoundaryConditions::default());
+        simulation.max_substeps = 100;
+        let before = simulation.temperature.clone();
+        let error = simulation.step(simulation.max_time_step() * 1000.0).unwrap_err();
+        assert_eq!(error.max_substeps, 100);
+        assert!(error.substeps.unwrap() > 100);
+        assert_eq!(simulation.temperature.data, before.data);
+        assert_eq!(simulation.time, 0.0);
+        //  an implicit integrator has no limit.
+        simulation.integrator = HeatIntegrator::BackwardEuler;
+        assert_eq!(simulation.step(simulation.max_time_step() * 1000.0).unwrap().substeps, 1);
+    }
+
+    const INTEGRATORS: [HeatIntegrator; 3] = [HeatIntegrator::Explicit, HeatIntegrator::BackwardEuler, HeatIntegrator::CrankNicolson];
+
+    //  Runs steps on an insulated domain checking the energy after each, returns the phase changes.
+    fn assert_conserves_energy(
+        lookup: &VoxelMaterialLookup,
+        material: &Volume<MaterialId>,
+        temperature: &Volume<Temperature>,
+        integrator: HeatIntegrator,
+        time: Time,
+        steps: usize,
+    ) -> usize {
+        assert_conserves_energy_with_radiation(lookup, material, temperature, integrator, None, time, steps)
+    }
+
+    fn assert_conserves_energy_with_radiation(
+        lookup: &VoxelMaterialLookup,
+        material: &Volume<MaterialId>,
+        temperature: &Volume<Temperature>,
+        integrator: HeatIntegrator,
+        radiation: Option<Radiation>,
+        time: Time,
+        steps: usize,
+    ) -> usize {
+        let mut simulation = HeatSimulation::new(material.clone(), temperature.clone(), lookup, integrator, BoundaryConditions::default());
+        simulation.radiation = radiation;
+        simulation.enable_energy_diagnostics(1e-4);
+        let mut phase_changes = 0;
+        for _ in 0 .. steps {
+            phase_changes += simulation.step(time).unwrap().phase_changes;
+            let report = simulation.energy_report().unwrap();
+            assert!(report.within_tolerance, "{:?}: {}", integrator, report);
+        }
+        phase_changes
+    }
+
+    #[test]
+    fn integrators_conserve_energy_on_an_insulated_domain() {
+        let lookup = iron_lookup();
+        let (material, temperature) = iron_block(&lookup);
+        let limit = max_stable_time_step(&material, &lookup, &BoundaryConditions::default());
+        for integrator in INTEGRATORS {
+            assert_conserves_energy(&lookup, &material, &temperature, integrator, limit * 5.0, 50);
+        }
+    }
+
+    #[test]
+    fn energy_is_conserved_while_ice_melts() {
+        let mut lookup = VoxelMaterialLookup::new(0.01);
+        lookup.add(materials::ICE);
+        lookup.add(materials::WATER);
+        let size = Size { x: 4, y: 2, z: 2 };
+        let mut material = Volume::new(size, lookup.id("Water"));
+        let mut temperature = Volume::new(size, 360.0);
+        for (coord, t) in temperature.iter_mut_coords() {
+            if coord.x < 2 {
+                *t = 265.0;
+                material.set(coord.x, coord.y, coord.z, lookup.id("Ice"));
+            }
+        }
+        for integrator in INTEGRATORS {
+            let phase_changes = assert_conserves_energy(&lookup, &material, &temperature, integrator, 50.0, 200);
+            assert!(phase_changes > 0, "{:?}: no ice melted", integrator);
+        }
+    }
+
+    #[test]
+    fn radiation_is_part_of_the_energy_balance() {
+        let mut lookup = iron_lookup();
+        lookup.add(materials::AIR);
+        let (mut material, mut temperature) = iron_block(&lookup);
+        material.set(1, 1, 0, lookup.id("Air"));
+        temperature.data.iter_mut().for_each(|t| *t += 600.0);
+        let radiation = Some(Radiation { ambient: 300.0 });
+        let limit = max_stable_time_step(&material, &lookup, &BoundaryConditions::default());
+        for integrator in INTEGRATORS {
+            assert_conserves_energy_with_radiation(&lookup, &material, &temperature, integrator, radiation, limit * 5.0, 50);
+        }
+        let mut simulation = HeatSimulation::new(material, temperature, &lookup, HeatIntegrator::Explicit, BoundaryConditions::default());
+        simulation.radiation = radiation;
+        simulation.enable_energy_diagnostics(1e-4);
+        simulation.step(limit * 5.0).unwrap();
+        let radiated = simulation.energy_report().unwrap().boundary_energy.radiation;
+        assert!(radiated < 0.0);
+    }
+
+    //  A density curve changes the mass of the voxels as they heat, energy still balances
+    //  because the temperature follows the enthalpy of ρ(T) c V.
+    #[test]
+    fn energy_is_conserved_with_a_density_curve() {
+        let mut expanding = materials::IRON;
+        expanding.name = "Expanding Iron";
+        expanding.curves.density = Some(PropertyCurve::new(&[(300.0, 7870.0), (900.0, 6000.0)]));
+        let mut lookup = VoxelMaterialLookup::new(0.01);
+        lookup.add(expanding);
+        let id = lookup.id("Expanding Iron");
+        let volume = 0.01f32.powi(3);
+        for (t, density) in [(300.0, 7870.0), (600.0, 6935.0), (900.0, 6000.0)] {
+            let mass = lookup.at_temperature(id, t).mass;
+            assert!((mass - density * volume).abs() < density * volume * 1e-3, "{}K {}kg", t, mass);
+        }
+
+        let size = Size { x: 4, y: 3, z: 2 };
+        let material = Volume::new(size, id);
+        let mut temperature = Volume::new(size, 300.0);
+        temperature.set(0, 0, 0, 900.0);
+        let limit = max_stable_time_step(&material, &lookup, &BoundaryConditions::default());
+        for integrator in INTEGRATORS {
+            assert_conserves_energy(&lookup, &material, &temperature, integrator, limit * 5.0, 50);
+        }
+    }
+
+    //  A lone voxel radiates from all six faces, ε σ 6 L² (T⁴ - T_ambient⁴).
+    #[test]
+    fn an_isolated_hot_voxel_radiates_from_every_face() {
+        let mut lookup = VoxelMaterialLookup::new(0.1);
+        lookup.add(materials::TUNGSTEN);
+        let size = Size { x: 1, y: 1, z: 1 };
+        let (hot, ambient) = (kelvin::TUNGSTEN_MELTING, kelvin::ROOM_TEMPERATURE);
+        let material = Volume::new(size, lookup.id("Tungsten"));
+        let temperature = Volume::new(size, hot);
+        let mut simulation = HeatSimulation::new(material, temperature, &lookup, HeatIntegrator::Explicit, BoundaryConditions::default());
+        simulation.radiation = Some(Radiation { ambient });
+        simulation.enable_energy_diagnostics(1e-4);
+        let start = simulation.total_energy();
+        let time = 0.01;
+        simulation.step(time).unwrap();
+        let lost = start - simulation.total_energy();
+
+        let tungsten = &lookup.materials[0];
+        let area = 6.0 * lookup.length as f64 * lookup.length as f64;
+        let expected = tungsten.emissivity as f64 * crate::physics::radiation::STEFAN_BOLTZMANN as f64 * area
+            * ((hot as f64).powi(4) - (ambient as f64).powi(4)) * time as f64;
+        assert!((lost - expected).abs() < expected * 0.01, "lost {} J, expected {} J", lost, expected);
+        let radiated = simulation.energy_report().unwrap().boundary_energy.radiation;
+        assert!((radiated + lost).abs() < expected * 0.01, "radiation {} J, lost {} J", radiated, lost);
+    }
+
+    #[test]
+    fn set_lookup_rebuilds_phase_changes_and_the_kernel() {
+        let water_lookup = |melting_point: Temperature| {
+            let mut ice = materials::ICE;
+            let mut water = materials::WATER;
+            ice.transitions.melting_point = melting_point;
+            water.transitions.melting_point = melting_point;
+            let mut lookup = VoxelMaterialLookup::new(0.01);
+            lookup.add(ice);
+            lookup.add(water);
+            lookup
+        };
+        let before = water_lookup(kelvin::WATER_FREEZING);
+        let after = water_lookup(250.0);
+        let size = Size { x: 2, y: 1, z: 1 };
+        let material = Volume::new(size, before.id("Ice"));
+        let temperature = Volume::new(size, 260.0);
+        let mut simulation = HeatSimulation::new(material, temperature, &before, HeatIntegrator::Explicit, BoundaryConditions::default());
+        simulation.enable_energy_diagnostics(1e-4);
+        assert_eq!(simulation.step(1.0).unwrap().phase_changes, 0);
+
+        assert!(simulation.latent.data.iter().all(|&e| e == 0.0));
+
+        //  above the new melting point the ice starts to melt, storing its excess energy.
+        simulation.set_lookup(&after);
+        simulation.step(1.0).unwrap();
+        assert!(simulation.temperature.data.iter().all(|&t| t == 250.0));
+        assert!(simulation.latent.data.iter().all(|&e| e > 0.0));
+        assert!(simulation.energy_report().unwrap().within_tolerance);
+        let kernel = HeatTransferKernel::at_temperature(&simulation.material, &simulation.temperature, &after, &simulation.boundaries);
+        assert_eq!(simulation.max_time_step(), kernel.max_stable_time_step());
+    }
+}
@@ -1,3 +1,5 @@
+use crate::physics::boundary::BoundaryConditions;
+use crate::physics::radiation::{radiating_coefficient, Radiation};
 use crate::physics::voxel_material_lookup::VoxelMaterialLookup;
 use crate::physics::*;
 
@@ -16,35 +18,39 @@ fn calculate_heat_transfer_voxel(
     heat_transfer_rate
 }
 
+//  radiation adds the exchange between exposed surfaces and the surroundings, None leaves it out.
 pub fn calculate_heat_transfer_volume(
     material: &Volume<MaterialId>,
     temperature: &Volume<Temperature>,
     heat: &mut Volume<HeatTransferRate>,
     lookup: &VoxelMaterialLookup,
+    boundaries: &BoundaryConditions,
+    radiation: Option<&Radiation>,
 ) {
-    let size = &material.size;
-    for z in 0 .. size.z {
-        for y in 0 .. size.y {
-            for x in 0 .. size.x {
-                let to_index = heat.index(x, y, z);    // we know this is linear, could increment.
-                let to_mat = &lookup.materials[material.data[to_index] as usize];
-                let to_temp = temperature.data[to_index];
-                let mut heat_transfer_rate = 0.0;
-                let mut add_heat = |from_index: usize| {
-                    let from_mat = &lookup.materials[material.data[from_index] as usize];
-                    let from_temp = temperature.data[from_index];
-                    heat_transfer_rate += calculate_heat_transfer_voxel(from_mat, from_temp, to_mat, to_temp);
-                };
-                //  only add heat for values which are not on the boundaries
-                if x > 0 { add_heat(to_index - 1); }
-                if x + 1 < size.x { add_heat(to_index + 1); }
-                if y > 0 { add_heat(to_index - size.x); }
-                if y + 1 < size.y { add_heat(to_index + size.x); }
-                if z > 0 { add_heat(to_index - size.x * size.y); }
-                if z + 1 < size.z { add_heat(to_index + size.x + size.y); }
-                heat.set(x, y, z, heat_transfer_rate);
-            }
+    let periodic = boundaries.periodic();
+    for (coord, to_material) in material.iter_coords() {
+        let to_index = material.size.index(coord);
+        let to_temp = temperature.data[to_index];
+        let to_mat = &lookup.at_temperature(*to_material, to_temp);
+        let mut heat_transfer_rate = 0.0;
+        for face in Face::ALL {
+            let from = match material.face_neighbor(coord, face) {
+                Some(from) => from,
+                None if periodic[face.axis()] => material.wrapped_face_neighbor(coord, face),
+                None => {
+                    heat_transfer_rate += boundaries.get(face).term(to_mat, lookup.length).heat(to_temp);
+                    continue;
+                }
+            };
+            let from_index = material.size.index(from);
+            let from_temp = temperature.data[from_index];
+            let from_mat = &lookup.at_temperature(material.data[from_index], from_temp);
+            heat_transfer_rate += calculate_heat_transfer_voxel(from_mat, from_temp, to_mat, to_temp);
+        }
+        if let Some(radiation) = radiation {
+            heat_transfer_rate += radiation.heat(radiating_coefficient(material, coord, to_mat, lookup, boundaries), to_temp);
         }
+        heat.data[to_index] = heat_transfer_rate;
     }
 }
 
@@ -56,11 +62,58 @@ pub fn apply_heat_to_volume(
     time: Time,
 ) {
     for i in 0 .. temperature.data.len() {
-        let mat = material_lookup.materials[material.data[i] as usize];
         //  heat is power, power * time = energy.
         let heat_energy = heat.data[i] * time;
         //  energy / heat capacity = temperature change in kelvin.
-        let temp_change = heat_energy  / mat.heat_capacity;
-        temperature.data[i] += temp_change;
+        temperature.data[i] = material_lookup.add_energy(material.data[i], temperature.data[i], heat_energy);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //  Two voxels stacked in z, the hand rolled +z offset used to miss this neighbor.
+    #[test]
+    fn heat_flows_across_z() {
+        let mut lookup = VoxelMaterialLookup::new(0.1);
+        lookup.add(materials::IRON);
+        let size = Size { x: 1, y: 1, z: 2 };
+        let material = Volume::new(size, 0);
+        let mut temperature = Volume::new(size, 300.0);
+        temperature.set(0, 0, 0, 400.0);
+        let mut heat = Volume::new(size, 0.0);
+        calculate_heat_transfer_volume(&material, &temperature, &mut heat, &lookup, &BoundaryConditions::default(), None);
+        let below = heat.get(0, 0, 0);
+        let above = heat.get(0, 0, 1);
+        assert!(above > 0.0, "no heat flowed into the +z voxel");
+        assert_eq!(below, -above);
+
+        apply_heat_to_volume(&material, &mut temperature, &heat, &lookup, 1.0);
+        assert!(temperature.get(0, 0, 0) < 400.0);
+        assert!(temperature.get(0, 0, 1) > 300.0);
+    }
+
+    //  On a wider volume the +z neighbor of a voxel is a whole slab away.
+    #[test]
+    fn heat_flows_to_the_voxel_directly_above() {
+        let mut lookup = VoxelMaterialLookup::new(0.1);
+        lookup.add(materials::IRON);
+        let size = Size { x: 3, y: 2, z: 2 };
+        let material = Volume::new(size, 0);
+        let mut temperature = Volume::new(size, 300.0);
+        temperature.set(1, 1, 0, 400.0);
+        let mut heat = Volume::new(size, 0.0);
+        calculate_heat_transfer_volume(&material, &temperature, &mut heat, &lookup, &BoundaryConditions::default(), None);
+        let hot = Coord::new(1, 1, 0);
+        let neighbors: Vec<Coord> = material.face_neighbors(hot).map(|(_, n)| n).collect();
+        assert!(neighbors.contains(&Coord::new(1, 1, 1)));
+        for (coord, &h) in heat.iter_coords() {
+            if neighbors.contains(&coord) {
+                assert!(h > 0.0, "{:?} should be heated", coord);
+            } else if coord != hot {
+                assert_eq!(h, 0.0, "{:?} should not be heated", coord);
+            }
+        }
     }
 }
@@ -0,0 +1,443 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+use bevy::utils::HashMap;
+use crate::physics::*;
+
+//  CPU rendering of volume slices to PNG images, for runs on machines without a GPU.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SliceError {
+    OutOfRange { axis: Axis, index: usize, size: Size },
+    //  a volume with no voxels along some axis, which would make an image with no pixels.
+    Empty(Size),
+}
+
+impl fmt::Display for SliceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SliceError::OutOfRange { axis, index, size } => {
+                write!(f, "slice {} along {:?} is outside of a {} x {} x {} volume", index, axis, size.x, size.y, size.z)
+            }
+            SliceError::Empty(size) => write!(f, "cannot render a slice of an empty {} x {} x {} volume", size.x, size.y, size.z),
+        }
+    }
+}
+
+impl std::error::Error for SliceError {}
+
+impl From<SliceError> for io::Error {
+    fn from(error: SliceError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Colormap {
+    Viridis,
+    Inferno,
+    //  values are temperatures in Kelvin colored as a glowing black body, so the range
+    //  only sets the legend. Anything below the 798K Draper point is black.
+    Blackbody,
+}
+
+//  Matplotlib's colormaps sampled at tenths.
+const VIRIDIS: [[u8; 3]; 11] = [
+    [68, 1, 84], [72, 36, 117], [65, 68, 135], [53, 95, 141], [42, 120, 142], [33, 145, 140],
+    [34, 168, 132], [68, 191, 112], [122, 209, 81], [189, 223, 38], [253, 231, 37],
+];
+const INFERNO: [[u8; 3]; 11] = [
+    [0, 0, 4], [22, 11, 57], [66, 10, 104], [106, 23, 110], [147, 38, 103], [188, 55, 84],
+    [221, 81, 58], [243, 120, 25], [252, 165, 10], [246, 215, 70], [252, 255, 164],
+];
+const NOT_FINITE: [u8; 3] = [255, 0, 255];
+const BACKGROUND: [u8; 3] = [32, 32, 32];
+const TEXT: [u8; 3] = [230, 230, 230];
+
+fn lerp_table(table: &[[u8; 3]], t: f32) -> [u8; 3] {
+    let x = t.clamp(0.0, 1.0) * (table.len() - 1) as f32;
+    let i = (x as usize).min(table.len() - 2);
+    let f = x - i as f32;
+    let mut color = [0; 3];
+    for c in 0 .. 3 {
+        color[c] = (table[i][c] as f32 + (table[i + 1][c] as f32 - table[i][c] as f32) * f).round() as u8;
+    }
+    color
+}
+
+//  Tanner Helland's fit of black body chromaticity, faded in from the Draper point to 1500K.
+fn blackbody(temperature: Temperature) -> [u8; 3] {
+    let brightness = ((temperature - 798.0) / (1500.0 - 798.0)).clamp(0.0, 1.0);
+    let t = temperature.max(1000.0) / 100.0;
+    let r = if t <= 66.0 { 255.0 } else { 329.69873 * (t - 60.0).powf(-0.13320476) };
+    let g = if t <= 66.0 { 99.4708 * t.ln() - 161.11957 } else { 288.12216 * (t - 60.0).powf(-0.07551485) };
+    let b = if t >= 66.0 { 255.0 } else if t <= 19.0 { 0.0 } else { 138.51773 * (t - 10.0).ln() - 305.0448 };
+    [r, g, b].map(|c| (c.clamp(0.0, 255.0) * brightness).round() as u8)
+}
+
+impl Colormap {
+    pub fn color(&self, value: f32, min: f32, max: f32) -> [u8; 3] {
+        if !value.is_finite() {
+            return NOT_FINITE;
+        }
+        let t = if max > min { (value - min) / (max - min) } else { 0.5 };
+        match self {
+            Colormap::Viridis => lerp_table(&VIRIDIS, t),
+            Colormap::Inferno => lerp_table(&INFERNO, t),
+            Colormap::Blackbody => blackbody(value),
+        }
+    }
+}
+
+//  Colors of materials, ids without a color set get a hue spaced by the golden angle
+//  so neighboring ids are easy to tell apart.
+#[derive(Debug, Clone, Default)]
+pub struct MaterialColors {
+    pub colors: HashMap<MaterialId, [u8; 3]>,
+}
+
+impl MaterialColors {
+    pub fn set(&mut self, id: MaterialId, color: [u8; 3]) -> &mut Self {
+        self.colors.insert(id, color);
+        self
+    }
+
+    pub fn color(&self, id: MaterialId) -> [u8; 3] {
+        if let Some(&color) = self.colors.get(&id) {
+            return color;
+        }
+        let hue = (id as f32 * 137.50777) % 360.0;
+        let x = 1.0 - ((hue / 60.0) % 2.0 - 1.0).abs();
+        let (r, g, b) = match (hue / 60.0) as u32 {
+            0 => (1.0, x, 0.0),
+            1 => (x, 1.0, 0.0),
+            2 => (0.0, 1.0, x),
+            3 => (0.0, x, 1.0),
+            4 => (x, 0.0, 1.0),
+            _ => (1.0, 0.0, x),
+        };
+        //  muted so text on top stays readable.
+        [r, g, b].map(|c: f32| (60.0 + c * 170.0) as u8)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SliceImage {
+    pub width: usize,
+    pub height: usize,
+    //  row major from the top left.
+    pub pixels: Vec<[u8; 3]>,
+}
+
+impl SliceImage {
+    fn new(width: usize, height: usize) -> Self {
+        SliceImage { width, height, pixels: vec![BACKGROUND; width * height] }
+    }
+
+    fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, color: [u8; 3]) {
+        for py in y .. (y + height).min(self.height) {
+            for px in x .. (x + width).min(self.width) {
+                self.pixels[py * self.width + px] = color;
+            }
+        }
+    }
+
+    fn text(&mut self, x: usize, y: usize, text: &str) {
+        for (i, c) in text.chars().enumerate() {
+            let rows = glyph(c);
+            for (row, bits) in rows.iter().enumerate() {
+                for column in 0 .. 3 {
+                    if bits & (0b100 >> column) != 0 {
+                        let px = x + (i * 4 + column) * TEXT_SCALE;
+                        self.fill(px, y + row * TEXT_SCALE, TEXT_SCALE, TEXT_SCALE, TEXT);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn encode_png(&self) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut encoder = png::Encoder::new(&mut out, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(self.pixels.concat().as_slice())?;
+        writer.finish()?;
+        Ok(out)
+    }
+
+    pub fn save_png(&self, path: &Path) -> io::Result<()> {
+        std::fs::write(path, self.encode_png()?)
+    }
+}
+
+//  3 x 5 pixel digits for legend labels, one row of bits per line.
+const TEXT_SCALE: usize = 2;
+const TEXT_HEIGHT: usize = 5 * TEXT_SCALE;
+
+fn glyph(c: char) -> [u8; 5] {
+    match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        'e' => [0b000, 0b111, 0b111, 0b100, 0b111],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        _ => [0; 5],
+    }
+}
+
+fn text_width(text: &str) -> usize {
+    text.chars().count() * 4 * TEXT_SCALE
+}
+
+fn label(value: f32) -> String {
+    let magnitude = value.abs();
+    if magnitude != 0.0 && !(0.01 .. 100000.0).contains(&magnitude) { format!("{:.2e}", value) } else { format!("{:.2}", value) }
+}
+
+//  Width, height and the volume coordinate of each image pixel column and row of a slice,
+//  with the second axis pointing up the image.
+fn slice_layout(size: Size, axis: Axis, index: usize) -> Result<(usize, usize, impl Fn(usize, usize) -> Coord), SliceError> {
+    if size.product() == 0 {
+        return Err(SliceError::Empty(size));
+    }
+    let (width, height) = match axis {
+        Axis::X => (size.z, size.y),
+        Axis::Y => (size.x, size.z),
+        Axis::Z => (size.x, size.y),
+    };
+    let depth = match axis {
+        Axis::X => size.x,
+        Axis::Y => size.y,
+        Axis::Z => size.z,
+    };
+    if index >= depth {
+        return Err(SliceError::OutOfRange { axis, index, size });
+    }
+    let coord = move |u: usize, v: usize| {
+        let v = height - 1 - v;
+        match axis {
+            Axis::X => Coord::new(index, v, u),
+            Axis::Y => Coord::new(u, index, v),
+            Axis::Z => Coord::new(u, v, index),
+        }
+    };
+    Ok((width, height, coord))
+}
+
+const PADDING: usize = 8;
+const LEGEND_BAR: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeatmapOptions {
+    pub colormap: Colormap,
+    //  value range of the colormap, None for the range of the finite values in the slice.
+    pub range: Option<(f32, f32)>,
+    //  pixels per voxel.
+    pub scale: usize,
+    pub legend: bool,
+}
+
+impl Default for HeatmapOptions {
+    fn default() -> Self {
+        HeatmapOptions { colormap: Colormap::Viridis, range: None, scale: 16, legend: true }
+    }
+}
+
+//  A slice of a scalar field with a colormap and a bar labeled with its range.
+//  Values which are not finite are magenta.
+pub fn render_heatmap(volume: &Volume<f32>, axis: Axis, index: usize, options: &HeatmapOptions) -> Result<SliceImage, SliceError> {
+    let (width, height, coord) = slice_layout(volume.size, axis, index)?;
+    let value = |u: usize, v: usize| {
+        let c = coord(u, v);
+        volume.get(c.x, c.y, c.z)
+    };
+    let (min, max) = options.range.unwrap_or_else(|| {
+        (0 .. width * height)
+            .map(|i| value(i % width, i / width))
+            .filter(|v| v.is_finite())
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), v| (min.min(v), max.max(v)))
+    });
+    let (min, max) = if min > max { (0.0, 0.0) } else { (min, max) };
+
+    let scale = options.scale.max(1);
+    let unit = if options.colormap == Colormap::Blackbody { "K" } else { "" };
+    let labels = [label(max) + unit, label(min) + unit];
+    let legend_width = if options.legend {
+        PADDING + LEGEND_BAR + PADDING + labels.iter().map(|l| text_width(l)).max().unwrap_or(0) + PADDING
+    } else {
+        0
+    };
+    let slice_height = height * scale;
+    let mut image = SliceImage::new(width * scale + legend_width, slice_height.max(if options.legend { 3 * TEXT_HEIGHT } else { 0 }));
+    for v in 0 .. height {
+        for u in 0 .. width {
+            image.fill(u * scale, v * scale, scale, scale, options.colormap.color(value(u, v), min, max));
+        }
+    }
+    if options.legend {
+        let x = width * scale + PADDING;
+        let bar_height = image.height;
+        for y in 0 .. bar_height {
+            let t = 1.0 - y as f32 / (bar_height - 1).max(1) as f32;
+            image.fill(x, y, LEGEND_BAR, 1, options.colormap.color(min + (max - min) * t, min, max));
+        }
+        image.text(x + LEGEND_BAR + PADDING, 0, &labels[0]);
+        image.text(x + LEGEND_BAR + PADDING, image.height - TEXT_HEIGHT, &labels[1]);
+    }
+    Ok(image)
+}
+
+//  A slice of a material volume with a swatch and id for each material in it.
+pub fn render_materials(
+    volume: &Volume<MaterialId>,
+    axis: Axis,
+    index: usize,
+    colors: &MaterialColors,
+    scale: usize,
+    legend: bool,
+) -> Result<SliceImage, SliceError> {
+    let (width, height, coord) = slice_layout(volume.size, axis, index)?;
+    let material = |u: usize, v: usize| {
+        let c = coord(u, v);
+        volume.get(c.x, c.y, c.z)
+    };
+    let mut present: Vec<MaterialId> = (0 .. width * height).map(|i| material(i % width, i / width)).collect();
+    present.sort();
+    present.dedup();
+
+    let scale = scale.max(1);
+    let row = TEXT_HEIGHT + 4;
+    let legend_width = if legend {
+        PADDING + TEXT_HEIGHT + PADDING + present.iter().map(|id| text_width(&id.to_string())).max().unwrap_or(0) + PADDING
+    } else {
+        0
+    };
+    let legend_height = if legend { present.len() * row + PADDING } else { 0 };
+    let mut image = SliceImage::new(width * scale + legend_width, (height * scale).max(legend_height));
+    for v in 0 .. height {
+        for u in 0 .. width {
+            image.fill(u * scale, v * scale, scale, scale, colors.color(material(u, v)));
+        }
+    }
+    if legend {
+        let x = width * scale + PADDING;
+        for (i, &id) in present.iter().enumerate() {
+            let y = PADDING / 2 + i * row;
+            image.fill(x, y, TEXT_HEIGHT, TEXT_HEIGHT, colors.color(id));
+            image.text(x + TEXT_HEIGHT + PADDING, y, &id.to_string());
+        }
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slices_outside_the_volume_are_errors() {
+        let volume = Volume::new(Size { x: 4, y: 3, z: 2 }, 300.0);
+        let options = HeatmapOptions { scale: 2, ..Default::default() };
+        let image = render_heatmap(&volume, Axis::Z, 1, &options).unwrap();
+        assert_eq!(image.height, (3 * 2).max(3 * TEXT_HEIGHT));
+        assert!(!image.encode_png().unwrap().is_empty());
+        assert!(matches!(render_heatmap(&volume, Axis::Z, 2, &options), Err(SliceError::OutOfRange { index: 2, .. })));
+        assert!(matches!(render_heatmap(&volume, Axis::X, 4, &options), Err(SliceError::OutOfRange { .. })));
+        let materials = Volume::new(volume.size, 0);
+        assert!(render_materials(&materials, Axis::Y, 2, &MaterialColors::default(), 2, false).is_ok());
+        assert!(render_materials(&materials, Axis::Y, 3, &MaterialColors::default(), 2, false).is_err());
+    }
+
+    #[test]
+    fn empty_volumes_are_errors() {
+        for size in [Size { x: 0, y: 3, z: 2 }, Size { x: 4, y: 0, z: 2 }, Size { x: 4, y: 3, z: 0 }] {
+            let volume = Volume::new(size, 300.0);
+            let options = HeatmapOptions { legend: false, ..Default::default() };
+            assert!(matches!(render_heatmap(&volume, Axis::Z, 0, &options), Err(SliceError::Empty(_))));
+            let materials = Volume::new(size, 0);
+            assert!(matches!(render_materials(&materials, Axis::Z, 0, &MaterialColors::default(), 4, true), Err(SliceError::Empty(_))));
+        }
+    }
+
+    #[test]
+    fn colormaps_span_their_tables() {
+        for (colormap, table) in [(Colormap::Viridis, VIRIDIS), (Colormap::Inferno, INFERNO)] {
+            assert_eq!(colormap.color(250.0, 250.0, 350.0), table[0]);
+            assert_eq!(colormap.color(300.0, 250.0, 350.0), table[5]);
+            assert_eq!(colormap.color(350.0, 250.0, 350.0), table[10]);
+            //  values outside of the range clamp to the ends.
+            assert_eq!(colormap.color(0.0, 250.0, 350.0), table[0]);
+            assert_eq!(colormap.color(1000.0, 250.0, 350.0), table[10]);
+        }
+    }
+
+    #[test]
+    fn blackbody_glows_above_the_draper_point() {
+        for temperature in [0.0, 300.0, 797.0, 798.0] {
+            assert_eq!(Colormap::Blackbody.color(temperature, 0.0, 1.0), [0, 0, 0]);
+        }
+        let dim = Colormap::Blackbody.color(1000.0, 0.0, 1.0);
+        let bright = Colormap::Blackbody.color(1500.0, 0.0, 1.0);
+        assert_ne!(dim, [0, 0, 0]);
+        assert!(dim[0] < bright[0]);
+        assert_eq!(bright[0], 255);
+        //  the range only sets the legend.
+        assert_eq!(Colormap::Blackbody.color(3000.0, 0.0, 1.0), Colormap::Blackbody.color(3000.0, 2000.0, 4000.0));
+    }
+
+    #[test]
+    fn non_finite_values_are_magenta() {
+        for colormap in [Colormap::Viridis, Colormap::Inferno, Colormap::Blackbody] {
+            for value in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+                assert_eq!(colormap.color(value, 0.0, 1.0), NOT_FINITE);
+            }
+        }
+        let mut volume = Volume::new(Size { x: 2, y: 1, z: 1 }, 300.0);
+        volume.set(1, 0, 0, f32::NAN);
+        let options = HeatmapOptions { scale: 1, legend: false, ..Default::default() };
+        let image = render_heatmap(&volume, Axis::Z, 0, &options).unwrap();
+        assert_eq!(image.pixels, vec![VIRIDIS[5], NOT_FINITE]);
+    }
+
+    #[test]
+    fn the_second_axis_points_up() {
+        let options = HeatmapOptions { range: Some((0.0, 1.0)), scale: 1, legend: false, ..Default::default() };
+        let mut volume = Volume::new(Size { x: 3, y: 2, z: 4 }, 0.0);
+        volume.set(2, 0, 3, 1.0);
+        //  Z slices are x across and y up, so y 0 is the bottom row.
+        let image = render_heatmap(&volume, Axis::Z, 3, &options).unwrap();
+        assert_eq!((image.width, image.height), (3, 2));
+        assert_eq!(image.pixels[image.width + 2], VIRIDIS[10]);
+        assert_eq!(image.pixels[2], VIRIDIS[0]);
+        //  X slices are z across and y up.
+        let image = render_heatmap(&volume, Axis::X, 2, &options).unwrap();
+        assert_eq!((image.width, image.height), (4, 2));
+        assert_eq!(image.pixels[image.width + 3], VIRIDIS[10]);
+        //  Y slices are x across and z up, so z 3 is the top row.
+        let mut materials = Volume::new(volume.size, 0);
+        materials.set(2, 0, 3, 5);
+        let colors = MaterialColors::default();
+        let image = render_materials(&materials, Axis::Y, 0, &colors, 1, false).unwrap();
+        assert_eq!((image.width, image.height), (3, 4));
+        assert_eq!(image.pixels[2], colors.color(5));
+        assert_eq!(image.pixels[3 * image.width + 2], colors.color(0));
+    }
+}
@@ -0,0 +1,388 @@
+use crate::physics::boundary::BoundaryConditions;
+use crate::physics::heat_transfer::{apply_heat_to_volume, calculate_heat_transfer_volume};
+use crate::physics::heat_transfer_kernel::HeatTransferKernel;
+use crate::physics::voxel_material_lookup::VoxelMaterialLookup;
+use crate::physics::*;
+
+//  Selects how temperature is advanced over a time step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeatIntegrator {
+    //  forward Euler, cheap but only stable for small time steps.
+    Explicit,
+    //  backward Euler, unconditionally stable and first order accurate.
+    BackwardEuler,
+    //  trapezoidal rule, unconditionally stable and second order accurate.
+    //  Stable is not the same as smooth, at time steps far above the explicit limit stiff
+    //  voxels such as thin high conductivity ones overshoot and oscillate around the
+    //  solution, decaying only slowly. Use BackwardEuler for large steps.
+    CrankNicolson,
+}
+
+impl HeatIntegrator {
+    //  weight of the end of step temperatures in the heat flow.
+    pub fn theta(&self) -> f64 {
+        match self {
+            HeatIntegrator::Explicit => 0.0,
+            HeatIntegrator::BackwardEuler => 1.0,
+            HeatIntegrator::CrankNicolson => 0.5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConjugateGradientSettings {
+    pub max_iterations: usize,
+    //  residual relative to the right hand side at which we stop.
+    pub tolerance: f64,
+}
+
+impl Default for ConjugateGradientSettings {
+    fn default() -> Self {
+        ConjugateGradientSettings { max_iterations: 1000, tolerance: 1.0e-8 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConjugateGradientResult {
+    pub iterations: usize,
+    pub relative_residual: f64,
+}
+
+//  Advances temperature by time using the chosen integrator.
+//  heat receives the average heat transfer rate into each voxel over the step.
+//  The implicit system is assembled on every call, HeatSimulation keeps it between steps.
+pub fn integrate_heat(
+    integrator: HeatIntegrator,
+    material: &Volume<MaterialId>,
+    temperature: &mut Volume<Temperature>,
+    heat: &mut Volume<HeatTransferRate>,
+    lookup: &VoxelMaterialLookup,
+    boundaries: &BoundaryConditions,
+    time: Time,
+) -> ConjugateGradientResult {
+    match integrator {
+        HeatIntegrator::Explicit => {
+            calculate_heat_transfer_volume(material, temperature, heat, lookup, boundaries, None);
+            apply_heat_to_volume(material, temperature, heat, lookup, time);
+            ConjugateGradientResult::default()
+        }
+        _ => {
+            let system = ImplicitHeatSystem::at_temperature(material, temperature, lookup, boundaries, time, integrator.theta());
+            system.solve(temperature, heat, ConjugateGradientSettings::default())
+        }
+    }
+}
+
+//  The sparse linear system for one implicit step
+//      (C / dt + θ L) T' = C / dt T - (1 - θ) L T
+//  where C is the voxel heat capacity and L is the conductance Laplacian built
+//  from the thermal resistance between face neighbors. The matrix is the 7 point
+//  stencil so it is stored as a diagonal plus the +x, +y and +z conductances.
+//  Boundary conditions add to the diagonal and the right hand side.
+//  Voxels with infinite heat capacity keep their temperature and are eliminated
+//  into the right hand side, which keeps the matrix symmetric positive definite.
+//  Property curves are evaluated at the start of step temperatures and held over the step.
+pub struct ImplicitHeatSystem {
+    pub size: Size,
+    pub time: Time,
+    pub theta: f64,
+    pub heat_capacity: Vec<f64>,
+    pub diagonal: Vec<f64>,
+    pub fixed: Vec<bool>,
+    kernel: HeatTransferKernel,
+}
+
+impl ImplicitHeatSystem {
+    pub fn new(
+        material: &Volume<MaterialId>,
+        lookup: &VoxelMaterialLookup,
+        boundaries: &BoundaryConditions,
+        time: Time,
+        theta: f64,
+    ) -> Self {
+        let kernel = HeatTransferKernel::new(material, lookup, boundaries);
+        let heat_capacity = material.data.iter()
+            .map(|&id| lookup.materials[id as usize].heat_capacity as f64)
+            .collect();
+        Self::build(material.size, kernel, heat_capacity, time, theta)
+    }
+
+    pub fn at_temperature(
+        material: &Volume<MaterialId>,
+        temperature: &Volume<Temperature>,
+        lookup: &VoxelMaterialLookup,
+        boundaries: &BoundaryConditions,
+        time: Time,
+        theta: f64,
+    ) -> Self {
+        let kernel = HeatTransferKernel::at_temperature(material, temperature, lookup, boundaries);
+        let heat_capacity = material.data.iter().zip(temperature.data.iter())
+            .map(|(&id, &t)| lookup.heat_capacity(id, t) as f64)
+            .collect();
+        Self::build(material.size, kernel, heat_capacity, time, theta)
+    }
+
+    fn build(size: Size, kernel: HeatTransferKernel, heat_capacity: Vec<f64>, time: Time, theta: f64) -> Self {
+        let mut system = ImplicitHeatSystem {
+            size,
+            time,
+            theta,
+            heat_capacity,
+            diagonal: Vec::new(),
+            fixed: Vec::new(),
+            kernel,
+        };
+        system.assemble();
+        system
+    }
+
+    fn assemble(&mut self) {
+        let (time, theta) = (self.time as f64, self.theta);
+        self.diagonal.clear();
+        self.diagonal.extend(self.heat_capacity.iter().zip(self.kernel.boundary_conductance.iter())
+            .map(|(c, g)| c / time + theta * *g as f64));
+        let diagonal = &mut self.diagonal;
+        self.kernel.for_each_link(|a, b, g| {
+            diagonal[a] += theta * g;
+            diagonal[b] += theta * g;
+        });
+        self.fixed.clear();
+        self.fixed.extend(self.heat_capacity.iter().zip(self.diagonal.iter())
+            .map(|(c, d)| c.is_infinite() || *d == 0.0));
+    }
+
+    //  Re-evaluates property curves at the current temperatures in place and reassembles.
+    pub fn update_temperature(
+        &mut self,
+        material: &Volume<MaterialId>,
+        temperature: &Volume<Temperature>,
+        lookup: &VoxelMaterialLookup,
+        boundaries: &BoundaryConditions,
+    ) {
+        self.kernel.update_temperature(material, temperature, lookup, boundaries);
+        for (i, (&id, &t)) in material.data.iter().zip(temperature.data.iter()).enumerate() {
+            if lookup.tables[id as usize].is_some() {
+                self.heat_capacity[i] = lookup.heat_capacity(id, t) as f64;
+            }
+        }
+        self.assemble();
+    }
+
+    //  Reuses the conductances and heat capacities for another time step or integrator,
+    //  only the diagonal depends on them.
+    pub fn set_time_step(&mut self, time: Time, theta: f64) {
+        if time != self.time || theta != self.theta {
+            self.time = time;
+            self.theta = theta;
+            self.assemble();
+        }
+    }
+
+    //  y = A x over the free voxels, fixed voxels map to themselves.
+    fn multiply(&self, x: &[f64], y: &mut [f64]) {
+        for i in 0 .. x.len() {
+            y[i] = if self.fixed[i] { x[i] } else { self.diagonal[i] * x[i] };
+        }
+        self.kernel.for_each_link(|a, b, g| {
+            if !self.fixed[a] && !self.fixed[b] {
+                y[a] -= self.theta * g * x[b];
+                y[b] -= self.theta * g * x[a];
+            }
+        });
+    }
+
+    fn right_hand_side(&self, temperature: &[Temperature]) -> Vec<f64> {
+        let dt = self.time as f64;
+        let mut b: Vec<f64> = (0 .. temperature.len())
+            .map(|i| {
+                let t = temperature[i] as f64;
+                if self.fixed[i] {
+                    return t;
+                }
+                let boundary_conductance = self.kernel.boundary_conductance[i] as f64;
+                let boundary_source = self.kernel.boundary_source[i] as f64;
+                self.heat_capacity[i] / dt * t + boundary_source - (1.0 - self.theta) * boundary_conductance * t
+            })
+            .collect();
+        self.kernel.for_each_link(|i, j, g| {
+            let ti = temperature[i] as f64;
+            let tj = temperature[j] as f64;
+            //  explicit part of the flow from j into i.
+            let flow = (1.0 - self.theta) * g * (tj - ti);
+            if !self.fixed[i] {
+                b[i] += flow;
+                if self.fixed[j] { b[i] += self.theta * g * tj; }
+            }
+            if !self.fixed[j] {
+                b[j] -= flow;
+                if self.fixed[i] { b[j] += self.theta * g * ti; }
+            }
+        });
+        b
+    }
+
+    //  Solves for the end of step temperature using Jacobi preconditioned conjugate gradient.
+    pub fn solve(
+        &self,
+        temperature: &mut Volume<Temperature>,
+        heat: &mut Volume<HeatTransferRate>,
+        settings: ConjugateGradientSettings,
+    ) -> ConjugateGradientResult {
+        let n = temperature.data.len();
+        let b = self.right_hand_side(&temperature.data);
+        let inverse_diagonal: Vec<f64> = (0 .. n)
+            .map(|i| if self.fixed[i] { 1.0 } else { 1.0 / self.diagonal[i] })
+            .collect();
+        let b_norm = dot(&b, &b).sqrt().max(f64::MIN_POSITIVE);
+
+        //  start from the current temperature which is usually close.
+        let mut x: Vec<f64> = temperature.data.iter().map(|&t| t as f64).collect();
+        let mut r = vec![0.0; n];
+        self.multiply(&x, &mut r);
+        for i in 0 .. n {
+            r[i] = b[i] - r[i];
+        }
+        let mut z: Vec<f64> = r.iter().zip(&inverse_diagonal).map(|(r, d)| r * d).collect();
+        let mut p = z.clone();
+        let mut ap = vec![0.0; n];
+        let mut rz = dot(&r, &z);
+        let mut result = ConjugateGradientResult { iterations: 0, relative_residual: dot(&r, &r).sqrt() / b_norm };
+
+        while result.relative_residual > settings.tolerance && result.iterations < settings.max_iterations {
+            self.multiply(&p, &mut ap);
+            let alpha = rz / dot(&p, &ap);
+            for i in 0 .. n {
+                x[i] += alpha * p[i];
+                r[i] -= alpha * ap[i];
+            }
+            for i in 0 .. n {
+                z[i] = r[i] * inverse_diagonal[i];
+            }
+            let rz_next = dot(&r, &z);
+            let beta = rz_next / rz;
+            rz = rz_next;
+            for i in 0 .. n {
+                p[i] = z[i] + beta * p[i];
+            }
+            result.iterations += 1;
+            result.relative_residual = dot(&r, &r).sqrt() / b_norm;
+        }
+
+        let dt = self.time as f64;
+        for i in 0 .. n {
+            let change = x[i] - temperature.data[i] as f64;
+            heat.data[i] = if self.fixed[i] { 0.0 } else { (self.heat_capacity[i] * change / dt) as HeatTransferRate };
+            temperature.data[i] = x[i] as Temperature;
+        }
+        result
+    }
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::boundary::BoundaryCondition;
+    use crate::physics::test::fill_volume_with_test_material;
+
+    struct Setup {
+        lookup: VoxelMaterialLookup,
+        material: Volume<MaterialId>,
+        temperature: Volume<Temperature>,
+        boundaries: BoundaryConditions,
+    }
+
+    fn setup() -> Setup {
+        let mut lookup = VoxelMaterialLookup::new(0.05);
+        lookup.add(materials::IRON);
+        lookup.add(materials::WOOD_HARD);
+        let size = Size { x: 6, y: 4, z: 3 };
+        let mut material = Volume::new(size, 0);
+        fill_volume_with_test_material(&mut material, &lookup);
+        let mut temperature = Volume::new(size, 300.0);
+        for (coord, t) in temperature.iter_mut_coords() {
+            *t += (coord.x * 40 + coord.z * 15) as Temperature;
+        }
+        let boundaries = BoundaryConditions::default()
+            .with(Face::NegativeX, BoundaryCondition::FixedTemperature(250.0))
+            .unwrap();
+        Setup { lookup, material, temperature, boundaries }
+    }
+
+    fn max_difference(a: &Volume<Temperature>, b: &Volume<Temperature>) -> Temperature {
+        a.data.iter().zip(b.data.iter())
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, |max, d| if d.is_nan() || max.is_nan() { f32::NAN } else { max.max(d) })
+    }
+
+    //  Temperatures after end_time in steps of end_time / steps, reusing one system.
+    fn run_implicit(s: &Setup, integrator: HeatIntegrator, end_time: Time, steps: usize) -> Volume<Temperature> {
+        let time = end_time / steps as Time;
+        //  assembled for another step first, as HeatSimulation reuses it.
+        let mut system = ImplicitHeatSystem::new(&s.material, &s.lookup, &s.boundaries, time * 2.0, 0.0);
+        system.set_time_step(time, integrator.theta());
+        let mut temperature = s.temperature.clone();
+        let mut heat = Volume::new(temperature.size, 0.0);
+        for _ in 0 .. steps {
+            let result = system.solve(&mut temperature, &mut heat, ConjugateGradientSettings::default());
+            assert!(result.relative_residual <= 1.0e-8, "did not converge {:?}", result);
+        }
+        temperature
+    }
+
+    #[test]
+    fn implicit_integrators_converge_to_explicit() {
+        let s = setup();
+        let kernel = HeatTransferKernel::new(&s.material, &s.lookup, &s.boundaries);
+        let limit = kernel.max_stable_time_step();
+        let end_time = limit * 20.0;
+
+        //  explicit reference in f64 at a small fraction of the stability limit,
+        //  f32 rounding over this many steps would be larger than the implicit error.
+        let reference_steps = 20000;
+        let dt = end_time as f64 / reference_steps as f64;
+        let mut t: Vec<f64> = s.temperature.data.iter().map(|&t| t as f64).collect();
+        let mut heat = vec![0.0; t.len()];
+        for _ in 0 .. reference_steps {
+            for (i, h) in heat.iter_mut().enumerate() {
+                *h = kernel.boundary_source[i] as f64 - kernel.boundary_conductance[i] as f64 * t[i];
+            }
+            kernel.for_each_link(|a, b, g| {
+                let flow = g * (t[b] - t[a]);
+                heat[a] += flow;
+                heat[b] -= flow;
+            });
+            for (i, t) in t.iter_mut().enumerate() {
+                *t += heat[i] * dt * kernel.inverse_heat_capacity[i] as f64;
+            }
+        }
+        let reference = Volume { size: s.temperature.size, data: t.iter().map(|&t| t as Temperature).collect() };
+        assert!(max_difference(&reference, &s.temperature) > 10.0, "the test should change temperatures");
+
+        let backward_coarse = max_difference(&run_implicit(&s, HeatIntegrator::BackwardEuler, end_time, 10), &reference);
+        let backward_fine = max_difference(&run_implicit(&s, HeatIntegrator::BackwardEuler, end_time, 100), &reference);
+        let crank_coarse = max_difference(&run_implicit(&s, HeatIntegrator::CrankNicolson, end_time, 10), &reference);
+        let crank_fine = max_difference(&run_implicit(&s, HeatIntegrator::CrankNicolson, end_time, 100), &reference);
+
+        //  first order, ten times smaller steps give about ten times less error.
+        assert!(backward_fine < backward_coarse / 5.0, "backward Euler {} then {}", backward_coarse, backward_fine);
+        assert!(backward_fine < 0.5, "backward Euler is {} K from explicit", backward_fine);
+        //  second order.
+        assert!(crank_fine < crank_coarse / 20.0, "Crank-Nicolson {} then {}", crank_coarse, crank_fine);
+        assert!(crank_fine < backward_fine, "Crank-Nicolson {} backward Euler {}", crank_fine, backward_fine);
+        assert!(crank_fine < 0.005, "Crank-Nicolson is {} K from explicit", crank_fine);
+    }
+
+    #[test]
+    fn set_time_step_matches_a_new_system() {
+        let s = setup();
+        let mut reused = ImplicitHeatSystem::new(&s.material, &s.lookup, &s.boundaries, 1.0, 1.0);
+        reused.set_time_step(7.0, 0.5);
+        let fresh = ImplicitHeatSystem::new(&s.material, &s.lookup, &s.boundaries, 7.0, 0.5);
+        assert_eq!(reused.diagonal, fresh.diagonal);
+        assert_eq!(reused.fixed, fresh.fixed);
+    }
+}
@@ -1,6 +1,6 @@
 pub const ABSOLUTE_ZERO: f32 = 0.0;
-pub const WATER_FREEZING: f32 = 0.0;
+pub const WATER_FREEZING: f32 = 273.15;
 pub const ROOM_TEMPERATURE: f32 = 295.0;
-pub const WATER_BOILING: f32 = 373.0;
+pub const WATER_BOILING: f32 = 373.15;
 pub const TUNGSTEN_MELTING: f32 = 3683.0;
 
@@ -0,0 +1,429 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+use bevy::prelude::{Event, Resource};
+use serde::Deserialize;
+use crate::physics::units::{JoulesPerKilogramKelvin, WattsPerMeterKelvin};
+use crate::physics::voxel_material_lookup::VoxelMaterialLookup;
+use crate::physics::*;
+
+#[derive(Debug)]
+pub enum MaterialError {
+    Io { path: PathBuf, error: std::io::Error },
+    Parse { path: Option<PathBuf>, error: ron::error::SpannedError },
+    DuplicateName(String),
+    InvalidDensity { name: String, density: Density },
+    UnknownPhase { name: String, phase: String },
+    //  a phase transition names a material which is not in the same file.
+    UnknownTransition { name: String, target: String },
+    InvalidCurve { name: String, property: &'static str, reason: &'static str },
+}
+
+impl fmt::Display for MaterialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MaterialError::Io { path, error } => write!(f, "could not read materials from {}: {}", path.display(), error),
+            MaterialError::Parse { path: Some(path), error } => write!(f, "could not parse materials in {}:{}", path.display(), error),
+            MaterialError::Parse { path: None, error } => write!(f, "could not parse materials: {}", error),
+            MaterialError::DuplicateName(name) => write!(f, "material \"{}\" is defined more than once", name),
+            MaterialError::InvalidDensity { name, density } => {
+                //  in g/cm3 as materials files give it.
+                write!(f, "material \"{}\" has density {} g/cm3, it must be positive", name, density.grams_per_cubic_centimeter())
+            }
+            MaterialError::UnknownPhase { name, phase } => {
+                write!(f, "material \"{}\" has unknown phase \"{}\", expected Solid, Grain, Liquid or Gas", name, phase)
+            }
+            MaterialError::UnknownTransition { name, target } => {
+                write!(f, "material \"{}\" changes phase into \"{}\" which is not defined", name, target)
+            }
+            MaterialError::InvalidCurve { name, property, reason } => {
+                write!(f, "material \"{}\" has an invalid {} curve, {}", name, property, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MaterialError {}
+
+//  One entry of a materials file, optional properties default to those of an inert material.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaterialDefinition {
+    name: String,
+    phase: String,
+    //  J/kg K
+    specific_heat_capacity: f32,
+    //  W/m K
+    thermal_conductivity: f32,
+    //  g/cm3, as in materials.rs
+    density: f32,
+    viscosity: Viscosity,
+    #[serde(default)]
+    emissivity: Emissivity,
+    #[serde(default)]
+    molar_mass: MolarMass,
+    #[serde(default)]
+    tensile_strength: Pressure,
+    #[serde(default)]
+    compressive_strength: Pressure,
+    #[serde(default)]
+    transitions: Option<TransitionsDefinition>,
+    #[serde(default)]
+    curves: Option<CurvesDefinition>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TransitionsDefinition {
+    #[serde(default = "infinity")]
+    melting_point: Temperature,
+    #[serde(default = "infinity")]
+    boiling_point: Temperature,
+    #[serde(default)]
+    latent_heat_of_fusion: SpecificLatentHeat,
+    #[serde(default)]
+    latent_heat_of_vaporization: SpecificLatentHeat,
+    solid: Option<String>,
+    liquid: Option<String>,
+    gas: Option<String>,
+}
+
+//  (temperature, value) points for each property which varies with temperature,
+//  in the same units as the constant property.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CurvesDefinition {
+    #[serde(default)]
+    thermal_conductivity: Option<Vec<(Temperature, f32)>>,
+    #[serde(default)]
+    specific_heat_capacity: Option<Vec<(Temperature, f32)>>,
+    //  g/cm3 like density.
+    #[serde(default)]
+    density: Option<Vec<(Temperature, f32)>>,
+}
+
+//  Curves are interned like names, each distinct list of points is leaked once and shared
+//  between reloads. Points are keyed by their bits as floats are not Eq.
+type CurveTable = HashMap<Vec<(u32, u32)>, &'static [(Temperature, f32)]>;
+
+//  Values are multiplied by scale to convert them to SI.
+fn intern_curve(points: &Option<Vec<(Temperature, f32)>>, scale: f32) -> Option<PropertyCurve> {
+    static CURVES: OnceLock<Mutex<CurveTable>> = OnceLock::new();
+    let points: Vec<(Temperature, f32)> = points.as_ref()?.iter().map(|&(t, value)| (t, value * scale)).collect();
+    let key = points.iter().map(|(t, value)| (t.to_bits(), value.to_bits())).collect();
+    let mut curves = CURVES.get_or_init(Default::default).lock().unwrap();
+    let interned = *curves.entry(key).or_insert_with(|| Box::leak(points.into_boxed_slice()));
+    Some(PropertyCurve::new(interned))
+}
+
+fn infinity() -> f32 {
+    f32::INFINITY
+}
+
+//  Materials keep &'static str names, so names read from files are leaked once each
+//  and shared between reloads.
+fn intern(name: &str) -> &'static str {
+    static NAMES: OnceLock<Mutex<HashSet<&'static str>>> = OnceLock::new();
+    let mut names = NAMES.get_or_init(Default::default).lock().unwrap();
+    match names.get(name) {
+        Some(&interned) => interned,
+        None => {
+            let interned: &'static str = Box::leak(name.to_owned().into_boxed_str());
+            names.insert(interned);
+            interned
+        }
+    }
+}
+
+fn parse_phase(name: &str, phase: &str) -> Result<PhysicsPhase, MaterialError> {
+    match phase {
+        "Solid" => Ok(PhysicsPhase::Solid),
+        "Grain" => Ok(PhysicsPhase::Grain),
+        "Liquid" => Ok(PhysicsPhase::Liquid),
+        "Gas" => Ok(PhysicsPhase::Gas),
+        _ => Err(MaterialError::UnknownPhase { name: name.to_owned(), phase: phase.to_owned() }),
+    }
+}
+
+impl MaterialDefinition {
+    fn to_physics_material(&self) -> Result<PhysicsMaterial, MaterialError> {
+        let transitions = match &self.transitions {
+            None => PhaseTransitions::NONE,
+            Some(t) => PhaseTransitions {
+                melting_point: t.melting_point,
+                boiling_point: t.boiling_point,
+                latent_heat_of_fusion: t.latent_heat_of_fusion,
+                latent_heat_of_vaporization: t.latent_heat_of_vaporization,
+                solid: t.solid.as_deref().map(intern),
+                liquid: t.liquid.as_deref().map(intern),
+                gas: t.gas.as_deref().map(intern),
+            },
+        };
+        let curves = match &self.curves {
+            None => PropertyCurves::NONE,
+            Some(c) => PropertyCurves {
+                thermal_conductivity: intern_curve(&c.thermal_conductivity, 1.0),
+                specific_heat_capacity: intern_curve(&c.specific_heat_capacity, 1.0),
+                density: intern_curve(&c.density, Density::from_grams_per_cubic_centimeter(1.0).0),
+            },
+        };
+        Ok(PhysicsMaterial {
+            name: intern(&self.name),
+            phase: parse_phase(&self.name, &self.phase)?,
+            specific_heat_capacity: JoulesPerKilogramKelvin(self.specific_heat_capacity),
+            thermal_conductivity: WattsPerMeterKelvin(self.thermal_conductivity),
+            density: Density::from_grams_per_cubic_centimeter(self.density),
+            viscosity: self.viscosity,
+            emissivity: self.emissivity,
+            molar_mass: self.molar_mass,
+            tensile_strength: self.tensile_strength,
+            compressive_strength: self.compressive_strength,
+            transitions,
+            curves,
+        })
+    }
+}
+
+fn validate_curve(name: &str, property: &'static str, curve: Option<PropertyCurve>) -> Result<(), MaterialError> {
+    let Some(curve) = curve else {
+        return Ok(());
+    };
+    let invalid = |reason| Err(MaterialError::InvalidCurve { name: name.to_owned(), property, reason });
+    if curve.points.is_empty() {
+        return invalid("it has no points");
+    }
+    if curve.points.windows(2).any(|w| w[0].0.partial_cmp(&w[1].0) != Some(Ordering::Less)) {
+        return invalid("temperatures must be increasing");
+    }
+    if curve.points.iter().any(|&(_, value)| value.partial_cmp(&0.0) != Some(Ordering::Greater)) {
+        return invalid("values must be positive");
+    }
+    Ok(())
+}
+
+//  Checks that names are unique, densities are positive, property curves are well formed
+//  and every phase transition names a material in the list.
+pub fn validate_materials(materials: &[PhysicsMaterial]) -> Result<(), MaterialError> {
+    let mut names = HashSet::new();
+    for mat in materials {
+        if !names.insert(mat.name) {
+            return Err(MaterialError::DuplicateName(mat.name.to_owned()));
+        }
+        //  NaN is rejected too.
+        if mat.density.0.partial_cmp(&0.0) != Some(Ordering::Greater) {
+            return Err(MaterialError::InvalidDensity { name: mat.name.to_owned(), density: mat.density });
+        }
+        validate_curve(mat.name, "thermal_conductivity", mat.curves.thermal_conductivity)?;
+        validate_curve(mat.name, "specific_heat_capacity", mat.curves.specific_heat_capacity)?;
+        validate_curve(mat.name, "density", mat.curves.density)?;
+    }
+    for mat in materials {
+        let t = &mat.transitions;
+        for target in [t.solid, t.liquid, t.gas].into_iter().flatten() {
+            if !names.contains(target) {
+                return Err(MaterialError::UnknownTransition { name: mat.name.to_owned(), target: target.to_owned() });
+            }
+        }
+    }
+    Ok(())
+}
+
+//  Parses and validates a RON list of materials, see assets/materials.ron.
+pub fn parse_materials(source: &str) -> Result<Vec<PhysicsMaterial>, MaterialError> {
+    let definitions: Vec<MaterialDefinition> = ron::from_str(source)
+        .map_err(|error| MaterialError::Parse { path: None, error })?;
+    let materials = definitions.iter()
+        .map(|d| d.to_physics_material())
+        .collect::<Result<Vec<_>, _>>()?;
+    validate_materials(&materials)?;
+    Ok(materials)
+}
+
+pub fn load_materials(path: &Path) -> Result<Vec<PhysicsMaterial>, MaterialError> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|error| MaterialError::Io { path: path.to_owned(), error })?;
+    parse_materials(&source).map_err(|e| match e {
+        MaterialError::Parse { path: None, error } => MaterialError::Parse { path: Some(path.to_owned()), error },
+        e => e,
+    })
+}
+
+//  Sent by the reload_materials system after the VoxelMaterialLookup resource changed.
+//  Anything built from the old properties must be rebuilt, HeatSimulation::set_lookup
+//  and FluidSolver::material_changed do that for the simulations.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct MaterialsReloaded;
+
+//  Materials loaded from a file which can be reloaded when the file changes.
+//  As a resource alongside a VoxelMaterialLookup resource it is reloaded by PhysicsPlugin.
+#[derive(Resource)]
+pub struct MaterialDatabase {
+    pub path: PathBuf,
+    pub materials: Vec<PhysicsMaterial>,
+    modified: Option<SystemTime>,
+}
+
+impl MaterialDatabase {
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, MaterialError> {
+        let path = path.into();
+        let modified = modified_time(&path);
+        let materials = load_materials(&path)?;
+        Ok(MaterialDatabase { path, materials, modified })
+    }
+
+    //  Adds every material to the lookup, replacing materials with the same name so
+    //  existing material ids in volumes stay valid.
+    pub fn apply(&self, lookup: &mut VoxelMaterialLookup) {
+        for mat in self.materials.iter() {
+            lookup.set(*mat);
+        }
+    }
+
+    //  Reloads and applies the file if it was modified since it was last read.
+    //  Returns true if the lookup changed, callers should then rebuild anything which caches
+    //  material properties, see MaterialsReloaded. On an error the previous materials are kept
+    //  and the file is not read again until it changes.
+    pub fn reload_if_changed(&mut self, lookup: &mut VoxelMaterialLookup) -> Result<bool, MaterialError> {
+        let modified = modified_time(&self.path);
+        if modified == self.modified {
+            return Ok(false);
+        }
+        self.modified = modified;
+        self.materials = load_materials(&self.path)?;
+        self.apply(lookup);
+        Ok(true)
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn material(name: &str, phase: &str, density: f32, transitions: &str) -> String {
+        format!(
+            "(name: \"{}\", phase: \"{}\", specific_heat_capacity: 1000.0, thermal_conductivity: 1.0, \
+             density: {}, viscosity: 0.0, transitions: {}),",
+            name, phase, density, transitions
+        )
+    }
+
+    fn parse(materials: &[String]) -> Result<Vec<PhysicsMaterial>, MaterialError> {
+        parse_materials(&format!("[{}]", materials.concat()))
+    }
+
+    #[test]
+    fn valid_materials_parse() {
+        let melts = "Some((melting_point: 300.0, latent_heat_of_fusion: 1000.0, liquid: Some(\"Melt\")))";
+        let materials = parse(&[material("Solid", "Solid", 2.0, melts), material("Melt", "Liquid", 1.5, "None")]).unwrap();
+        assert_eq!(materials.len(), 2);
+        assert_eq!(materials[0].transitions.liquid, Some("Melt"));
+        assert_eq!(materials[1].density.0, 1500.0);
+    }
+
+    #[test]
+    fn duplicate_names_are_rejected() {
+        let error = parse(&[material("Rock", "Solid", 2.0, "None"), material("Rock", "Grain", 1.0, "None")]).unwrap_err();
+        assert!(matches!(error, MaterialError::DuplicateName(ref name) if name == "Rock"), "{}", error);
+    }
+
+    #[test]
+    fn non_positive_densities_are_rejected() {
+        for density in [0.0, -1.0] {
+            let error = parse(&[material("Rock", "Solid", density, "None")]).unwrap_err();
+            assert!(matches!(error, MaterialError::InvalidDensity { ref name, .. } if name == "Rock"), "{}", error);
+            assert!(error.to_string().contains(&format!("density {} g/cm3", density)), "{}", error);
+        }
+    }
+
+    #[test]
+    fn unknown_phases_are_rejected() {
+        let error = parse(&[material("Rock", "Plasma", 2.0, "None")]).unwrap_err();
+        assert!(matches!(error, MaterialError::UnknownPhase { ref phase, .. } if phase == "Plasma"), "{}", error);
+    }
+
+    #[test]
+    fn missing_transition_targets_are_rejected() {
+        let melts = "Some((melting_point: 300.0, liquid: Some(\"Lava\")))";
+        let error = parse(&[material("Rock", "Solid", 2.0, melts)]).unwrap_err();
+        assert!(matches!(error, MaterialError::UnknownTransition { ref target, .. } if target == "Lava"), "{}", error);
+    }
+
+    #[test]
+    fn reload_picks_up_changes_and_keeps_ids() {
+        let path = std::env::temp_dir().join(format!("material_database_reload_{}.ron", std::process::id()));
+        std::fs::write(&path, format!("[{}]", material("Rock", "Solid", 2.0, "None"))).unwrap();
+        let mut database = MaterialDatabase::load(&path).unwrap();
+        let mut lookup = VoxelMaterialLookup::new(0.1);
+        lookup.add(materials::AIR);
+        database.apply(&mut lookup);
+        let rock = lookup.id("Rock");
+        assert!(!database.reload_if_changed(&mut lookup).unwrap());
+
+        let write = |source: String, seconds: u64| {
+            std::fs::write(&path, source).unwrap();
+            //  some file systems only keep whole seconds.
+            let modified = SystemTime::now() + std::time::Duration::from_secs(seconds);
+            std::fs::File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+        };
+        write(format!("[{}]", material("Rock", "Solid", 3.0, "None")), 10);
+        assert!(database.reload_if_changed(&mut lookup).unwrap());
+        assert_eq!(lookup.id("Rock"), rock);
+        assert_eq!(database.materials[0].density.0, 3000.0);
+        assert_eq!(lookup.materials[rock as usize].mass, database.materials[0].to_voxel_material(lookup.length).mass);
+
+        //  a broken file is reported once and the materials are kept.
+        write(format!("[{}]", material("Rock", "Solid", -3.0, "None")), 20);
+        assert!(database.reload_if_changed(&mut lookup).is_err());
+        assert!(!database.reload_if_changed(&mut lookup).unwrap());
+        assert_eq!(database.materials[0].density.0, 3000.0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn curves_are_shared_between_reloads() {
+        let curve = "Some((thermal_conductivity: Some([(200.0, 413.0), (1000.0, 352.0)])))";
+        let with_curve = |name: &str| format!(
+            "(name: \"{}\", phase: \"Solid\", specific_heat_capacity: 385.0, thermal_conductivity: 400.0, \
+             density: 8.96, viscosity: 0.0, curves: {}),",
+            name, curve,
+        );
+        let first = parse(&[with_curve("Copper")]).unwrap();
+        let second = parse(&[with_curve("Copper"), with_curve("Other Copper")]).unwrap();
+        let points = |mat: &PhysicsMaterial| mat.curves.thermal_conductivity.unwrap().points;
+        assert!(std::ptr::eq(points(&first[0]), points(&second[0])));
+        assert!(std::ptr::eq(points(&first[0]), points(&second[1])));
+    }
+
+    #[test]
+    fn density_curves_are_read_in_grams_per_cubic_centimeter() {
+        let curve = "Some((density: Some([(300.0, 7.87), (900.0, 6.0)])))";
+        let iron = format!(
+            "(name: \"Iron\", phase: \"Solid\", specific_heat_capacity: 450.0, thermal_conductivity: 80.0, \
+             density: 7.87, viscosity: 0.0, curves: {}),",
+            curve,
+        );
+        let materials = parse(&[iron]).unwrap();
+        let density = materials[0].curves.density.unwrap();
+        assert_eq!(density.sample(300.0), 7870.0);
+        assert_eq!(density.sample(900.0), 6000.0);
+        let error = parse(&[material("Rock", "Solid", 2.0, "None, curves: Some((density: Some([(300.0, 0.0)])))")]).unwrap_err();
+        assert!(matches!(error, MaterialError::InvalidCurve { property: "density", .. }), "{}", error);
+    }
+
+    //  The asset and the built in materials are two copies of the same table.
+    #[test]
+    fn the_materials_asset_matches_the_built_in_materials() {
+        let parsed = parse_materials(include_str!("../../assets/materials.ron")).unwrap();
+        assert_eq!(parsed.len(), materials::MATERIALS.len());
+        for (parsed, built_in) in parsed.iter().zip(materials::MATERIALS.iter()) {
+            assert_eq!(format!("{:?}", parsed), format!("{:?}", built_in));
+        }
+    }
+}
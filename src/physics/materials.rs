@@ -1,84 +1,209 @@
-use crate::physics::{PhysicsMaterial, PhysicsPhase};
+use crate::physics::units::{JoulesPerKilogramKelvin, WattsPerMeterKelvin};
+use crate::physics::{kelvin, Density, PhaseTransitions, PhysicsMaterial, PhysicsPhase, PropertyCurves};
 
-pub const MATERIALS: [PhysicsMaterial; 10] = [
-    PhysicsMaterial {
-        name: "Air",
-        phase: PhysicsPhase::Gas,
-        specific_heat_capacity: 1006.0,
-        thermal_conductivity: 0.024,
-        density:  0.0012,
-        viscosity: 0.0181,
-    },
-    PhysicsMaterial {
-        name: "Water",
-        phase: PhysicsPhase::Liquid,
-        specific_heat_capacity: 4200.0,
-        thermal_conductivity: 0.66,
-        density: 0.997,
-        viscosity: 1.0,
-    },
-    PhysicsMaterial {
-        name: "Rock",
-        specific_heat_capacity: 800.0,
-        thermal_conductivity: 4.0,
-        density: 2.65,
-        phase: PhysicsPhase::Solid,
-        viscosity: f32::INFINITY,
-    },
-    PhysicsMaterial {
-        name: "Water",
-        specific_heat_capacity: 2040.0,
-        thermal_conductivity: 2.18,
-        density: 0.997,
-        phase: PhysicsPhase::Solid,
-        viscosity: f32::INFINITY,
-    },
-    PhysicsMaterial {
-        name: "Iron",
-        specific_heat_capacity: 460.0,
-        thermal_conductivity: 50.0,
-        density: 7.874,
-        phase: PhysicsPhase::Solid,
-        viscosity: f32::INFINITY,
-    },
-    PhysicsMaterial {
-        name: "Dirt",
-        specific_heat_capacity: 800.0,
-        thermal_conductivity: 0.25,
-        density: 1.51,
-        phase: PhysicsPhase::Solid,
-        viscosity: f32::INFINITY,
-    },
-    PhysicsMaterial {
-        name: "Sand",
-        specific_heat_capacity: 830.0,
-        thermal_conductivity: 0.2,
-        density: 2.1,
-        phase: PhysicsPhase::Solid,
-        viscosity: f32::INFINITY,
-    },
-    PhysicsMaterial {
-        name: "Hardwood",
-        specific_heat_capacity: 2000.0,
-        thermal_conductivity: 0.16,
-        density: 0.65,
-        phase: PhysicsPhase::Solid,
-        viscosity: f32::INFINITY,
-    },
-    PhysicsMaterial {
-        name: "Softwood",
-        specific_heat_capacity: 2300.0,
-        thermal_conductivity: 0.12,
-        density: 0.49,
-        phase: PhysicsPhase::Solid,
-        viscosity: f32::INFINITY,
-    },
-    PhysicsMaterial {
-        name: "Infinite Heat Sink",
-        specific_heat_capacity: f32::INFINITY,
-        thermal_conductivity: 100.0,
-        density: 10.0,
-        phase: PhysicsPhase::Solid,
-        viscosity: f32::INFINITY,
-    }
-];
\ No newline at end of file
+//  Built in materials, assets/materials.ron defines the same set for material_database.
+
+const WATER_TRANSITIONS: PhaseTransitions = PhaseTransitions {
+    melting_point: kelvin::WATER_FREEZING,
+    boiling_point: kelvin::WATER_BOILING,
+    latent_heat_of_fusion: 334000.0,
+    latent_heat_of_vaporization: 2260000.0,
+    solid: Some("Ice"),
+    liquid: Some("Water"),
+    gas: Some("Steam"),
+};
+
+pub const AIR: PhysicsMaterial = PhysicsMaterial {
+    name: "Air",
+    phase: PhysicsPhase::Gas,
+    specific_heat_capacity: JoulesPerKilogramKelvin(1006.0),
+    thermal_conductivity: WattsPerMeterKelvin(0.024),
+    density: Density::from_grams_per_cubic_centimeter(0.0012),
+    viscosity: 0.0181,
+    emissivity: 0.0,
+    molar_mass: 0.02897,
+    tensile_strength: 0.0,
+    compressive_strength: 0.0,
+    transitions: PhaseTransitions::NONE,
+    curves: PropertyCurves::NONE,
+};
+
+pub const WATER: PhysicsMaterial = PhysicsMaterial {
+    name: "Water",
+    phase: PhysicsPhase::Liquid,
+    specific_heat_capacity: JoulesPerKilogramKelvin(4200.0),
+    thermal_conductivity: WattsPerMeterKelvin(0.66),
+    density: Density::from_grams_per_cubic_centimeter(0.997),
+    viscosity: 1.0,
+    emissivity: 0.96,
+    molar_mass: 0.018015,
+    tensile_strength: 0.0,
+    compressive_strength: 0.0,
+    transitions: WATER_TRANSITIONS,
+    curves: PropertyCurves::NONE,
+};
+
+pub const STEAM: PhysicsMaterial = PhysicsMaterial {
+    name: "Steam",
+    phase: PhysicsPhase::Gas,
+    specific_heat_capacity: JoulesPerKilogramKelvin(2010.0),
+    thermal_conductivity: WattsPerMeterKelvin(0.025),
+    density: Density::from_grams_per_cubic_centimeter(0.0006),
+    viscosity: 0.0125,
+    emissivity: 0.0,
+    molar_mass: 0.018015,
+    tensile_strength: 0.0,
+    compressive_strength: 0.0,
+    transitions: WATER_TRANSITIONS,
+    curves: PropertyCurves::NONE,
+};
+
+pub const ROCK: PhysicsMaterial = PhysicsMaterial {
+    name: "Rock",
+    specific_heat_capacity: JoulesPerKilogramKelvin(800.0),
+    thermal_conductivity: WattsPerMeterKelvin(4.0),
+    density: Density::from_grams_per_cubic_centimeter(2.65),
+    phase: PhysicsPhase::Solid,
+    viscosity: f32::INFINITY,
+    emissivity: 0.9,
+    molar_mass: 0.0,
+    tensile_strength: 5.0e6,
+    compressive_strength: 100.0e6,
+    transitions: PhaseTransitions::NONE,
+    curves: PropertyCurves::NONE,
+};
+
+pub const ICE: PhysicsMaterial = PhysicsMaterial {
+    name: "Ice",
+    specific_heat_capacity: JoulesPerKilogramKelvin(2040.0),
+    thermal_conductivity: WattsPerMeterKelvin(2.18),
+    density: Density::from_grams_per_cubic_centimeter(0.917),
+    phase: PhysicsPhase::Solid,
+    viscosity: f32::INFINITY,
+    emissivity: 0.97,
+    molar_mass: 0.018015,
+    tensile_strength: 1.0e6,
+    compressive_strength: 5.0e6,
+    transitions: WATER_TRANSITIONS,
+    curves: PropertyCurves::NONE,
+};
+
+pub const IRON: PhysicsMaterial = PhysicsMaterial {
+    name: "Iron",
+    specific_heat_capacity: JoulesPerKilogramKelvin(460.0),
+    thermal_conductivity: WattsPerMeterKelvin(50.0),
+    density: Density::from_grams_per_cubic_centimeter(7.874),
+    phase: PhysicsPhase::Solid,
+    viscosity: f32::INFINITY,
+    emissivity: 0.3,
+    molar_mass: 0.0,
+    tensile_strength: 200.0e6,
+    compressive_strength: 200.0e6,
+    transitions: PhaseTransitions::NONE,
+    curves: PropertyCurves::NONE,
+};
+
+pub const TUNGSTEN: PhysicsMaterial = PhysicsMaterial {
+    name: "Tungsten",
+    specific_heat_capacity: JoulesPerKilogramKelvin(134.0),
+    thermal_conductivity: WattsPerMeterKelvin(173.0),
+    density: Density::from_grams_per_cubic_centimeter(19.25),
+    phase: PhysicsPhase::Solid,
+    viscosity: f32::INFINITY,
+    emissivity: 0.35,
+    molar_mass: 0.0,
+    tensile_strength: 1500.0e6,
+    compressive_strength: 1500.0e6,
+    transitions: PhaseTransitions::NONE,
+    curves: PropertyCurves::NONE,
+};
+
+pub const DIRT: PhysicsMaterial = PhysicsMaterial {
+    name: "Dirt",
+    specific_heat_capacity: JoulesPerKilogramKelvin(800.0),
+    thermal_conductivity: WattsPerMeterKelvin(0.25),
+    density: Density::from_grams_per_cubic_centimeter(1.51),
+    phase: PhysicsPhase::Solid,
+    viscosity: f32::INFINITY,
+    emissivity: 0.92,
+    molar_mass: 0.0,
+    tensile_strength: 0.01e6,
+    compressive_strength: 0.1e6,
+    transitions: PhaseTransitions::NONE,
+    curves: PropertyCurves::NONE,
+};
+
+pub const SAND: PhysicsMaterial = PhysicsMaterial {
+    name: "Sand",
+    specific_heat_capacity: JoulesPerKilogramKelvin(830.0),
+    thermal_conductivity: WattsPerMeterKelvin(0.2),
+    density: Density::from_grams_per_cubic_centimeter(2.1),
+    phase: PhysicsPhase::Grain,
+    viscosity: f32::INFINITY,
+    emissivity: 0.76,
+    molar_mass: 0.0,
+    tensile_strength: 0.0,
+    compressive_strength: 0.0,
+    transitions: PhaseTransitions::NONE,
+    curves: PropertyCurves::NONE,
+};
+
+pub const WOOD_HARD: PhysicsMaterial = PhysicsMaterial {
+    name: "Hardwood",
+    specific_heat_capacity: JoulesPerKilogramKelvin(2000.0),
+    thermal_conductivity: WattsPerMeterKelvin(0.16),
+    density: Density::from_grams_per_cubic_centimeter(0.65),
+    phase: PhysicsPhase::Solid,
+    viscosity: f32::INFINITY,
+    emissivity: 0.9,
+    molar_mass: 0.0,
+    tensile_strength: 100.0e6,
+    compressive_strength: 50.0e6,
+    transitions: PhaseTransitions::NONE,
+    curves: PropertyCurves::NONE,
+};
+
+pub const WOOD_SOFT: PhysicsMaterial = PhysicsMaterial {
+    name: "Softwood",
+    specific_heat_capacity: JoulesPerKilogramKelvin(2300.0),
+    thermal_conductivity: WattsPerMeterKelvin(0.12),
+    density: Density::from_grams_per_cubic_centimeter(0.49),
+    phase: PhysicsPhase::Solid,
+    viscosity: f32::INFINITY,
+    emissivity: 0.9,
+    molar_mass: 0.0,
+    tensile_strength: 80.0e6,
+    compressive_strength: 35.0e6,
+    transitions: PhaseTransitions::NONE,
+    curves: PropertyCurves::NONE,
+};
+
+pub const INFINITE_HEAT_CAPACITY: PhysicsMaterial = PhysicsMaterial {
+    name: "Infinite Heat Sink",
+    specific_heat_capacity: JoulesPerKilogramKelvin(f32::INFINITY),
+    thermal_conductivity: WattsPerMeterKelvin(100.0),
+    density: Density::from_grams_per_cubic_centimeter(10.0),
+    phase: PhysicsPhase::Solid,
+    viscosity: f32::INFINITY,
+    emissivity: 0.0,
+    molar_mass: 0.0,
+    tensile_strength: f32::INFINITY,
+    compressive_strength: f32::INFINITY,
+    transitions: PhaseTransitions::NONE,
+    curves: PropertyCurves::NONE,
+};
+
+pub const MATERIALS: [PhysicsMaterial; 12] = [
+    AIR,
+    WATER,
+    STEAM,
+    ROCK,
+    ICE,
+    IRON,
+    TUNGSTEN,
+    DIRT,
+    SAND,
+    WOOD_HARD,
+    WOOD_SOFT,
+    INFINITE_HEAT_CAPACITY,
+];
@@ -1,9 +1,27 @@
 mod types;
 pub use types::*;
 pub mod materials;
+pub mod material_database;
+pub mod boundary;
 pub mod heat_transfer;
+pub mod heat_transfer_kernel;
+pub mod implicit_heat_transfer;
+pub mod heat_simulation;
+pub mod energy;
+pub mod radiation;
+pub mod fluid;
+pub mod falling_sand;
+pub mod pressure;
+pub mod structure;
+pub mod phase_change;
 pub mod kelvin;
+pub mod units;
 pub mod voxel_material_lookup;
+pub mod vox;
+pub mod vtk;
+pub mod heatmap;
+pub mod save;
+pub mod scenario;
 pub mod test;
 mod volume;
 mod components;
@@ -0,0 +1,212 @@
+use crate::physics::voxel_material_lookup::VoxelMaterialLookup;
+use crate::physics::*;
+
+//  A resolved transition for one voxel material.
+#[derive(Debug, Clone, Copy)]
+pub struct VoxelPhaseTransition {
+    pub temperature: Temperature,
+    //  energy needed to convert a whole voxel, from the mass of the lower phase material
+    //  so melting and freezing, or boiling and condensing, exchange the same energy.
+    pub latent_heat: Energy,
+    pub target: MaterialId,
+}
+
+//  Phase transitions for each MaterialId with target names resolved through the lookup.
+//  Transitions whose target material is not in the lookup are ignored.
+pub struct PhaseChangeTable {
+    //  melting for solids, boiling for liquids.
+    pub up: Vec<Option<VoxelPhaseTransition>>,
+    //  freezing for liquids, condensing for gases.
+    pub down: Vec<Option<VoxelPhaseTransition>>,
+}
+
+impl PhaseChangeTable {
+    pub fn new(lookup: &VoxelMaterialLookup) -> Self {
+        //  lower_mass is None when the target is the lower phase.
+        let resolve = |name: Option<&'static str>, temperature: Temperature, specific_latent_heat: SpecificLatentHeat, lower_mass: Option<Mass>| {
+            let target = *lookup.name_to_id.get(name?)?;
+            let mass = lower_mass.unwrap_or(lookup.materials[target as usize].mass);
+            Some(VoxelPhaseTransition { temperature, latent_heat: specific_latent_heat * mass, target })
+        };
+        let mut up = Vec::new();
+        let mut down = Vec::new();
+        for mat in lookup.materials.iter() {
+            let t = &mat.transitions;
+            let (melt, boil) = (t.melting_point, t.boiling_point);
+            let (fusion, vaporization) = (t.latent_heat_of_fusion, t.latent_heat_of_vaporization);
+            let mass = Some(mat.mass);
+            let (u, d) = match mat.phase {
+                PhysicsPhase::Solid => (resolve(t.liquid, melt, fusion, mass), None),
+                PhysicsPhase::Liquid => (resolve(t.gas, boil, vaporization, mass), resolve(t.solid, melt, fusion, None)),
+                PhysicsPhase::Gas => (None, resolve(t.liquid, boil, vaporization, None)),
+                PhysicsPhase::Grain => (None, None),
+            };
+            up.push(u);
+            down.push(d);
+        }
+        PhaseChangeTable { up, down }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.up.iter().chain(self.down.iter()).all(|t| t.is_none())
+    }
+}
+
+//  Enthalpy based phase change.
+//  A voxel which crosses a transition temperature is held at that temperature and the
+//  excess energy is accumulated in latent until the latent heat of the whole voxel is
+//  reached, then it converts to the target material and any remaining energy raises or
+//  lowers the temperature of the new material. Heating is positive latent energy and
+//  cooling negative, so a voxel which turns back part way gives its energy back.
+//  VoxelMaterialLookup::enthalpy plus latent is the same before and after every update.
+//  Voxels have a fixed volume so their mass follows the density of their material,
+//  water which boils keeps its energy but not its mass.
+//  Returns the number of voxels which changed material.
+pub fn apply_phase_changes(
+    table: &PhaseChangeTable,
+    material: &mut Volume<MaterialId>,
+    temperature: &mut Volume<Temperature>,
+    latent: &mut Volume<Energy>,
+    lookup: &VoxelMaterialLookup,
+) -> usize {
+    let mut changed = 0;
+    for i in 0 .. material.data.len() {
+        let id = material.data[i];
+        let t = temperature.data[i];
+        let capacity = lookup.heat_capacity(id, t);
+        if capacity.is_infinite() || capacity == 0.0 {
+            continue;
+        }
+        let stored = latent.data[i];
+        //  energy above the transition temperature, integrated for materials with curves.
+        let excess = |transition: &VoxelPhaseTransition| {
+            stored as f64 + lookup.thermal_energy(id, t) - lookup.thermal_energy(id, transition.temperature)
+        };
+        if let Some(up) = table.up[id as usize] {
+            if stored > 0.0 || (stored == 0.0 && t > up.temperature) {
+                let energy = excess(&up);
+                if energy < 0.0 {
+                    //  cooled back below the transition before converting.
+                    temperature.data[i] = lookup.add_energy(id, up.temperature, energy as Energy);
+                    latent.data[i] = 0.0;
+                } else if energy >= up.latent_heat as f64 {
+                    material.data[i] = up.target;
+                    temperature.data[i] = lookup.add_energy(up.target, up.temperature, (energy - up.latent_heat as f64) as Energy);
+                    latent.data[i] = 0.0;
+                    changed += 1;
+                } else {
+                    temperature.data[i] = up.temperature;
+                    latent.data[i] = energy as Energy;
+                }
+                continue;
+            }
+        }
+        if let Some(down) = table.down[id as usize] {
+            if stored < 0.0 || (stored == 0.0 && t < down.temperature) {
+                let energy = excess(&down);
+                if energy > 0.0 {
+                    //  warmed back above the transition before converting.
+                    temperature.data[i] = lookup.add_energy(id, down.temperature, energy as Energy);
+                    latent.data[i] = 0.0;
+                } else if -energy >= down.latent_heat as f64 {
+                    material.data[i] = down.target;
+                    temperature.data[i] = lookup.add_energy(down.target, down.temperature, (energy + down.latent_heat as f64) as Energy);
+                    latent.data[i] = 0.0;
+                    changed += 1;
+                } else {
+                    temperature.data[i] = down.temperature;
+                    latent.data[i] = energy as Energy;
+                }
+            }
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn water_lookup() -> VoxelMaterialLookup {
+        let mut lookup = VoxelMaterialLookup::new(0.01);
+        lookup.add(materials::ICE);
+        lookup.add(materials::WATER);
+        lookup.add(materials::STEAM);
+        lookup
+    }
+
+    fn energy(material: &Volume<MaterialId>, temperature: &Volume<Temperature>, latent: &Volume<Energy>, lookup: &VoxelMaterialLookup) -> f64 {
+        (0 .. material.data.len())
+            .map(|i| lookup.enthalpy(material.data[i], temperature.data[i]) + latent.data[i] as f64)
+            .sum()
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() <= 1.0e-5 * a.abs().max(b.abs()), "{} != {}", a, b);
+    }
+
+    #[test]
+    fn enthalpy_steps_by_the_latent_heat_between_phases() {
+        let lookup = water_lookup();
+        let (ice, water, steam) = (lookup.id("Ice"), lookup.id("Water"), lookup.id("Steam"));
+        let ice_mass = lookup.materials[ice as usize].mass as f64;
+        let water_mass = lookup.materials[water as usize].mass as f64;
+        assert!(ice_mass < water_mass, "ice is less dense than water");
+        let melting = kelvin::WATER_FREEZING;
+        let boiling = kelvin::WATER_BOILING;
+        assert_close(lookup.enthalpy(water, melting) - lookup.enthalpy(ice, melting), 334000.0 * ice_mass);
+        assert_close(lookup.enthalpy(steam, boiling) - lookup.enthalpy(water, boiling), 2260000.0 * water_mass);
+    }
+
+    //  Heats one voxel of ice in small steps through melting and boiling,
+    //  each phase change update must leave the energy as it found it.
+    #[test]
+    fn energy_is_continuous_through_melting_and_boiling() {
+        let lookup = water_lookup();
+        let table = PhaseChangeTable::new(&lookup);
+        let size = Size { x: 1, y: 1, z: 1 };
+        let mut material = Volume::new(size, lookup.id("Ice"));
+        let mut temperature = Volume::new(size, 260.0);
+        let mut latent = Volume::new(size, 0.0);
+        let heat = 2.0;
+        let mut changes = 0;
+        for _ in 0 .. 10000 {
+            temperature.data[0] = lookup.add_energy(material.data[0], temperature.data[0], heat);
+            let before = energy(&material, &temperature, &latent, &lookup);
+            changes += apply_phase_changes(&table, &mut material, &mut temperature, &mut latent, &lookup);
+            assert_close(energy(&material, &temperature, &latent, &lookup), before);
+            if material.data[0] == lookup.id("Steam") {
+                break;
+            }
+        }
+        assert_eq!(changes, 2);
+        assert_eq!(material.data[0], lookup.id("Steam"));
+        assert!(temperature.data[0] >= kelvin::WATER_BOILING);
+    }
+
+    #[test]
+    fn melting_then_freezing_gives_back_the_energy() {
+        let lookup = water_lookup();
+        let table = PhaseChangeTable::new(&lookup);
+        let size = Size { x: 1, y: 1, z: 1 };
+        let ice = lookup.id("Ice");
+        let mut material = Volume::new(size, ice);
+        let mut temperature = Volume::new(size, 270.0);
+        let mut latent = Volume::new(size, 0.0);
+        let start = energy(&material, &temperature, &latent, &lookup);
+        let melt = table.up[ice as usize].unwrap().latent_heat;
+        //  enough to melt all of it in one step then take the same energy out again.
+        let heat = lookup.heat_capacity(ice, 270.0) * 10.0 + melt * 1.5;
+        temperature.data[0] = lookup.add_energy(ice, 270.0, heat);
+        apply_phase_changes(&table, &mut material, &mut temperature, &mut latent, &lookup);
+        assert_eq!(material.data[0], lookup.id("Water"));
+        assert_close(energy(&material, &temperature, &latent, &lookup), start + heat as f64);
+
+        temperature.data[0] = lookup.add_energy(material.data[0], temperature.data[0], -heat);
+        apply_phase_changes(&table, &mut material, &mut temperature, &mut latent, &lookup);
+        assert_eq!(material.data[0], ice);
+        assert_eq!(latent.data[0], 0.0);
+        assert!((temperature.data[0] - 270.0).abs() < 0.01, "{}", temperature.data[0]);
+        assert_close(energy(&material, &temperature, &latent, &lookup), start);
+    }
+}
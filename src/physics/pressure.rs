@@ -0,0 +1,166 @@
+use bevy::math::Vec3;
+use crate::physics::voxel_material_lookup::VoxelMaterialLookup;
+use crate::physics::*;
+
+pub const ATMOSPHERIC_PRESSURE: Pressure = 101325.0;
+
+//  Joules / Mol Kelvin
+pub const GAS_CONSTANT: f32 = 8.314462;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PressureSettings {
+    pub gravity: Vec3,
+    //  pressure above the top of the volume.
+    pub surface_pressure: Pressure,
+}
+
+impl PressureSettings {
+    pub fn from_world(world: &PhysicsWorld) -> Self {
+        PressureSettings { gravity: world.gravity, surface_pressure: ATMOSPHERIC_PRESSURE }
+    }
+}
+
+impl Default for PressureSettings {
+    fn default() -> Self {
+        PressureSettings::from_world(&PhysicsWorld::default())
+    }
+}
+
+//  Density in kg/m3 of a voxel.
+fn density(mat: &VoxelMaterial, length: Length) -> f32 {
+    mat.mass / (length * length * length)
+}
+
+//  Pressure from the ideal gas law, p = ρ R T / M.
+pub fn ideal_gas_pressure(mat: &VoxelMaterial, temperature: Temperature, length: Length) -> Pressure {
+    if mat.molar_mass == 0.0 {
+        return 0.0;
+    }
+    density(mat, length) * GAS_CONSTANT * temperature / mat.molar_mass
+}
+
+//  Static pressure at the center of every voxel.
+//  Columns are walked from the top down along the axis gravity mostly points along.
+//  Gas voxels use the ideal gas law from their temperature. Liquid and Grain voxels add
+//  the weight of the column ρ g h to the pressure above them. Solid voxels carry the load
+//  structurally, they report the pressure resting on them and the column below restarts
+//  from the surface pressure.
+pub fn calculate_pressure(
+    material: &Volume<MaterialId>,
+    temperature: &Volume<Temperature>,
+    lookup: &VoxelMaterialLookup,
+    settings: &PressureSettings,
+) -> Volume<Pressure> {
+    let size = material.size;
+    let length = lookup.length;
+    let mut pressure = Volume::new(size, settings.surface_pressure);
+    let g = settings.gravity;
+    let down = Face::from_direction(g.x, g.y, g.z);
+    let axis = down.axis();
+    let g_along = [g.x, g.y, g.z][axis];
+    let gravity = g_along.abs();
+    let extent = [size.x, size.y, size.z];
+    //  the other two axes index the columns.
+    let (a, b) = match axis { 0 => (1, 2), 1 => (0, 2), _ => (0, 1) };
+    for i in 0 .. extent[a] {
+        for j in 0 .. extent[b] {
+            //  pressure at the top face of the current voxel.
+            let mut top = settings.surface_pressure;
+            for k in 0 .. extent[axis] {
+                //  gravity pointing along -axis means the top is the high end.
+                let depth = if g_along <= 0.0 { extent[axis] - 1 - k } else { k };
+                let mut c = [0; 3];
+                c[axis] = depth;
+                c[a] = i;
+                c[b] = j;
+                let index = size.index(Coord { x: c[0], y: c[1], z: c[2] });
+                let mat = &lookup.materials[material.data[index] as usize];
+                match mat.phase {
+                    PhysicsPhase::Gas => {
+                        let p = ideal_gas_pressure(mat, temperature.data[index], length);
+                        pressure.data[index] = p;
+                        top = p;
+                    }
+                    PhysicsPhase::Liquid | PhysicsPhase::Grain => {
+                        let weight = density(mat, length) * gravity * length;
+                        pressure.data[index] = top + weight * 0.5;
+                        top += weight;
+                    }
+                    PhysicsPhase::Solid => {
+                        pressure.data[index] = top;
+                        top = settings.surface_pressure;
+                    }
+                }
+            }
+        }
+    }
+    pressure
+}
+
+//  Net force in Newtons from the pressure of the surrounding voxels, which is the buoyant
+//  force for a voxel immersed in a fluid. Faces on the edge of the volume use the voxel's own pressure.
+pub fn pressure_force(pressure: &Volume<Pressure>, coord: Coord, length: Length) -> Vec3 {
+    let area = length * length;
+    let own = pressure.get(coord.x, coord.y, coord.z);
+    let mut force = Vec3::ZERO;
+    for face in Face::ALL {
+        let p = pressure.face_neighbor(coord, face).map_or(own, |n| pressure.get(n.x, n.y, n.z));
+        let (dx, dy, dz) = face.offset();
+        //  pressure on a face pushes inward, against the face normal.
+        force -= Vec3::new(dx as f32, dy as f32, dz as f32) * p * area;
+    }
+    //  neighbors are a voxel apart so halve to get the pressure difference across one voxel.
+    force * 0.5
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup() -> VoxelMaterialLookup {
+        let mut lookup = VoxelMaterialLookup::new(0.1);
+        lookup.add(materials::AIR);
+        lookup.add(materials::WATER);
+        lookup
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() <= expected.abs() * 1e-4, "{} != {}", actual, expected);
+    }
+
+    //  p = p0 + ρ g h at each voxel center, with ρ in kg/m3.
+    #[test]
+    fn hydrostatic_column() {
+        let lookup = lookup();
+        let size = Size { x: 1, y: 10, z: 1 };
+        let material = Volume::new(size, lookup.id("Water"));
+        let temperature = Volume::new(size, 300.0);
+        let settings = PressureSettings { gravity: Vec3::new(0.0, -9.8, 0.0), surface_pressure: ATMOSPHERIC_PRESSURE };
+        let pressure = calculate_pressure(&material, &temperature, &lookup, &settings);
+        for y in 0 .. 10 {
+            let depth = (10 - y) as f32 - 0.5;
+            assert_close(pressure.get(0, y, 0), ATMOSPHERIC_PRESSURE + 997.0 * 9.8 * depth * 0.1);
+        }
+        //  a metre of water adds close to a tenth of an atmosphere.
+        let bottom = pressure.get(0, 0, 0) - ATMOSPHERIC_PRESSURE;
+        assert!((9000.0 .. 10000.0).contains(&bottom), "{}", bottom);
+    }
+
+    #[test]
+    fn ideal_gas() {
+        let lookup = lookup();
+        let air = &lookup.materials[lookup.id("Air") as usize];
+        //  1.2 kg/m3 of air at 300K is about an atmosphere.
+        let expected = 1.2 * GAS_CONSTANT * 300.0 / 0.02897;
+        assert_close(ideal_gas_pressure(air, 300.0, lookup.length), expected);
+        assert!((expected - ATMOSPHERIC_PRESSURE).abs() < ATMOSPHERIC_PRESSURE * 0.05);
+
+        let size = Size { x: 1, y: 2, z: 1 };
+        let material = Volume::new(size, lookup.id("Air"));
+        let mut temperature = Volume::new(size, 300.0);
+        temperature.set(0, 0, 0, 600.0);
+        let pressure = calculate_pressure(&material, &temperature, &lookup, &PressureSettings::default());
+        assert_close(pressure.get(0, 1, 0), expected);
+        assert_close(pressure.get(0, 0, 0), expected * 2.0);
+    }
+}
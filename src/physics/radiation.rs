@@ -0,0 +1,150 @@
+use crate::physics::boundary::BoundaryConditions;
+use crate::physics::voxel_material_lookup::VoxelMaterialLookup;
+use crate::physics::*;
+
+//  Watts / Meter2 Kelvin4
+pub const STEFAN_BOLTZMANN: f32 = 5.670374e-8;
+
+//  Radiative heat exchange between exposed voxel surfaces and the surroundings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Radiation {
+    //  temperature of the surroundings the surfaces radiate to, in kelvin.
+    pub ambient: Temperature,
+}
+
+impl Radiation {
+    //  Net radiated power into a voxel, ε σ A (T_ambient⁴ - T⁴) with coefficient = ε σ A,
+    //  negative when it is hotter than ambient.
+    pub fn heat(&self, coefficient: f32, temperature: Temperature) -> HeatTransferRate {
+        coefficient * (self.ambient.powi(4) - temperature.powi(4))
+    }
+}
+
+//  Solids and liquids radiate, gases are treated as transparent.
+fn radiates(mat: &VoxelMaterial) -> bool {
+    matches!(mat.phase, PhysicsPhase::Solid | PhysicsPhase::Grain | PhysicsPhase::Liquid)
+        && mat.emissivity > 0.0
+        && mat.heat_capacity.is_finite()
+}
+
+//  Number of faces of a voxel which border a gas or a domain face which is not periodic,
+//  the surroundings lie beyond those. Periodic faces count the gas on the other side.
+pub fn exposed_faces(
+    material: &Volume<MaterialId>,
+    coord: Coord,
+    lookup: &VoxelMaterialLookup,
+    boundaries: &BoundaryConditions,
+) -> u32 {
+    let periodic = boundaries.periodic();
+    let mut exposed = 0;
+    for face in Face::ALL {
+        let neighbor = match material.face_neighbor(coord, face) {
+            Some(n) => n,
+            None if periodic[face.axis()] => material.wrapped_face_neighbor(coord, face),
+            None => {
+                exposed += 1;
+                continue;
+            }
+        };
+        let id = material.get(neighbor.x, neighbor.y, neighbor.z);
+        exposed += matches!(lookup.materials[id as usize].phase, PhysicsPhase::Gas) as u32;
+    }
+    exposed
+}
+
+//  ε σ A of the exposed surface of the voxel at coord, zero if it does not radiate.
+pub fn radiating_coefficient(
+    material: &Volume<MaterialId>,
+    coord: Coord,
+    mat: &VoxelMaterial,
+    lookup: &VoxelMaterialLookup,
+    boundaries: &BoundaryConditions,
+) -> f32 {
+    if !radiates(mat) {
+        return 0.0;
+    }
+    let area = exposed_faces(material, coord, lookup, boundaries) as f32 * lookup.length * lookup.length;
+    mat.emissivity * STEFAN_BOLTZMANN * area
+}
+
+//  Applies radiation for a time step on its own, used after the implicit integrators
+//  which leave it out of the linear system.
+//  T⁴ makes very hot surfaces stiff, so the change is limited to never cross the
+//  ambient temperature, which keeps this stable at any time step.
+//  Returns the energy which entered the volume, negative when it cooled.
+pub fn apply_radiation(
+    material: &Volume<MaterialId>,
+    temperature: &mut Volume<Temperature>,
+    lookup: &VoxelMaterialLookup,
+    boundaries: &BoundaryConditions,
+    radiation: &Radiation,
+    time: Time,
+) -> f64 {
+    let mut energy = 0.0;
+    for (coord, id) in material.iter_coords() {
+        let index = material.size.index(coord);
+        let t = temperature.data[index];
+        let mat = &lookup.at_temperature(*id, t);
+        let coefficient = radiating_coefficient(material, coord, mat, lookup, boundaries);
+        if coefficient == 0.0 {
+            continue;
+        }
+        let mut change = radiation.heat(coefficient, t) * time / mat.heat_capacity;
+        let to_ambient = radiation.ambient - t;
+        if change.abs() > to_ambient.abs() {
+            change = to_ambient;
+        }
+        temperature.data[index] = t + change;
+        energy += (change * mat.heat_capacity) as f64;
+    }
+    energy
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup() -> VoxelMaterialLookup {
+        let mut lookup = VoxelMaterialLookup::new(0.1);
+        lookup.add(materials::AIR);
+        lookup.add(materials::IRON);
+        lookup.add(materials::WATER);
+        lookup
+    }
+
+    #[test]
+    fn domain_faces_radiate_unless_periodic() {
+        let lookup = lookup();
+        let size = Size { x: 3, y: 3, z: 3 };
+        let mut material = Volume::new(size, lookup.id("Iron"));
+        let insulated = BoundaryConditions::default();
+        assert_eq!(exposed_faces(&material, Coord::new(1, 1, 1), &lookup, &insulated), 0);
+        assert_eq!(exposed_faces(&material, Coord::new(0, 1, 1), &lookup, &insulated), 1);
+        assert_eq!(exposed_faces(&material, Coord::new(0, 0, 0), &lookup, &insulated), 3);
+        material.set(1, 1, 0, lookup.id("Air"));
+        assert_eq!(exposed_faces(&material, Coord::new(1, 1, 1), &lookup, &insulated), 1);
+        //  periodic faces lead to the other side of the volume instead of the surroundings.
+        let periodic = BoundaryConditions::default().with_periodic(0).with_periodic(2);
+        assert_eq!(exposed_faces(&material, Coord::new(0, 1, 1), &lookup, &periodic), 0);
+        assert_eq!(exposed_faces(&material, Coord::new(1, 1, 2), &lookup, &periodic), 1);
+        assert_eq!(exposed_faces(&material, Coord::new(0, 0, 0), &lookup, &periodic), 1);
+    }
+
+    #[test]
+    fn liquids_radiate_and_gases_do_not() {
+        let lookup = lookup();
+        let size = Size { x: 3, y: 1, z: 1 };
+        let mut material = Volume::new(size, lookup.id("Air"));
+        material.set(0, 0, 0, lookup.id("Water"));
+        let boundaries = BoundaryConditions::default();
+        let water = &lookup.materials[lookup.id("Water") as usize];
+        let air = &lookup.materials[lookup.id("Air") as usize];
+        let coefficient = radiating_coefficient(&material, Coord::new(0, 0, 0), water, &lookup, &boundaries);
+        //  the +x face borders air and the other five the surroundings.
+        assert_eq!(coefficient, water.emissivity * STEFAN_BOLTZMANN * (6.0 * lookup.length * lookup.length));
+        assert_eq!(radiating_coefficient(&material, Coord::new(1, 0, 0), air, &lookup, &boundaries), 0.0);
+        let radiation = Radiation { ambient: 300.0 };
+        assert!(radiation.heat(coefficient, 350.0) < 0.0);
+        assert!(radiation.heat(coefficient, 250.0) > 0.0);
+    }
+}
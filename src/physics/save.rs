@@ -0,0 +1,545 @@
+use std::fmt;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use crate::physics::heat_simulation::HeatSimulation;
+use crate::physics::energy::EnergyDiagnostics;
+use crate::physics::voxel_material_lookup::{UnknownMaterial, VoxelMaterialLookup};
+use crate::physics::*;
+
+//  Binary save files of volumes, all numbers little endian:
+//      "VXSV", version u16,
+//      header: size x y z u32, voxel length f32, time f64,
+//          material count u16 and names in id order, field count u16 and per field
+//          its name and type u8, where strings are a u16 length and UTF-8,
+//      compression u8, stored data length u64, checksum u64 of the header and uncompressed data,
+//      data: each field's values in order, x fastest.
+//  The header is never compressed so it can be read without the data.
+
+const MAGIC: &[u8; 4] = b"VXSV";
+//  version 1 stored the time as f32.
+pub const VERSION: u16 = 2;
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io { path: PathBuf, error: io::Error },
+    NotASave,
+    UnsupportedVersion { found: u16, supported: u16 },
+    Truncated,
+    //  the data does not match its checksum.
+    Corrupt,
+    Invalid(&'static str),
+    Compression(io::Error),
+    MissingField(String),
+    //  a field was read as a different type than it was saved with.
+    WrongFieldType(String),
+    SizeMismatch { expected: Size, found: Size },
+    UnknownMaterial(UnknownMaterial),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::Io { path, error } => write!(f, "could not access {}: {}", path.display(), error),
+            SaveError::NotASave => write!(f, "not a volume save file"),
+            SaveError::UnsupportedVersion { found, supported } => {
+                write!(f, "save file version {} is not supported, expected version {}", found, supported)
+            }
+            SaveError::Truncated => write!(f, "save file ends early"),
+            SaveError::Corrupt => write!(f, "save file is corrupt, its checksum does not match"),
+            SaveError::Invalid(reason) => write!(f, "invalid save file, {}", reason),
+            SaveError::Compression(error) => write!(f, "could not decompress save data: {}", error),
+            SaveError::MissingField(name) => write!(f, "save file has no field \"{}\"", name),
+            SaveError::WrongFieldType(name) => write!(f, "save file field \"{}\" has a different type", name),
+            SaveError::SizeMismatch { expected, found } => write!(f, "save file is {:?}, expected {:?}", found, expected),
+            SaveError::UnknownMaterial(error) => write!(f, "save file has an {}", error),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    //  zstd level, 1 to 22, 3 is a good default.
+    Zstd(i32),
+}
+
+#[derive(Debug, Clone)]
+pub enum FieldData {
+    Material(Volume<MaterialId>),
+    Scalar(Volume<f32>),
+}
+
+impl FieldData {
+    fn type_id(&self) -> u8 {
+        match self {
+            FieldData::Material(_) => 0,
+            FieldData::Scalar(_) => 1,
+        }
+    }
+
+    fn size(&self) -> Size {
+        match self {
+            FieldData::Material(volume) => volume.size,
+            FieldData::Scalar(volume) => volume.size,
+        }
+    }
+}
+
+//  Named volumes of one size along with what is needed to make sense of them.
+#[derive(Debug, Clone)]
+pub struct SaveFile {
+    pub size: Size,
+    pub length: Length,
+    //  simulation time in seconds, zero when the fields are not from a simulation.
+    pub time: f64,
+    //  material names in id order, so material fields can be loaded against a lookup
+    //  whose ids are different.
+    pub materials: Vec<String>,
+    pub fields: Vec<(String, FieldData)>,
+}
+
+//  64 bit FNV-1a, enough to catch damaged files but not tampering.
+fn checksum(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for &byte in part.iter() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+//  Counts and string lengths are u16 in the file, anything longer is refused rather than cut.
+fn write_u16(out: &mut Vec<u8>, value: usize, reason: &'static str) -> Result<(), SaveError> {
+    let value = u16::try_from(value).map_err(|_| SaveError::Invalid(reason))?;
+    out.extend_from_slice(&value.to_le_bytes());
+    Ok(())
+}
+
+fn write_string(out: &mut Vec<u8>, text: &str) -> Result<(), SaveError> {
+    write_u16(out, text.len(), "a name is longer than 65535 bytes")?;
+    out.extend_from_slice(text.as_bytes());
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], SaveError> {
+        if self.bytes.len() < count {
+            return Err(SaveError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, SaveError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SaveError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, SaveError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, SaveError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> Result<f32, SaveError> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    fn f64(&mut self) -> Result<f64, SaveError> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+
+    fn string(&mut self) -> Result<String, SaveError> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| SaveError::Invalid("a name is not UTF-8"))
+    }
+}
+
+impl SaveFile {
+    //  An empty save with the materials and voxel length of a lookup.
+    pub fn new(size: Size, lookup: &VoxelMaterialLookup) -> Self {
+        SaveFile {
+            size,
+            length: lookup.length,
+            time: 0.0,
+            materials: lookup.iter().map(|entry| entry.name.to_owned()).collect(),
+            fields: Vec::new(),
+        }
+    }
+
+    //  Adds or replaces a field, panics if its size is not the size of the save.
+    pub fn add(&mut self, name: &str, data: FieldData) -> &mut Self {
+        assert_eq!(data.size(), self.size, "field {} does not match the size of the save", name);
+        match self.fields.iter_mut().find(|(n, _)| n == name) {
+            Some(field) => field.1 = data,
+            None => self.fields.push((name.to_owned(), data)),
+        }
+        self
+    }
+
+    pub fn field(&self, name: &str) -> Result<&FieldData, SaveError> {
+        self.fields.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, data)| data)
+            .ok_or_else(|| SaveError::MissingField(name.to_owned()))
+    }
+
+    pub fn scalar(&self, name: &str) -> Result<&Volume<f32>, SaveError> {
+        match self.field(name)? {
+            FieldData::Scalar(volume) => Ok(volume),
+            _ => Err(SaveError::WrongFieldType(name.to_owned())),
+        }
+    }
+
+    //  A material field with its ids as saved.
+    pub fn material(&self, name: &str) -> Result<&Volume<MaterialId>, SaveError> {
+        match self.field(name)? {
+            FieldData::Material(volume) => Ok(volume),
+            _ => Err(SaveError::WrongFieldType(name.to_owned())),
+        }
+    }
+
+    //  A material field with its ids changed to those of the same names in a lookup.
+    pub fn material_ids(&self, name: &str, lookup: &VoxelMaterialLookup) -> Result<Volume<MaterialId>, SaveError> {
+        let ids = self.materials.iter()
+            .map(|name| lookup.try_id(name))
+            .collect::<Result<Vec<MaterialId>, _>>()
+            .map_err(SaveError::UnknownMaterial)?;
+        let saved = self.material(name)?;
+        let mut volume = Volume::new(saved.size, 0);
+        for (to, &from) in volume.data.iter_mut().zip(saved.data.iter()) {
+            *to = *ids.get(from as usize).ok_or(SaveError::Invalid("a material id is not in the material table"))?;
+        }
+        Ok(volume)
+    }
+
+    fn header(&self) -> Result<Vec<u8>, SaveError> {
+        let mut out = Vec::new();
+        for n in [self.size.x, self.size.y, self.size.z] {
+            let n = u32::try_from(n).map_err(|_| SaveError::Invalid("the size is too large"))?;
+            out.extend_from_slice(&n.to_le_bytes());
+        }
+        out.extend_from_slice(&self.length.to_le_bytes());
+        out.extend_from_slice(&self.time.to_le_bytes());
+        write_u16(&mut out, self.materials.len(), "there are more than 65535 materials")?;
+        for name in self.materials.iter() {
+            write_string(&mut out, name)?;
+        }
+        write_u16(&mut out, self.fields.len(), "there are more than 65535 fields")?;
+        for (name, data) in self.fields.iter() {
+            write_string(&mut out, name)?;
+            out.push(data.type_id());
+        }
+        Ok(out)
+    }
+
+    pub fn to_bytes(&self, compression: Compression) -> Result<Vec<u8>, SaveError> {
+        let header = self.header()?;
+        let mut data = Vec::with_capacity(self.size.product() * 4 * self.fields.len());
+        for (_, field) in self.fields.iter() {
+            match field {
+                FieldData::Material(volume) => data.extend(volume.data.iter().flat_map(|v| v.to_le_bytes())),
+                FieldData::Scalar(volume) => data.extend(volume.data.iter().flat_map(|v| v.to_le_bytes())),
+            }
+        }
+        let hash = checksum(&[&header, &data]);
+        let (kind, stored) = match compression {
+            Compression::None => (0, data),
+            Compression::Zstd(level) => (1, zstd::bulk::compress(&data, level).map_err(SaveError::Compression)?),
+        };
+        let mut out = Vec::with_capacity(header.len() + stored.len() + 32);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&header);
+        out.push(kind);
+        out.extend_from_slice(&(stored.len() as u64).to_le_bytes());
+        out.extend_from_slice(&hash.to_le_bytes());
+        out.extend_from_slice(&stored);
+        Ok(out)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SaveError> {
+        let mut reader = Reader { bytes };
+        if reader.take(4).map_err(|_| SaveError::NotASave)? != MAGIC {
+            return Err(SaveError::NotASave);
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(SaveError::UnsupportedVersion { found: version, supported: VERSION });
+        }
+
+        let header_start = reader.bytes;
+        let size = Size { x: reader.u32()? as usize, y: reader.u32()? as usize, z: reader.u32()? as usize };
+        let length = reader.f32()?;
+        let time = reader.f64()?;
+        let materials = (0 .. reader.u16()?).map(|_| reader.string()).collect::<Result<Vec<_>, _>>()?;
+        let mut descriptors = Vec::new();
+        for _ in 0 .. reader.u16()? {
+            let name = reader.string()?;
+            let kind = reader.u8()?;
+            if kind > 1 {
+                return Err(SaveError::Invalid("a field has an unknown type"));
+            }
+            descriptors.push((name, kind));
+        }
+        let header = &header_start[.. header_start.len() - reader.bytes.len()];
+
+        let compression = reader.u8()?;
+        let stored_length = usize::try_from(reader.u64()?).map_err(|_| SaveError::Truncated)?;
+        let hash = reader.u64()?;
+        let stored = reader.take(stored_length)?;
+        if !reader.bytes.is_empty() {
+            return Err(SaveError::Invalid("there are bytes after the data"));
+        }
+        let expected = size.x.checked_mul(size.y)
+            .and_then(|n| n.checked_mul(size.z))
+            .and_then(|n| n.checked_mul(4 * descriptors.len()))
+            .ok_or(SaveError::Invalid("the size is too large"))?;
+        let data = match compression {
+            0 => stored.to_vec(),
+            1 => {
+                //  read at most one byte more than expected so a bad header can not make it allocate
+                //  more than the data holds.
+                let mut data = Vec::new();
+                zstd::stream::read::Decoder::new(stored)
+                    .and_then(|decoder| decoder.take(expected as u64 + 1).read_to_end(&mut data))
+                    .map_err(SaveError::Compression)?;
+                data
+            }
+            _ => return Err(SaveError::Invalid("unknown compression")),
+        };
+        if data.len() != expected {
+            return Err(SaveError::Invalid("the data does not match the size and fields"));
+        }
+        if checksum(&[header, &data]) != hash {
+            return Err(SaveError::Corrupt);
+        }
+
+        let count = size.product();
+        let fields = descriptors.into_iter().enumerate().map(|(i, (name, kind))| {
+            let values = data[i * count * 4 .. (i + 1) * count * 4].chunks_exact(4).map(|b| <[u8; 4]>::try_from(b).unwrap());
+            let field = match kind {
+                0 => FieldData::Material(Volume { size, data: values.map(MaterialId::from_le_bytes).collect() }),
+                _ => FieldData::Scalar(Volume { size, data: values.map(f32::from_le_bytes).collect() }),
+            };
+            (name, field)
+        }).collect();
+        Ok(SaveFile { size, length, time, materials, fields })
+    }
+
+    pub fn save(&self, path: &Path, compression: Compression) -> Result<(), SaveError> {
+        let bytes = self.to_bytes(compression)?;
+        std::fs::write(path, bytes).map_err(|error| SaveError::Io { path: path.to_owned(), error })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, SaveError> {
+        let bytes = std::fs::read(path).map_err(|error| SaveError::Io { path: path.to_owned(), error })?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+//  The state of a heat simulation which changes as it runs. Integrator, boundaries and
+//  radiation are part of the setup and are not saved.
+pub fn checkpoint(simulation: &HeatSimulation) -> SaveFile {
+    let mut save = SaveFile::new(simulation.material.size, simulation.lookup);
+    save.time = simulation.time;
+    save.add("material", FieldData::Material(simulation.material.clone()))
+        .add("temperature", FieldData::Scalar(simulation.temperature.clone()))
+        .add("heat", FieldData::Scalar(simulation.heat.clone()))
+        .add("latent", FieldData::Scalar(simulation.latent.clone()));
+    save
+}
+
+//  Restores a checkpoint into a simulation set up the same way as the one it was taken from.
+//  Energy diagnostics, when enabled, start over from the restored state.
+pub fn resume(simulation: &mut HeatSimulation, save: &SaveFile) -> Result<(), SaveError> {
+    if save.size != simulation.material.size {
+        return Err(SaveError::SizeMismatch { expected: simulation.material.size, found: save.size });
+    }
+    let material = save.material_ids("material", simulation.lookup)?;
+    let temperature = save.scalar("temperature")?.clone();
+    let heat = save.scalar("heat")?.clone();
+    let latent = save.scalar("latent")?.clone();
+    simulation.material = material;
+    simulation.temperature = temperature;
+    simulation.heat = heat;
+    simulation.latent = latent;
+    simulation.time = save.time;
+    simulation.material_changed();
+    if let Some(tolerance) = simulation.energy.as_ref().map(|e| e.tolerance) {
+        simulation.energy = Some(EnergyDiagnostics::new(simulation.total_energy(), tolerance));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::boundary::BoundaryConditions;
+    use crate::physics::implicit_heat_transfer::HeatIntegrator;
+
+    fn save() -> SaveFile {
+        let size = Size { x: 4, y: 3, z: 2 };
+        let mut save = SaveFile {
+            size,
+            length: 0.1,
+            time: 123456.789012,
+            materials: vec!["Air".to_owned(), "Rock".to_owned()],
+            fields: Vec::new(),
+        };
+        let mut material = Volume::new(size, 0);
+        material.set(1, 2, 1, 1);
+        let mut temperature = Volume::new(size, 300.0);
+        for (coord, t) in temperature.iter_mut_coords() {
+            *t += (coord.x + 4 * coord.y + 12 * coord.z) as f32;
+        }
+        save.add("material", FieldData::Material(material)).add("temperature", FieldData::Scalar(temperature));
+        save
+    }
+
+    #[test]
+    fn saves_round_trip() {
+        let save = save();
+        for compression in [Compression::None, Compression::Zstd(3)] {
+            let read = SaveFile::from_bytes(&save.to_bytes(compression).unwrap()).unwrap();
+            assert_eq!(read.size, save.size);
+            assert_eq!(read.length, save.length);
+            assert_eq!(read.time, save.time);
+            assert_eq!(read.materials, save.materials);
+            assert_eq!(read.material("material").unwrap().data, save.material("material").unwrap().data);
+            assert_eq!(read.scalar("temperature").unwrap().data, save.scalar("temperature").unwrap().data);
+        }
+    }
+
+    #[test]
+    fn damaged_files_are_rejected() {
+        let bytes = save().to_bytes(Compression::None).unwrap();
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        assert!(matches!(SaveFile::from_bytes(&bad), Err(SaveError::NotASave)));
+        let mut bad = bytes.clone();
+        bad[4 .. 6].copy_from_slice(&1u16.to_le_bytes());
+        assert!(matches!(SaveFile::from_bytes(&bad), Err(SaveError::UnsupportedVersion { found: 1, supported: VERSION })));
+        assert!(matches!(SaveFile::from_bytes(&bytes[.. bytes.len() - 1]), Err(SaveError::Truncated)));
+        assert!(matches!(SaveFile::from_bytes(&bytes[.. 20]), Err(SaveError::Truncated)));
+        let mut bad = bytes.clone();
+        *bad.last_mut().unwrap() ^= 1;
+        assert!(matches!(SaveFile::from_bytes(&bad), Err(SaveError::Corrupt)));
+        let mut long = bytes.clone();
+        long.push(0);
+        assert!(matches!(SaveFile::from_bytes(&long), Err(SaveError::Invalid(_))));
+    }
+
+    #[test]
+    fn corrupt_compressed_data_is_rejected() {
+        let save = save();
+        let bytes = save.to_bytes(Compression::Zstd(3)).unwrap();
+        //  magic, version, header, compression, stored length and checksum come before the data.
+        let data = 4 + 2 + save.header().unwrap().len() + 1 + 8 + 8;
+        let mut bad = bytes.clone();
+        bad[data .. data + 4].copy_from_slice(b"junk");
+        assert!(matches!(SaveFile::from_bytes(&bad), Err(SaveError::Compression(_))));
+    }
+
+    #[test]
+    fn counts_too_large_for_the_format_are_errors() {
+        let mut long_name = save();
+        long_name.materials.push("x".repeat(70000));
+        assert!(matches!(long_name.to_bytes(Compression::None), Err(SaveError::Invalid(_))));
+        let mut many = save();
+        many.materials = (0 .. 70000).map(|i| i.to_string()).collect();
+        assert!(matches!(many.to_bytes(Compression::None), Err(SaveError::Invalid(_))));
+    }
+
+    //  An iron bar with a hot end and an air gap, in a lookup with the given materials.
+    fn bar(lookup: &VoxelMaterialLookup) -> (Volume<MaterialId>, Volume<Temperature>) {
+        let size = Size { x: 6, y: 2, z: 2 };
+        let mut material = Volume::new(size, lookup.id("Iron"));
+        material.set(3, 1, 1, lookup.id("Air"));
+        let mut temperature = Volume::new(size, 300.0);
+        temperature.set(0, 0, 0, 1200.0);
+        (material, temperature)
+    }
+
+    fn run(simulation: &mut HeatSimulation, time: Time, steps: usize) {
+        for _ in 0 .. steps {
+            simulation.step(time).unwrap();
+        }
+    }
+
+    fn through_bytes(save: &SaveFile) -> SaveFile {
+        SaveFile::from_bytes(&save.to_bytes(Compression::Zstd(3)).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn resuming_a_checkpoint_continues_the_run() {
+        let mut lookup = VoxelMaterialLookup::new(0.01);
+        lookup.add(materials::AIR);
+        lookup.add(materials::IRON);
+        let (material, temperature) = bar(&lookup);
+        for integrator in [HeatIntegrator::Explicit, HeatIntegrator::BackwardEuler, HeatIntegrator::CrankNicolson] {
+            let new = || HeatSimulation::new(material.clone(), temperature.clone(), &lookup, integrator, BoundaryConditions::default());
+            let mut straight = new();
+            let time = straight.max_time_step() * 3.0;
+            run(&mut straight, time, 10);
+
+            let mut first = new();
+            run(&mut first, time, 4);
+            let save = through_bytes(&checkpoint(&first));
+            let mut resumed = new();
+            resume(&mut resumed, &save).unwrap();
+            assert_eq!(resumed.time, first.time);
+            run(&mut resumed, time, 6);
+
+            assert_eq!(resumed.time, straight.time);
+            assert_eq!(resumed.temperature.data, straight.temperature.data, "{:?}", integrator);
+            assert_eq!(resumed.heat.data, straight.heat.data, "{:?}", integrator);
+        }
+    }
+
+    #[test]
+    fn resuming_maps_material_ids_by_name() {
+        let mut saved_lookup = VoxelMaterialLookup::new(0.01);
+        saved_lookup.add(materials::AIR);
+        saved_lookup.add(materials::IRON);
+        let (material, temperature) = bar(&saved_lookup);
+        let mut simulation = HeatSimulation::new(material, temperature, &saved_lookup, HeatIntegrator::Explicit, BoundaryConditions::default());
+        let time = simulation.max_time_step();
+        run(&mut simulation, time, 3);
+        let save = through_bytes(&checkpoint(&simulation));
+
+        let mut lookup = VoxelMaterialLookup::new(0.01);
+        lookup.add(materials::WATER);
+        lookup.add(materials::IRON);
+        lookup.add(materials::AIR);
+        let (material, temperature) = bar(&lookup);
+        let mut resumed = HeatSimulation::new(material.clone(), temperature, &lookup, HeatIntegrator::Explicit, BoundaryConditions::default());
+        resume(&mut resumed, &save).unwrap();
+        assert_eq!(resumed.material.data, material.data);
+        assert_eq!(resumed.temperature.data, simulation.temperature.data);
+        run(&mut simulation, time, 3);
+        run(&mut resumed, time, 3);
+        assert_eq!(resumed.temperature.data, simulation.temperature.data);
+
+        let mut missing = VoxelMaterialLookup::new(0.01);
+        missing.add(materials::IRON);
+        let material = Volume::new(save.size, missing.id("Iron"));
+        let temperature = Volume::new(save.size, 300.0);
+        let mut other = HeatSimulation::new(material, temperature, &missing, HeatIntegrator::Explicit, BoundaryConditions::default());
+        assert!(matches!(resume(&mut other, &save), Err(SaveError::UnknownMaterial(UnknownMaterial { name })) if name == "Air"));
+    }
+}
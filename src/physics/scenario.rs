@@ -0,0 +1,446 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use bevy::math::Vec3;
+use serde::Deserialize;
+use crate::physics::boundary::{BoundaryCondition, BoundaryConditions, UnpairedPeriodic};
+use crate::physics::material_database::{load_materials, MaterialError};
+use crate::physics::radiation::Radiation;
+use crate::physics::voxel_material_lookup::VoxelMaterialLookup;
+use crate::physics::*;
+
+//  Initial conditions read from a RON file instead of built in Rust, see assets/scenarios.
+//  Shapes are applied in order over a background so later shapes overwrite earlier ones.
+//  Coordinates are in voxels and temperatures in kelvin.
+
+#[derive(Debug)]
+pub enum ScenarioError {
+    Io { path: PathBuf, error: std::io::Error },
+    Parse { path: Option<PathBuf>, error: ron::error::SpannedError },
+    Materials(MaterialError),
+    //  a name which is neither in the materials file nor a built in material.
+    UnknownMaterial(String),
+    //  a shape uses a material which is not in the materials list.
+    UnlistedMaterial(String),
+    InvalidSize,
+    InvalidLength(Length),
+    ProbeOutside { name: String, position: (usize, usize, usize) },
+    UnpairedPeriodic(UnpairedPeriodic),
+}
+
+impl fmt::Display for ScenarioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScenarioError::Io { path, error } => write!(f, "could not read scenario {}: {}", path.display(), error),
+            ScenarioError::Parse { path: Some(path), error } => write!(f, "could not parse scenario {}:{}", path.display(), error),
+            ScenarioError::Parse { path: None, error } => write!(f, "could not parse scenario: {}", error),
+            ScenarioError::Materials(error) => write!(f, "{}", error),
+            ScenarioError::UnknownMaterial(name) => write!(f, "unknown material \"{}\"", name),
+            ScenarioError::UnlistedMaterial(name) => write!(f, "material \"{}\" is used but not in the materials list", name),
+            ScenarioError::InvalidSize => write!(f, "the grid size must be at least one voxel along each axis"),
+            ScenarioError::InvalidLength(length) => write!(f, "voxel_length is {}, it must be positive", length),
+            ScenarioError::ProbeOutside { name, position } => {
+                write!(f, "probe \"{}\" at {:?} is outside of the grid", name, position)
+            }
+            ScenarioError::UnpairedPeriodic(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for ScenarioError {}
+
+impl From<MaterialError> for ScenarioError {
+    fn from(error: MaterialError) -> Self {
+        ScenarioError::Materials(error)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScenarioDefinition {
+    size: (usize, usize, usize),
+    //  Meters
+    voxel_length: Length,
+    //  path of a materials file like assets/materials.ron, relative to the scenario file.
+    //  Its materials are used in place of built in materials with the same name.
+    #[serde(default)]
+    materials_file: Option<String>,
+    //  names of the materials in the lookup, in id order.
+    materials: Vec<String>,
+    background: FillDefinition,
+    #[serde(default)]
+    shapes: Vec<ShapeFillDefinition>,
+    #[serde(default)]
+    boundaries: BoundariesDefinition,
+    //  ambient temperature surfaces radiate to, None for no radiation.
+    #[serde(default)]
+    radiation: Option<Temperature>,
+    #[serde(default)]
+    probes: Vec<ProbeDefinition>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FillDefinition {
+    material: String,
+    temperature: Temperature,
+}
+
+//  A shape sets the material, the temperature or both of the voxels it covers.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ShapeFillDefinition {
+    shape: ShapeDefinition,
+    #[serde(default)]
+    material: Option<String>,
+    #[serde(default)]
+    temperature: Option<Temperature>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+enum AxisDefinition {
+    X,
+    Y,
+    Z,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+enum ShapeDefinition {
+    //  voxels from min up to but not including max, clipped to the grid.
+    Box { min: (usize, usize, usize), max: (usize, usize, usize) },
+    //  voxels whose centers are within radius, center (0.5, 0.5, 0.5) is the middle of voxel 0, 0, 0.
+    Sphere { center: (f32, f32, f32), radius: f32 },
+    //  voxels from one plane up to but not including another along an axis, spanning the other two.
+    Layer { axis: AxisDefinition, from: usize, to: usize },
+}
+
+impl ShapeDefinition {
+    fn contains(&self, coord: Coord) -> bool {
+        let Coord { x, y, z } = coord;
+        match *self {
+            ShapeDefinition::Box { min, max } => {
+                (min.0..max.0).contains(&x) && (min.1..max.1).contains(&y) && (min.2..max.2).contains(&z)
+            }
+            ShapeDefinition::Sphere { center, radius } => {
+                let voxel_center = Vec3::new(x as f32, y as f32, z as f32) + 0.5;
+                voxel_center.distance_squared(Vec3::from(center)) <= radius * radius
+            }
+            ShapeDefinition::Layer { axis, from, to } => {
+                let position = match axis {
+                    AxisDefinition::X => x,
+                    AxisDefinition::Y => y,
+                    AxisDefinition::Z => z,
+                };
+                (from..to).contains(&position)
+            }
+        }
+    }
+}
+
+//  Mirrors BoundaryCondition, temperatures in kelvin, flux W/m2 into the volume,
+//  coefficient W/m2 K.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+enum BoundaryDefinition {
+    Insulated,
+    FixedTemperature(Temperature),
+    FixedFlux(HeatFlux),
+    Convective { coefficient: HeatTransferCoefficient, ambient: Temperature },
+    Periodic,
+}
+
+impl BoundaryDefinition {
+    fn to_condition(self) -> BoundaryCondition {
+        match self {
+            BoundaryDefinition::Insulated => BoundaryCondition::Insulated,
+            BoundaryDefinition::FixedTemperature(t) => BoundaryCondition::FixedTemperature(t),
+            BoundaryDefinition::FixedFlux(flux) => BoundaryCondition::FixedFlux(flux),
+            BoundaryDefinition::Convective { coefficient, ambient } => BoundaryCondition::Convective { coefficient, ambient },
+            BoundaryDefinition::Periodic => BoundaryCondition::Periodic,
+        }
+    }
+}
+
+//  Faces which are not given use default, which is insulated unless set.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BoundariesDefinition {
+    #[serde(default)]
+    default: Option<BoundaryDefinition>,
+    #[serde(default)]
+    negative_x: Option<BoundaryDefinition>,
+    #[serde(default)]
+    positive_x: Option<BoundaryDefinition>,
+    #[serde(default)]
+    negative_y: Option<BoundaryDefinition>,
+    #[serde(default)]
+    positive_y: Option<BoundaryDefinition>,
+    #[serde(default)]
+    negative_z: Option<BoundaryDefinition>,
+    #[serde(default)]
+    positive_z: Option<BoundaryDefinition>,
+}
+
+impl BoundariesDefinition {
+    fn to_conditions(&self) -> Result<BoundaryConditions, ScenarioError> {
+        let default = self.default.unwrap_or(BoundaryDefinition::Insulated);
+        let faces = [self.negative_x, self.positive_x, self.negative_y, self.positive_y, self.negative_z, self.positive_z]
+            .map(|face| face.unwrap_or(default).to_condition());
+        BoundaryConditions::new(faces).map_err(ScenarioError::UnpairedPeriodic)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProbeDefinition {
+    name: String,
+    position: (usize, usize, usize),
+}
+
+//  A named voxel whose values are recorded while a scenario runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Probe {
+    pub name: String,
+    pub coord: Coord,
+}
+
+impl Probe {
+    pub fn sample<T: Copy>(&self, volume: &Volume<T>) -> T {
+        volume.get(self.coord.x, self.coord.y, self.coord.z)
+    }
+}
+
+//  Everything needed to start a HeatSimulation, the lookup is borrowed by the simulation
+//  so move the volumes out and keep the scenario.
+pub struct Scenario {
+    pub lookup: VoxelMaterialLookup,
+    pub material: Volume<MaterialId>,
+    pub temperature: Volume<Temperature>,
+    pub boundaries: BoundaryConditions,
+    pub radiation: Option<Radiation>,
+    pub probes: Vec<Probe>,
+}
+
+//  Materials of the lookup, the listed ones first in order, then the materials they change
+//  phase into so phase changes are not dropped because a target was left out of the list.
+fn material_lookup(names: &[String], length: Length, file: &[PhysicsMaterial]) -> Result<VoxelMaterialLookup, ScenarioError> {
+    let find = |name: &str| {
+        file.iter().chain(materials::MATERIALS.iter())
+            .find(|mat| mat.name == name)
+            .copied()
+            .ok_or_else(|| ScenarioError::UnknownMaterial(name.to_owned()))
+    };
+    let mut lookup = VoxelMaterialLookup::new(length);
+    for name in names {
+        if !lookup.contains(name) {
+            lookup.add(find(name)?);
+        }
+    }
+    let mut next = 0;
+    while next < lookup.len() {
+        let t = lookup.physics[next].transitions;
+        for target in [t.solid, t.liquid, t.gas].into_iter().flatten() {
+            if !lookup.contains(target) {
+                lookup.add(find(target)?);
+            }
+        }
+        next += 1;
+    }
+    Ok(lookup)
+}
+
+impl ScenarioDefinition {
+    fn to_scenario(&self, directory: &Path) -> Result<Scenario, ScenarioError> {
+        let (x, y, z) = self.size;
+        if x == 0 || y == 0 || z == 0 {
+            return Err(ScenarioError::InvalidSize);
+        }
+        let size = Size { x, y, z };
+        //  NaN is rejected too.
+        if !(self.voxel_length > 0.0 && self.voxel_length.is_finite()) {
+            return Err(ScenarioError::InvalidLength(self.voxel_length));
+        }
+        let file_materials = match &self.materials_file {
+            Some(path) => load_materials(&directory.join(path))?,
+            None => Vec::new(),
+        };
+        let lookup = material_lookup(&self.materials, self.voxel_length, &file_materials)?;
+        let listed = |name: &str| {
+            if self.materials.iter().any(|listed| listed == name) {
+                Ok(lookup.id(name))
+            } else {
+                Err(ScenarioError::UnlistedMaterial(name.to_owned()))
+            }
+        };
+
+        let mut material = Volume::new(size, listed(&self.background.material)?);
+        let mut temperature = Volume::new(size, self.background.temperature);
+        for fill in self.shapes.iter() {
+            let id = fill.material.as_deref().map(listed).transpose()?;
+            for coord in size.coords().filter(|&coord| fill.shape.contains(coord)) {
+                if let Some(id) = id {
+                    material.set(coord.x, coord.y, coord.z, id);
+                }
+                if let Some(t) = fill.temperature {
+                    temperature.set(coord.x, coord.y, coord.z, t);
+                }
+            }
+        }
+
+        let probes = self.probes.iter()
+            .map(|probe| {
+                let (x, y, z) = probe.position;
+                if x < size.x && y < size.y && z < size.z {
+                    Ok(Probe { name: probe.name.clone(), coord: Coord::new(x, y, z) })
+                } else {
+                    Err(ScenarioError::ProbeOutside { name: probe.name.clone(), position: probe.position })
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Scenario {
+            lookup,
+            material,
+            temperature,
+            boundaries: self.boundaries.to_conditions()?,
+            radiation: self.radiation.map(|ambient| Radiation { ambient }),
+            probes,
+        })
+    }
+}
+
+//  Parses a RON scenario, a materials_file is relative to directory.
+pub fn parse_scenario(source: &str, directory: &Path) -> Result<Scenario, ScenarioError> {
+    let definition: ScenarioDefinition = ron::from_str(source)
+        .map_err(|error| ScenarioError::Parse { path: None, error })?;
+    definition.to_scenario(directory)
+}
+
+pub fn load_scenario(path: &Path) -> Result<Scenario, ScenarioError> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|error| ScenarioError::Io { path: path.to_owned(), error })?;
+    let directory = path.parent().unwrap_or(Path::new(""));
+    parse_scenario(&source, directory).map_err(|e| match e {
+        ScenarioError::Parse { path: None, error } => ScenarioError::Parse { path: Some(path.to_owned()), error },
+        e => e,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRID: &str = "size: (4, 4, 4), voxel_length: 0.1, materials: [\"Air\", \"Iron\", \"Rock\"], \
+        background: (material: \"Air\", temperature: 300.0)";
+
+    fn parse(fields: &str) -> Result<Scenario, ScenarioError> {
+        parse_scenario(&format!("({})", fields), Path::new(""))
+    }
+
+    fn error(fields: &str) -> ScenarioError {
+        match parse(fields) {
+            Ok(_) => panic!("expected an error"),
+            Err(error) => error,
+        }
+    }
+
+    fn with_shapes(shapes: &str) -> Scenario {
+        parse(&format!("{}, shapes: [{}]", GRID, shapes)).unwrap()
+    }
+
+    //  coordinates of the voxels with a material, x fastest.
+    fn voxels_of(scenario: &Scenario, name: &str) -> Vec<(usize, usize, usize)> {
+        let id = scenario.lookup.id(name);
+        scenario.material.iter_coords().filter(|&(_, &m)| m == id).map(|(c, _)| (c.x, c.y, c.z)).collect()
+    }
+
+    #[test]
+    fn the_example_scenario_loads() {
+        let scenario = parse_scenario(include_str!("../../assets/scenarios/hot_iron_block.ron"), Path::new("assets/scenarios")).unwrap();
+        assert_eq!(scenario.material.size, Size { x: 16, y: 12, z: 8 });
+        assert_eq!(scenario.lookup.length, 0.05);
+        let probe = |name: &str| scenario.probes.iter().find(|p| p.name == name).unwrap().clone();
+        assert_eq!(scenario.lookup.name(probe("iron").sample(&scenario.material)), Some("Iron"));
+        assert_eq!(probe("iron").sample(&scenario.temperature), 1100.0);
+        assert_eq!(scenario.lookup.name(probe("wood").sample(&scenario.material)), Some("Hardwood"));
+        assert_eq!(scenario.lookup.name(probe("rock").sample(&scenario.material)), Some("Rock"));
+        assert_eq!(probe("water").sample(&scenario.temperature), 280.0);
+        assert_eq!(scenario.boundaries.get(Face::NegativeY), BoundaryCondition::FixedTemperature(285.0));
+        assert_eq!(scenario.boundaries.get(Face::PositiveY), BoundaryCondition::Convective { coefficient: 10.0, ambient: 295.0 });
+        assert_eq!(scenario.boundaries.get(Face::PositiveX), BoundaryCondition::Insulated);
+        assert_eq!(scenario.radiation.map(|r| r.ambient), Some(295.0));
+    }
+
+    #[test]
+    fn shapes_cover_their_voxels() {
+        let scenario = with_shapes("(shape: Box(min: (1, 2, 3), max: (3, 3, 9)), material: Some(\"Iron\"), temperature: Some(500.0))");
+        assert_eq!(voxels_of(&scenario, "Iron"), vec![(1, 2, 3), (2, 2, 3)]);
+        assert_eq!(scenario.temperature.get(2, 2, 3), 500.0);
+        assert_eq!(scenario.temperature.get(0, 2, 3), 300.0);
+
+        //  the voxel centers nearest the corner point 1,1,1 are 0.87 away, one for each of the eight voxels around it.
+        let scenario = with_shapes("(shape: Sphere(center: (1.0, 1.0, 1.0), radius: 0.9), material: Some(\"Iron\"))");
+        assert_eq!(voxels_of(&scenario, "Iron").len(), 8);
+        let scenario = with_shapes("(shape: Sphere(center: (1.5, 1.5, 1.5), radius: 1.0), material: Some(\"Iron\"))");
+        assert_eq!(voxels_of(&scenario, "Iron").len(), 7);
+
+        let scenario = with_shapes("(shape: Layer(axis: Z, from: 3, to: 4), temperature: Some(250.0))");
+        assert!(voxels_of(&scenario, "Iron").is_empty());
+        for (coord, &t) in scenario.temperature.iter_coords() {
+            assert_eq!(t, if coord.z == 3 { 250.0 } else { 300.0 });
+        }
+    }
+
+    #[test]
+    fn later_shapes_overwrite_earlier_ones() {
+        let scenario = with_shapes("\
+            (shape: Layer(axis: Y, from: 0, to: 2), material: Some(\"Rock\"), temperature: Some(280.0)), \
+            (shape: Box(min: (0, 1, 0), max: (1, 4, 1)), material: Some(\"Iron\")), \
+            (shape: Box(min: (0, 0, 0), max: (4, 1, 1)), temperature: Some(900.0))");
+        assert_eq!(voxels_of(&scenario, "Iron"), vec![(0, 1, 0), (0, 2, 0), (0, 3, 0)]);
+        assert_eq!(voxels_of(&scenario, "Rock").len(), 4 * 2 * 4 - 1);
+        //  a shape without a temperature keeps the one under it.
+        assert_eq!(scenario.temperature.get(0, 1, 0), 280.0);
+        assert_eq!(scenario.temperature.get(0, 2, 0), 300.0);
+        assert_eq!(scenario.temperature.get(3, 0, 0), 900.0);
+        assert_eq!(scenario.lookup.name(scenario.material.get(3, 0, 0)), Some("Rock"));
+    }
+
+    #[test]
+    fn phase_change_targets_are_added_after_the_listed_materials() {
+        let scenario = parse(&GRID.replace("\"Rock\"", "\"Water\"")).unwrap();
+        let names: Vec<&str> = scenario.lookup.iter().map(|entry| entry.name).collect();
+        assert_eq!(names, vec!["Air", "Iron", "Water", "Ice", "Steam"]);
+        //  they are in the lookup for phase changes, but shapes may only use listed materials.
+        let unlisted = error(&format!("{}, shapes: [(shape: Layer(axis: X, from: 0, to: 1), material: Some(\"Ice\"))]",
+            GRID.replace("\"Rock\"", "\"Water\"")));
+        assert!(matches!(unlisted, ScenarioError::UnlistedMaterial(name) if name == "Ice"));
+    }
+
+    #[test]
+    fn invalid_scenarios_are_errors() {
+        for size in ["(0, 4, 4)", "(4, 0, 4)", "(4, 4, 0)"] {
+            assert!(matches!(parse(&GRID.replace("(4, 4, 4)", size)), Err(ScenarioError::InvalidSize)));
+        }
+        for length in ["0.0", "-0.1", "NaN", "inf"] {
+            assert!(matches!(parse(&GRID.replace("0.1", length)), Err(ScenarioError::InvalidLength(_))), "{}", length);
+        }
+        let unknown = error(&GRID.replace("\"Rock\"", "\"Unobtainium\""));
+        assert!(matches!(unknown, ScenarioError::UnknownMaterial(name) if name == "Unobtainium"));
+        let background = error(&GRID.replace("material: \"Air\"", "material: \"Sand\""));
+        assert!(matches!(background, ScenarioError::UnlistedMaterial(name) if name == "Sand"));
+        let shape = error(&format!("{}, shapes: [(shape: Layer(axis: X, from: 0, to: 1), material: Some(\"Sand\"))]", GRID));
+        assert!(matches!(shape, ScenarioError::UnlistedMaterial(name) if name == "Sand"));
+
+        assert!(parse(&format!("{}, probes: [(name: \"corner\", position: (3, 3, 3))]", GRID)).is_ok());
+        let probe = error(&format!("{}, probes: [(name: \"outside\", position: (1, 4, 1))]", GRID));
+        assert!(matches!(probe, ScenarioError::ProbeOutside { name, position: (1, 4, 1) } if name == "outside"));
+
+        let periodic = error(&format!("{}, boundaries: (negative_z: Some(Periodic))", GRID));
+        assert!(matches!(periodic, ScenarioError::UnpairedPeriodic(UnpairedPeriodic { axis: 2 })));
+        assert!(parse(&format!("{}, boundaries: (negative_z: Some(Periodic), positive_z: Some(Periodic))", GRID)).is_ok());
+
+        assert!(matches!(parse(&format!("{}, colour: 3", GRID)), Err(ScenarioError::Parse { path: None, .. })));
+        assert!(matches!(load_scenario(Path::new("no/such/scenario.ron")), Err(ScenarioError::Io { .. })));
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::VecDeque;
+use bevy::math::Vec3;
+use crate::physics::voxel_material_lookup::VoxelMaterialLookup;
+use crate::physics::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BondLoad {
+    //  the load pushes the two voxels together, a voxel resting on the one below it.
+    Compression,
+    //  the load pulls the voxels apart or shears them, hanging or cantilevered voxels.
+    Tension,
+}
+
+//  A bond whose stress is more than its strength.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BondFailure {
+    //  the voxel whose load is carried through the bond.
+    pub from: Coord,
+    //  the voxel carrying it, None for the ground below the volume.
+    pub to: Option<Coord>,
+    pub load: BondLoad,
+    //  Newtons
+    pub force: f32,
+    //  stress / strength, more than 1 means the bond fails.
+    pub stress_ratio: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct StructuralAnalysis {
+    //  the highest stress / strength of any bond of each voxel, zero for non solids.
+    pub stress_ratio: Volume<f32>,
+    pub failures: Vec<BondFailure>,
+    //  solid voxels with no path through solids to the ground.
+    pub unsupported: Vec<Coord>,
+}
+
+fn is_solid(mat: &VoxelMaterial) -> bool {
+    matches!(mat.phase, PhysicsPhase::Solid) && mat.mass > 0.0
+}
+
+fn ratio(stress: f32, strength: f32) -> f32 {
+    if stress == 0.0 { 0.0 } else if strength > 0.0 { stress / strength } else { f32::INFINITY }
+}
+
+//  Approximate static stress in solid voxels under gravity.
+//  Solid voxels on the bottom face of the volume rest on the ground. Every other solid voxel
+//  passes its weight, plus the weight of Grain and Liquid voxels resting on it and any load
+//  passed to it, evenly to its face neighbors one step closer to the ground through solids.
+//  That gives each voxel a shortest load path, which is a good approximation for stacked and
+//  arched structures but ignores bending moments in long cantilevers.
+//  Load carried downward is compression, sideways or upward load is tension. A bond is as
+//  strong as the weaker of its two materials and the stress is the force over one face area.
+pub fn analyze_structure(material: &Volume<MaterialId>, lookup: &VoxelMaterialLookup, gravity: Vec3) -> StructuralAnalysis {
+    let size = material.size;
+    let length = lookup.length;
+    let area = length * length;
+    let down = Face::from_direction(gravity.x, gravity.y, gravity.z);
+    let g = gravity.length();
+    let mat = |i: usize| &lookup.materials[material.data[i] as usize];
+
+    //  own weight plus the loose weight above.
+    let mut load: Vec<f32> = (0 .. size.product())
+        .map(|i| if is_solid(mat(i)) { mat(i).mass * g } else { 0.0 })
+        .collect();
+    for coord in size.coords() {
+        let index = size.index(coord);
+        if !matches!(mat(index).phase, PhysicsPhase::Grain | PhysicsPhase::Liquid) {
+            continue;
+        }
+        let weight = mat(index).mass * g;
+        let mut c = coord;
+        while let Some(below) = material.face_neighbor(c, down) {
+            let b = size.index(below);
+            if is_solid(mat(b)) {
+                load[b] += weight;
+                break;
+            }
+            c = below;
+        }
+    }
+
+    //  breadth first distance to the ground through solids.
+    let mut distance = vec![usize::MAX; size.product()];
+    let mut queue = VecDeque::new();
+    for coord in size.coords() {
+        let index = size.index(coord);
+        if is_solid(mat(index)) && down.on_boundary(size, coord) {
+            distance[index] = 0;
+            queue.push_back(coord);
+        }
+    }
+    let mut order = Vec::new();
+    while let Some(coord) = queue.pop_front() {
+        let index = size.index(coord);
+        order.push(coord);
+        for (_, n) in material.face_neighbors(coord) {
+            let n_index = size.index(n);
+            if distance[n_index] == usize::MAX && is_solid(mat(n_index)) {
+                distance[n_index] = distance[index] + 1;
+                queue.push_back(n);
+            }
+        }
+    }
+
+    let mut stress_ratio = Volume::new(size, 0.0);
+    let mut failures = Vec::new();
+    let mut record = |from: Coord, to: Option<Coord>, load: BondLoad, force: f32, r: f32, stress_ratio: &mut Volume<f32>| {
+        for c in std::iter::once(from).chain(to) {
+            let i = size.index(c);
+            stress_ratio.data[i] = f32::max(stress_ratio.data[i], r);
+        }
+        if r > 1.0 {
+            failures.push(BondFailure { from, to, load, force, stress_ratio: r });
+        }
+    };
+
+    //  farthest first so every load is complete before it is passed on.
+    for &coord in order.iter().rev() {
+        let index = size.index(coord);
+        let a = mat(index);
+        let force = load[index];
+        if distance[index] == 0 {
+            record(coord, None, BondLoad::Compression, force, ratio(force / area, a.compressive_strength), &mut stress_ratio);
+            continue;
+        }
+        let supports: Vec<(Face, Coord)> = material.face_neighbors(coord)
+            .filter(|&(_, n)| distance[size.index(n)] + 1 == distance[index])
+            .collect();
+        let share = force / supports.len() as f32;
+        for (face, n) in supports {
+            let n_index = size.index(n);
+            let b = mat(n_index);
+            load[n_index] += share;
+            let (kind, strength) = if face == down {
+                (BondLoad::Compression, a.compressive_strength.min(b.compressive_strength))
+            } else {
+                (BondLoad::Tension, a.tensile_strength.min(b.tensile_strength))
+            };
+            record(coord, Some(n), kind, share, ratio(share / area, strength), &mut stress_ratio);
+        }
+    }
+
+    let unsupported = size.coords()
+        .filter(|&c| {
+            let index = size.index(c);
+            is_solid(mat(index)) && distance[index] == usize::MAX
+        })
+        .collect();
+    StructuralAnalysis { stress_ratio, failures, unsupported }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRAVITY: Vec3 = Vec3::new(0.0, -9.8, 0.0);
+
+    //  1 kg voxels of a material which holds 3 but not 4 voxels hanging from one face.
+    fn lookup() -> VoxelMaterialLookup {
+        let mut block = materials::ROCK;
+        block.name = "Block";
+        block.density = Density::from_grams_per_cubic_centimeter(1.0);
+        block.tensile_strength = 3.5 * 9.8 / (0.1 * 0.1);
+        block.compressive_strength = 1.0e6;
+        let mut lookup = VoxelMaterialLookup::new(0.1);
+        lookup.add(materials::AIR);
+        lookup.add(block);
+        lookup
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() <= expected.abs() * 1e-4, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn a_column_carries_its_weight_down() {
+        let lookup = lookup();
+        let block = lookup.id("Block");
+        let mut material = Volume::new(Size { x: 3, y: 5, z: 1 }, lookup.id("Air"));
+        for y in 0 .. 5 {
+            material.set(1, y, 0, block);
+        }
+        let analysis = analyze_structure(&material, &lookup, GRAVITY);
+        assert!(analysis.failures.is_empty());
+        assert!(analysis.unsupported.is_empty());
+        //  each voxel carries itself and those above it through the face below, 1 kg each.
+        for y in 0 .. 5 {
+            let stress = (5 - y) as f32 * 9.8 / (0.1 * 0.1);
+            assert_close(analysis.stress_ratio.get(1, y, 0), stress / 1.0e6);
+        }
+        assert_eq!(analysis.stress_ratio.get(0, 0, 0), 0.0);
+    }
+
+    #[test]
+    fn an_overhang_fails_in_tension() {
+        let lookup = lookup();
+        let block = lookup.id("Block");
+        let mut material = Volume::new(Size { x: 6, y: 3, z: 1 }, lookup.id("Air"));
+        for y in 0 .. 3 {
+            material.set(0, y, 0, block);
+        }
+        for x in 1 .. 4 {
+            material.set(x, 2, 0, block);
+        }
+        assert!(analyze_structure(&material, &lookup, GRAVITY).failures.is_empty());
+
+        //  a fourth voxel on the arm puts the load through the first bond over its strength.
+        material.set(4, 2, 0, block);
+        let analysis = analyze_structure(&material, &lookup, GRAVITY);
+        assert_eq!(analysis.failures.len(), 1, "{:?}", analysis.failures);
+        let failure = analysis.failures[0];
+        assert_eq!((failure.from, failure.to), (Coord::new(1, 2, 0), Some(Coord::new(0, 2, 0))));
+        assert_eq!(failure.load, BondLoad::Tension);
+        assert_close(failure.force, 4.0 * 9.8);
+        assert_close(failure.stress_ratio, 4.0 / 3.5);
+
+        //  a voxel with no path to the ground is reported rather than loaded.
+        material.set(4, 2, 0, lookup.id("Air"));
+        material.set(5, 1, 0, block);
+        let analysis = analyze_structure(&material, &lookup, GRAVITY);
+        assert_eq!(analysis.unsupported, vec![Coord::new(5, 1, 0)]);
+        assert_eq!(analysis.stress_ratio.get(5, 1, 0), 0.0);
+    }
+}
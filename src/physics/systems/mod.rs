@@ -1,5 +1,9 @@
+use std::time::Duration;
 use bevy::app::{App, Plugin, Update};
-use bevy::prelude::{Changed, Entity, IntoSystemConfigs, Query, Res, Time, Transform, Vec3, With};
+use bevy::prelude::{resource_exists, Changed, Entity, EventWriter, IntoSystemConfigs, Query, Res, ResMut, Time, Transform, Vec3, With};
+use bevy::time::common_conditions::on_timer;
+use crate::physics::material_database::{MaterialDatabase, MaterialsReloaded};
+use crate::physics::voxel_material_lookup::VoxelMaterialLookup;
 use crate::physics::{PhysicsWorld, Gravity, Spring, Particle};
 
 pub struct PhysicsPlugin;
@@ -11,6 +15,14 @@ impl Plugin for PhysicsPlugin {
             .add_systems(Update, move_position.after(gravitational_acceleration))
             .add_systems(Update, spring_system.after(move_position))
             .add_systems(Update, position_transform.after(move_position))
+            .add_event::<MaterialsReloaded>()
+            .add_systems(
+                Update,
+                reload_materials
+                    .run_if(resource_exists::<MaterialDatabase>)
+                    .run_if(resource_exists::<VoxelMaterialLookup>)
+                    .run_if(on_timer(Duration::from_secs(1))),
+            )
         ;
     }
 }
@@ -82,6 +94,21 @@ fn position_transform(mut query: Query<(&Particle, &mut Transform), Changed<Part
     }
 }
 
+//  Picks up edits to the materials file while the app runs, when both resources are present.
+fn reload_materials(
+    mut database: ResMut<MaterialDatabase>,
+    mut lookup: ResMut<VoxelMaterialLookup>,
+    mut reloaded: EventWriter<MaterialsReloaded>,
+) {
+    match database.reload_if_changed(&mut lookup) {
+        Ok(true) => {
+            reloaded.send(MaterialsReloaded);
+        }
+        Ok(false) => {}
+        Err(error) => bevy::log::warn!("{}", error),
+    }
+}
+
 fn gravitational_acceleration(
     time: Res<Time>,
     physics_world: Res<PhysicsWorld>,
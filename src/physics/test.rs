@@ -1,30 +1,41 @@
 use crate::physics::*;
+use crate::physics::boundary::{BoundaryCondition, BoundaryConditions};
 use crate::physics::voxel_material_lookup::VoxelMaterialLookup;
 
 pub fn fill_volume_with_test_material(volume: &mut Volume<MaterialId>, lookup: &VoxelMaterialLookup) {
     let iron = lookup.id("Iron");
     let wood = lookup.id("Hardwood");
-    for z in 0 .. volume.size.z {
-        let z_edge = (z == 0 || z + 1 == volume.size.z) as i32;
-        for y in 0 .. volume.size.y {
-            let y_edge = (y == 0 || y + 1 == volume.size.y) as i32;
-            for x in 0 .. volume.size.x {
-                let x_edge = (x == 0 || x + 1 == volume.size.x) as i32;
-                let edges = x_edge + y_edge + z_edge;
-                let material_id = if edges >= 2 { iron } else { wood };
-                volume.set(x, y, z, material_id);
-            }
-        }
+    let size = volume.size;
+    for (coord, material_id) in volume.iter_mut_coords() {
+        let x_edge = (coord.x == 0 || coord.x + 1 == size.x) as i32;
+        let y_edge = (coord.y == 0 || coord.y + 1 == size.y) as i32;
+        let z_edge = (coord.z == 0 || coord.z + 1 == size.z) as i32;
+        let edges = x_edge + y_edge + z_edge;
+        *material_id = if edges >= 2 { iron } else { wood };
     }
 }
 
-pub fn fill_with_heat_source_and_sink(material: &mut Volume<MaterialId>, temperature: &mut Volume<Temperature>, lookup: &VoxelMaterialLookup) {
-    let infinite_heat_capacity = lookup.id("Infinite Heat Sink");
+//  The hot source is a tungsten voxel at its melting point, so it cools through conduction and radiation.
+//  The sink is the -x face, see heat_sink_boundaries.
+pub fn fill_with_heat_source(material: &mut Volume<MaterialId>, temperature: &mut Volume<Temperature>, lookup: &VoxelMaterialLookup) {
+    let tungsten = lookup.id("Tungsten");
     let hot: usize = material.data.len() - 1;
-    let cold: usize = 0;
-    material.data[hot] = infinite_heat_capacity;
-    material.data[cold] = infinite_heat_capacity;
+    material.data[hot] = tungsten;
     temperature.data.fill(kelvin::ROOM_TEMPERATURE);
     temperature.data[hot] = kelvin::TUNGSTEN_MELTING;
-    temperature.data[cold] = kelvin::ABSOLUTE_ZERO;
-}
\ No newline at end of file
+}
+
+//  Holds the -x face cold, in place of an infinite heat capacity voxel in the corner.
+pub fn heat_sink_boundaries() -> BoundaryConditions {
+    let mut faces = [BoundaryCondition::Insulated; 6];
+    faces[Face::NegativeX as usize] = BoundaryCondition::FixedTemperature(kelvin::ABSOLUTE_ZERO);
+    BoundaryConditions::new(faces).unwrap()
+}
+
+//  Holds the -x face cold and the +x face hot, in place of infinite heat capacity corner voxels.
+pub fn heat_source_and_sink_boundaries() -> BoundaryConditions {
+    let mut faces = [BoundaryCondition::Insulated; 6];
+    faces[Face::NegativeX as usize] = BoundaryCondition::FixedTemperature(kelvin::ABSOLUTE_ZERO);
+    faces[Face::PositiveX as usize] = BoundaryCondition::FixedTemperature(kelvin::TUNGSTEN_MELTING);
+    BoundaryConditions::new(faces).unwrap()
+}
@@ -1,3 +1,5 @@
+use crate::physics::units::*;
+
 
 #[derive(Debug, Clone, Copy)]
 pub enum PhysicsPhase {
@@ -6,6 +8,95 @@ pub enum PhysicsPhase {
     Liquid,
     Gas,
 }
+
+//  Temperatures and latent heats at which a material changes phase,
+//  along with the names of the materials it becomes in each phase.
+#[derive(Debug, Clone, Copy)]
+pub struct PhaseTransitions {
+    pub melting_point: Temperature,
+    pub boiling_point: Temperature,
+    pub latent_heat_of_fusion: SpecificLatentHeat,
+    pub latent_heat_of_vaporization: SpecificLatentHeat,
+    pub solid: Option<&'static str>,
+    pub liquid: Option<&'static str>,
+    pub gas: Option<&'static str>,
+}
+
+impl PhaseTransitions {
+    pub const NONE: PhaseTransitions = PhaseTransitions {
+        melting_point: f32::INFINITY,
+        boiling_point: f32::INFINITY,
+        latent_heat_of_fusion: 0.0,
+        latent_heat_of_vaporization: 0.0,
+        solid: None,
+        liquid: None,
+        gas: None,
+    };
+}
+
+//  Piecewise linear property values at increasing temperatures,
+//  held at the first and last value outside of the points.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PropertyCurve {
+    pub points: &'static [(Temperature, f32)],
+}
+
+impl PropertyCurve {
+    pub const fn new(points: &'static [(Temperature, f32)]) -> Self {
+        PropertyCurve { points }
+    }
+
+    pub fn sample(&self, temperature: Temperature) -> f32 {
+        let points = self.points;
+        let upper = points.partition_point(|&(t, _)| t < temperature);
+        if upper == 0 {
+            return points[0].1;
+        }
+        if upper == points.len() {
+            return points[points.len() - 1].1;
+        }
+        let (t0, v0) = points[upper - 1];
+        let (t1, v1) = points[upper];
+        v0 + (v1 - v0) * (temperature - t0) / (t1 - t0)
+    }
+
+    //  lowest and highest temperature with a point.
+    pub fn range(&self) -> (Temperature, Temperature) {
+        (self.points[0].0, self.points[self.points.len() - 1].0)
+    }
+}
+
+//  Optional temperature dependent values which replace the constant properties.
+//  Voxels keep their volume, so a density curve changes the mass they hold and with it
+//  their heat capacity ρ(T) c(T) V. Temperatures follow the enthalpy ∫ C dT of that heat
+//  capacity, see PropertyTable, which keeps energy conserved as the density changes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PropertyCurves {
+    pub thermal_conductivity: Option<PropertyCurve>,
+    pub specific_heat_capacity: Option<PropertyCurve>,
+    pub density: Option<PropertyCurve>,
+}
+
+impl PropertyCurves {
+    pub const NONE: PropertyCurves = PropertyCurves {
+        thermal_conductivity: None,
+        specific_heat_capacity: None,
+        density: None,
+    };
+
+    pub fn is_empty(&self) -> bool {
+        self.thermal_conductivity.is_none() && self.specific_heat_capacity.is_none() && self.density.is_none()
+    }
+
+    //  temperatures covered by any of the curves, None if there are none.
+    pub fn range(&self) -> Option<(Temperature, Temperature)> {
+        [self.thermal_conductivity, self.specific_heat_capacity, self.density].into_iter()
+            .flatten()
+            .map(|curve| curve.range())
+            .reduce(|(min_a, max_a), (min_b, max_b)| (min_a.min(min_b), max_a.max(max_b)))
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct PhysicsMaterial {
     pub name: &'static str,
@@ -14,37 +105,85 @@ pub struct PhysicsMaterial {
     pub thermal_conductivity: ThermalConductivity,
     pub density: Density,
     pub viscosity: Viscosity,
+    //  0 to 1, fraction of black body radiation emitted from exposed surfaces.
+    pub emissivity: Emissivity,
+    //  used for the ideal gas law, zero for materials which are never a gas.
+    pub molar_mass: MolarMass,
+    //  stress at which a bond between solid voxels fails, zero for non solids.
+    pub tensile_strength: Pressure,
+    pub compressive_strength: Pressure,
+    pub transitions: PhaseTransitions,
+    pub curves: PropertyCurves,
 }
 
+//  Per voxel properties as plain SI values for the solvers, kg, K/W and J/K.
 #[derive(Debug, Clone, Copy)]
 pub struct VoxelMaterial {
     pub phase: PhysicsPhase,
     pub mass: Mass,
     pub thermal_resistance: ThermalResistance,
     pub heat_capacity: HeatCapacity,
+    pub emissivity: Emissivity,
+    pub viscosity: Viscosity,
+    pub molar_mass: MolarMass,
+    pub tensile_strength: Pressure,
+    pub compressive_strength: Pressure,
+    pub transitions: PhaseTransitions,
 }
 
 impl PhysicsMaterial {
     pub fn to_voxel_material(&self, length: Length) -> VoxelMaterial {
-        let volume = length * length * length;
-        let mass = self.density * volume;
+        self.voxel_material(length, self.thermal_conductivity, self.specific_heat_capacity, self.density)
+    }
+
+    //  Uses the property curves where there are any, curve values are in the SI unit of the property.
+    pub fn to_voxel_material_at(&self, length: Length, temperature: Temperature) -> VoxelMaterial {
+        let curves = &self.curves;
+        self.voxel_material(
+            length,
+            curves.thermal_conductivity.map_or(self.thermal_conductivity, |c| WattsPerMeterKelvin(c.sample(temperature))),
+            curves.specific_heat_capacity.map_or(self.specific_heat_capacity, |c| JoulesPerKilogramKelvin(c.sample(temperature))),
+            curves.density.map_or(self.density, |c| KilogramsPerCubicMeter(c.sample(temperature))),
+        )
+    }
+
+    fn voxel_material(
+        &self,
+        length: Length,
+        thermal_conductivity: ThermalConductivity,
+        specific_heat_capacity: SpecificHeatCapacity,
+        density: Density,
+    ) -> VoxelMaterial {
+        let length = Meters(length);
+        let mass = density * (length * length * length);
+        //  the voxel center is half a voxel from each face, conductance k A / (L / 2) = 2 k L.
+        let half_conductance = thermal_conductivity * length * 2.0;
         VoxelMaterial {
             phase: self.phase,
-            mass,
-            thermal_resistance: 1.0 / (2.0 * self.thermal_conductivity * length),
-            heat_capacity: mass * self.specific_heat_capacity,
+            mass: mass.0,
+            thermal_resistance: (1.0 / half_conductance).0,
+            heat_capacity: (mass * specific_heat_capacity).0,
+            emissivity: self.emissivity,
+            viscosity: self.viscosity,
+            molar_mass: self.molar_mass,
+            tensile_strength: self.tensile_strength,
+            compressive_strength: self.compressive_strength,
+            transitions: self.transitions,
         }
     }
 }
 
+//  The aliases below that aren't units.rs quantities are plain f32 in SI units, see the scope
+//  note in units.rs.
+
 //  Meter
 pub type Length = f32;
 
 //  Kelvin
 pub type Temperature = f32;
 
-//  g/cm3
-pub type Density = f32;
+//  Kg / Meter3, use Density::from_grams_per_cubic_centimeter for g/cm3.
+pub type Density = KilogramsPerCubicMeter;
 
 //  Pascals, kg/m sec2
 pub type Pressure = f32;
@@ -56,17 +195,38 @@ pub type Mass = f32;
 pub type Viscosity = f32;
 
 //  Watts / Meter Kelvin
-pub type ThermalConductivity = f32;
+pub type ThermalConductivity = WattsPerMeterKelvin;
 
 //  Kelvin / Watt
 pub type ThermalResistance = f32;
 
 //  Joules / Kg Kelvin
-pub type SpecificHeatCapacity = f32;
+pub type SpecificHeatCapacity = JoulesPerKilogramKelvin;
 
 //  Joules / Kelvin
 pub type HeatCapacity = f32;
 
+//  Watts / Kelvin
+pub type Conductance = f32;
+
+//  Watts / Meter2
+pub type HeatFlux = f32;
+
+//  Watts / Meter2 Kelvin
+pub type HeatTransferCoefficient = f32;
+
+//  Kg / Mol
+pub type MolarMass = f32;
+
+//  Dimensionless, 0 to 1
+pub type Emissivity = f32;
+
+//  Joules / Kg
+pub type SpecificLatentHeat = f32;
+
+//  Joules
+pub type Energy = f32;
+
 //  Joules / Second
 pub type Power = f32;
 
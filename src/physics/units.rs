@@ -0,0 +1,208 @@
+use std::fmt;
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+
+//  Newtype quantities in SI units so dimensional arithmetic is checked at compile time.
+//  Quantities add and subtract with their own kind and scale by f32. Multiplying or dividing
+//  two quantities only compiles for the combinations declared with product below.
+//  Values in other units come in and go out through explicit conversions.
+//  Scope: only the material properties a PhysicsMaterial is defined with (density, thermal
+//  conductivity and specific heat capacity) use these, along with the arithmetic turning them
+//  into the per voxel values of a VoxelMaterial. That is where the units disagreed. Temperature,
+//  Length, Mass, HeatCapacity, Pressure, Time and the other aliases in types.rs stay plain f32
+//  in SI units, and the solvers and volumes take them as such; wrapping every voxel value would
+//  touch each kernel's inner loop for no checking the material conversion doesn't already do.
+
+macro_rules! quantity {
+    ($name:ident, $unit:literal) => {
+        #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+        pub struct $name(pub f32);
+
+        impl $name {
+            pub const ZERO: $name = $name(0.0);
+        }
+
+        impl Add for $name {
+            type Output = $name;
+            fn add(self, other: $name) -> $name { $name(self.0 + other.0) }
+        }
+
+        impl Sub for $name {
+            type Output = $name;
+            fn sub(self, other: $name) -> $name { $name(self.0 - other.0) }
+        }
+
+        impl AddAssign for $name {
+            fn add_assign(&mut self, other: $name) { self.0 += other.0; }
+        }
+
+        impl SubAssign for $name {
+            fn sub_assign(&mut self, other: $name) { self.0 -= other.0; }
+        }
+
+        impl Neg for $name {
+            type Output = $name;
+            fn neg(self) -> $name { $name(-self.0) }
+        }
+
+        impl Mul<f32> for $name {
+            type Output = $name;
+            fn mul(self, scale: f32) -> $name { $name(self.0 * scale) }
+        }
+
+        impl Mul<$name> for f32 {
+            type Output = $name;
+            fn mul(self, quantity: $name) -> $name { $name(self * quantity.0) }
+        }
+
+        impl Div<f32> for $name {
+            type Output = $name;
+            fn div(self, scale: f32) -> $name { $name(self.0 / scale) }
+        }
+
+        //  the ratio of two quantities of the same kind has no unit.
+        impl Div for $name {
+            type Output = f32;
+            fn div(self, other: $name) -> f32 { self.0 / other.0 }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{} {}", self.0, $unit)
+            }
+        }
+    };
+}
+
+//  a * b = c, along with b * a = c, c / a = b and c / b = a.
+macro_rules! product {
+    ($a:ident * $a2:ident = $c:ident, square) => {
+        impl Mul for $a {
+            type Output = $c;
+            fn mul(self, other: $a) -> $c { $c(self.0 * other.0) }
+        }
+
+        impl Div<$a> for $c {
+            type Output = $a;
+            fn div(self, other: $a) -> $a { $a(self.0 / other.0) }
+        }
+    };
+    ($a:ident * $b:ident = $c:ident) => {
+        impl Mul<$b> for $a {
+            type Output = $c;
+            fn mul(self, other: $b) -> $c { $c(self.0 * other.0) }
+        }
+
+        impl Mul<$a> for $b {
+            type Output = $c;
+            fn mul(self, other: $a) -> $c { $c(self.0 * other.0) }
+        }
+
+        impl Div<$a> for $c {
+            type Output = $b;
+            fn div(self, other: $a) -> $b { $b(self.0 / other.0) }
+        }
+
+        impl Div<$b> for $c {
+            type Output = $a;
+            fn div(self, other: $b) -> $a { $a(self.0 / other.0) }
+        }
+    };
+}
+
+//  1 / a = b and 1 / b = a.
+macro_rules! reciprocal {
+    ($a:ident, $b:ident) => {
+        impl Div<$a> for f32 {
+            type Output = $b;
+            fn div(self, other: $a) -> $b { $b(self / other.0) }
+        }
+
+        impl Div<$b> for f32 {
+            type Output = $a;
+            fn div(self, other: $b) -> $a { $a(self / other.0) }
+        }
+    };
+}
+
+quantity!(Meters, "m");
+quantity!(SquareMeters, "m2");
+quantity!(CubicMeters, "m3");
+quantity!(Seconds, "s");
+quantity!(Kilograms, "kg");
+quantity!(Kelvin, "K");
+quantity!(KilogramsPerCubicMeter, "kg/m3");
+quantity!(MetersPerSecondSquared, "m/s2");
+quantity!(Newtons, "N");
+quantity!(Pascals, "Pa");
+quantity!(Joules, "J");
+quantity!(Watts, "W");
+quantity!(JoulesPerKilogram, "J/kg");
+quantity!(JoulesPerKelvin, "J/K");
+quantity!(JoulesPerKilogramKelvin, "J/kg K");
+quantity!(WattsPerKelvin, "W/K");
+quantity!(KelvinPerWatt, "K/W");
+quantity!(WattsPerMeterKelvin, "W/m K");
+
+product!(Meters * Meters = SquareMeters, square);
+product!(SquareMeters * Meters = CubicMeters);
+product!(KilogramsPerCubicMeter * CubicMeters = Kilograms);
+product!(Kilograms * MetersPerSecondSquared = Newtons);
+product!(Pascals * SquareMeters = Newtons);
+product!(Watts * Seconds = Joules);
+product!(Kilograms * JoulesPerKilogram = Joules);
+product!(Kilograms * JoulesPerKilogramKelvin = JoulesPerKelvin);
+product!(JoulesPerKelvin * Kelvin = Joules);
+product!(WattsPerKelvin * Kelvin = Watts);
+product!(WattsPerMeterKelvin * Meters = WattsPerKelvin);
+reciprocal!(WattsPerKelvin, KelvinPerWatt);
+
+impl Kelvin {
+    pub const fn from_celsius(celsius: f32) -> Kelvin {
+        Kelvin(celsius + 273.15)
+    }
+
+    pub const fn celsius(self) -> f32 {
+        self.0 - 273.15
+    }
+}
+
+impl KilogramsPerCubicMeter {
+    //  1 g/cm3 is 1000 kg/m3.
+    pub const fn from_grams_per_cubic_centimeter(density: f32) -> KilogramsPerCubicMeter {
+        KilogramsPerCubicMeter(density * 1000.0)
+    }
+
+    pub const fn grams_per_cubic_centimeter(self) -> f32 {
+        self.0 / 1000.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn celsius_converts_to_and_from_kelvin() {
+        assert_eq!(Kelvin::from_celsius(0.0), Kelvin(273.15));
+        assert_eq!(Kelvin::from_celsius(-273.15), Kelvin(0.0));
+        assert!((Kelvin(373.15).celsius() - 100.0).abs() < 1e-4);
+        assert!((Kelvin::from_celsius(21.5).celsius() - 21.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn grams_per_cubic_centimeter_convert_to_and_from_kilograms_per_cubic_meter() {
+        assert_eq!(KilogramsPerCubicMeter::from_grams_per_cubic_centimeter(1.0), KilogramsPerCubicMeter(1000.0));
+        assert_eq!(KilogramsPerCubicMeter::from_grams_per_cubic_centimeter(7.87), KilogramsPerCubicMeter(7870.0));
+        assert_eq!(KilogramsPerCubicMeter(2700.0).grams_per_cubic_centimeter(), 2.7);
+    }
+
+    #[test]
+    fn density_times_volume_is_mass_in_kilograms() {
+        //  a 1 cm voxel of water weighs a gram.
+        let length = Meters(0.01);
+        let volume = length * length * length;
+        let mass = KilogramsPerCubicMeter::from_grams_per_cubic_centimeter(1.0) * volume;
+        assert!((mass.0 - 0.001).abs() < 1e-9);
+        assert!((mass / volume - KilogramsPerCubicMeter(1000.0)).0.abs() < 1e-2);
+    }
+}
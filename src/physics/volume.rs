@@ -1,6 +1,6 @@
 use std::fmt::Display;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Size {
     pub x: usize,
     pub y: usize,
@@ -11,15 +11,170 @@ impl Size {
     pub fn product(&self) -> usize {
         return self.x * self.y * self.z;
     }
+
+    // Linear index of a coordinate, x varies fastest then y then z.
+    pub fn index(&self, coord: Coord) -> usize {
+        (coord.z * self.y + coord.y) * self.x + coord.x
+    }
+
+    // Inverse of index.
+    pub fn coord(&self, index: usize) -> Coord {
+        let x = index % self.x;
+        let y = (index / self.x) % self.y;
+        let z = index / (self.x * self.y);
+        Coord { x, y, z }
+    }
+
+    pub fn contains(&self, x: isize, y: isize, z: isize) -> bool {
+        x >= 0 && y >= 0 && z >= 0
+            && (x as usize) < self.x && (y as usize) < self.y && (z as usize) < self.z
+    }
+
+    // Coordinate offset by (dx, dy, dz) or None if that lands outside of these bounds.
+    pub fn offset(&self, coord: Coord, dx: isize, dy: isize, dz: isize) -> Option<Coord> {
+        let x = coord.x as isize + dx;
+        let y = coord.y as isize + dy;
+        let z = coord.z as isize + dz;
+        if self.contains(x, y, z) {
+            Some(Coord { x: x as usize, y: y as usize, z: z as usize })
+        } else {
+            None
+        }
+    }
+
+    // Iterates every coordinate in linear index order.
+    pub fn coords(&self) -> Coords {
+        Coords { size: *self, next: Coord { x: 0, y: 0, z: 0 }, remaining: self.product() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Coord {
+    pub x: usize,
+    pub y: usize,
+    pub z: usize,
+}
+
+impl Coord {
+    pub fn new(x: usize, y: usize, z: usize) -> Self {
+        Coord { x, y, z }
+    }
+}
+
+pub struct Coords {
+    size: Size,
+    next: Coord,
+    remaining: usize,
+}
+
+impl Iterator for Coords {
+    type Item = Coord;
+
+    fn next(&mut self) -> Option<Coord> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let current = self.next;
+        //  increment x, carrying into y and z.
+        self.next.x += 1;
+        if self.next.x == self.size.x {
+            self.next.x = 0;
+            self.next.y += 1;
+            if self.next.y == self.size.y {
+                self.next.y = 0;
+                self.next.z += 1;
+            }
+        }
+        Some(current)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl ExactSizeIterator for Coords {}
+
+//  One of the six faces of a voxel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Face {
+    NegativeX,
+    PositiveX,
+    NegativeY,
+    PositiveY,
+    NegativeZ,
+    PositiveZ,
+}
+
+impl Face {
+    pub const ALL: [Face; 6] = [
+        Face::NegativeX,
+        Face::PositiveX,
+        Face::NegativeY,
+        Face::PositiveY,
+        Face::NegativeZ,
+        Face::PositiveZ,
+    ];
+
+    pub fn offset(&self) -> (isize, isize, isize) {
+        match self {
+            Face::NegativeX => (-1, 0, 0),
+            Face::PositiveX => (1, 0, 0),
+            Face::NegativeY => (0, -1, 0),
+            Face::PositiveY => (0, 1, 0),
+            Face::NegativeZ => (0, 0, -1),
+            Face::PositiveZ => (0, 0, 1),
+        }
+    }
+
+    // The face a direction mostly points towards, ties go to x then y.
+    pub fn from_direction(x: f32, y: f32, z: f32) -> Face {
+        if x.abs() >= y.abs() && x.abs() >= z.abs() {
+            if x > 0.0 { Face::PositiveX } else { Face::NegativeX }
+        } else if y.abs() >= z.abs() {
+            if y > 0.0 { Face::PositiveY } else { Face::NegativeY }
+        } else {
+            if z > 0.0 { Face::PositiveZ } else { Face::NegativeZ }
+        }
+    }
+
+    // 0 for x, 1 for y and 2 for z.
+    pub fn axis(&self) -> usize {
+        *self as usize / 2
+    }
+
+    // true if coord lies against this face of a volume with the given size.
+    pub fn on_boundary(&self, size: Size, coord: Coord) -> bool {
+        match self {
+            Face::NegativeX => coord.x == 0,
+            Face::PositiveX => coord.x + 1 == size.x,
+            Face::NegativeY => coord.y == 0,
+            Face::PositiveY => coord.y + 1 == size.y,
+            Face::NegativeZ => coord.z == 0,
+            Face::PositiveZ => coord.z + 1 == size.z,
+        }
+    }
+
+    pub fn opposite(&self) -> Face {
+        match self {
+            Face::NegativeX => Face::PositiveX,
+            Face::PositiveX => Face::NegativeX,
+            Face::NegativeY => Face::PositiveY,
+            Face::PositiveY => Face::NegativeY,
+            Face::NegativeZ => Face::PositiveZ,
+            Face::PositiveZ => Face::NegativeZ,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
-pub struct Volume<T: Copy + Display> {
+pub struct Volume<T: Copy> {
     pub size: Size, // Dimensions as a tuple
     pub data: Vec<T>,
 }
 
-impl<T: Copy + Display> Volume<T> {
+impl<T: Copy> Volume<T> {
     pub fn new(size: Size, initial_value: T) -> Self {
         let data = vec![initial_value; size.product()];
         Volume { size, data }
@@ -30,6 +185,10 @@ impl<T: Copy + Display> Volume<T> {
         (z * self.size.y + y) * self.size.x + x
     }
 
+    pub fn coord(&self, index: usize) -> Coord {
+        self.size.coord(index)
+    }
+
     // Get a copy of the value at specified coordinates
     pub fn get(&self, x: usize, y: usize, z: usize) -> T {
         let index = self.index(x, y, z);
@@ -42,6 +201,80 @@ impl<T: Copy + Display> Volume<T> {
         self.data[index] = value;
     }
 
+    pub fn iter_coords(&self) -> impl Iterator<Item = (Coord, &T)> {
+        self.size.coords().zip(self.data.iter())
+    }
+
+    pub fn iter_mut_coords(&mut self) -> impl Iterator<Item = (Coord, &mut T)> {
+        self.size.coords().zip(self.data.iter_mut())
+    }
+
+    // The in bounds face neighbors of a voxel, neighbors outside the volume are skipped.
+    pub fn face_neighbors(&self, coord: Coord) -> impl Iterator<Item = (Face, Coord)> {
+        let size = self.size;
+        Face::ALL.into_iter().filter_map(move |face| {
+            let (dx, dy, dz) = face.offset();
+            size.offset(coord, dx, dy, dz).map(|neighbor| (face, neighbor))
+        })
+    }
+
+    // The face neighbor of a voxel, or None if it lies on that boundary of the volume.
+    pub fn face_neighbor(&self, coord: Coord, face: Face) -> Option<Coord> {
+        let (dx, dy, dz) = face.offset();
+        self.size.offset(coord, dx, dy, dz)
+    }
+
+    // The face neighbor of a voxel, wrapping around to the opposite side at the boundary.
+    pub fn wrapped_face_neighbor(&self, coord: Coord, face: Face) -> Coord {
+        let wrap = |value: usize, length: usize, delta: isize| {
+            ((value as isize + delta).rem_euclid(length as isize)) as usize
+        };
+        let (dx, dy, dz) = face.offset();
+        Coord {
+            x: wrap(coord.x, self.size.x, dx),
+            y: wrap(coord.y, self.size.y, dy),
+            z: wrap(coord.z, self.size.z, dz),
+        }
+    }
+
+    // The in bounds face, edge and corner neighbors of a voxel.
+    pub fn neighbors_26(&self, coord: Coord) -> impl Iterator<Item = Coord> {
+        let size = self.size;
+        (0..27)
+            .filter(|&i| i != 13)
+            .filter_map(move |i| {
+                let dx = (i % 3) as isize - 1;
+                let dy = ((i / 3) % 3) as isize - 1;
+                let dz = (i / 9) as isize - 1;
+                size.offset(coord, dx, dy, dz)
+            })
+    }
+
+    pub fn map<U: Copy>(&self, f: impl Fn(T) -> U) -> Volume<U> {
+        Volume { size: self.size, data: self.data.iter().map(|&value| f(value)).collect() }
+    }
+
+    pub fn zip_map<U: Copy, V: Copy>(&self, other: &Volume<U>, f: impl Fn(T, U) -> V) -> Volume<V> {
+        assert_eq!(self.size, other.size, "zip_map requires volumes of the same size");
+        Volume {
+            size: self.size,
+            data: self.data.iter().zip(other.data.iter()).map(|(&a, &b)| f(a, b)).collect(),
+        }
+    }
+
+    // A view of the sub region starting at min with the given size.
+    pub fn region(&self, min: Coord, size: Size) -> VolumeView<'_, T> {
+        check_region(self.size, min, size);
+        VolumeView { volume: self, min, size }
+    }
+
+    pub fn region_mut(&mut self, min: Coord, size: Size) -> VolumeViewMut<'_, T> {
+        check_region(self.size, min, size);
+        VolumeViewMut { volume: self, min, size }
+    }
+}
+
+impl<T: Copy + Display> Volume<T> {
     // Function to visualize the volume with padding/truncation
     pub fn print(&self, length: usize) {
         let mut indent = " ".to_string();
@@ -60,3 +293,177 @@ impl<T: Copy + Display> Volume<T> {
         }
     }
 }
+
+fn check_region(bounds: Size, min: Coord, size: Size) {
+    assert!(
+        min.x + size.x <= bounds.x && min.y + size.y <= bounds.y && min.z + size.z <= bounds.z,
+        "region {:?} + {:?} is outside of volume {:?}", min, size, bounds
+    );
+}
+
+//  Read only window onto part of a Volume, coordinates are relative to min.
+pub struct VolumeView<'a, T: Copy> {
+    volume: &'a Volume<T>,
+    pub min: Coord,
+    pub size: Size,
+}
+
+impl<'a, T: Copy> VolumeView<'a, T> {
+    pub fn get(&self, x: usize, y: usize, z: usize) -> T {
+        self.volume.get(self.min.x + x, self.min.y + y, self.min.z + z)
+    }
+
+    pub fn iter_coords(&self) -> impl Iterator<Item = (Coord, T)> + '_ {
+        self.size.coords().map(move |c| (c, self.get(c.x, c.y, c.z)))
+    }
+
+    // Copies the viewed region out into its own Volume.
+    pub fn to_volume(&self) -> Volume<T> {
+        Volume { size: self.size, data: self.iter_coords().map(|(_, value)| value).collect() }
+    }
+}
+
+//  Mutable window onto part of a Volume, coordinates are relative to min.
+pub struct VolumeViewMut<'a, T: Copy> {
+    volume: &'a mut Volume<T>,
+    pub min: Coord,
+    pub size: Size,
+}
+
+impl<'a, T: Copy> VolumeViewMut<'a, T> {
+    pub fn get(&self, x: usize, y: usize, z: usize) -> T {
+        self.volume.get(self.min.x + x, self.min.y + y, self.min.z + z)
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, z: usize, value: T) {
+        self.volume.set(self.min.x + x, self.min.y + y, self.min.z + z, value);
+    }
+
+    pub fn fill(&mut self, value: T) {
+        for c in self.size.coords() {
+            self.set(c.x, c.y, c.z, value);
+        }
+    }
+
+    pub fn iter_coords(&self) -> impl Iterator<Item = (Coord, T)> + '_ {
+        self.size.coords().map(move |c| (c, self.get(c.x, c.y, c.z)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(x: usize, y: usize, z: usize) -> Size {
+        Size { x, y, z }
+    }
+
+    #[test]
+    fn iter_coords_follows_linear_index() {
+        let mut volume = Volume::new(size(3, 2, 4), 0usize);
+        for (coord, value) in volume.iter_mut_coords() {
+            *value = (coord.z * 2 + coord.y) * 3 + coord.x;
+        }
+        for (i, (coord, value)) in volume.iter_coords().enumerate() {
+            assert_eq!(*value, i);
+            assert_eq!(volume.size.index(coord), i);
+            assert_eq!(volume.coord(i), coord);
+        }
+    }
+
+    #[test]
+    fn face_neighbors_at_corners_edges_and_inside() {
+        let volume = Volume::new(size(3, 3, 3), 0u8);
+        let corner: Vec<(Face, Coord)> = volume.face_neighbors(Coord::new(0, 0, 0)).collect();
+        assert_eq!(corner, vec![
+            (Face::PositiveX, Coord::new(1, 0, 0)),
+            (Face::PositiveY, Coord::new(0, 1, 0)),
+            (Face::PositiveZ, Coord::new(0, 0, 1)),
+        ]);
+        let far_corner: Vec<Face> = volume.face_neighbors(Coord::new(2, 2, 2)).map(|(face, _)| face).collect();
+        assert_eq!(far_corner, vec![Face::NegativeX, Face::NegativeY, Face::NegativeZ]);
+        assert_eq!(volume.face_neighbors(Coord::new(1, 0, 0)).count(), 4);
+        assert_eq!(volume.face_neighbors(Coord::new(1, 1, 0)).count(), 5);
+        assert_eq!(volume.face_neighbors(Coord::new(1, 1, 1)).count(), 6);
+        for coord in volume.size.coords() {
+            for (face, neighbor) in volume.face_neighbors(coord) {
+                assert_eq!(volume.face_neighbor(neighbor, face.opposite()), Some(coord));
+            }
+        }
+    }
+
+    #[test]
+    fn face_neighbor_in_z_is_one_slab_away() {
+        //  the +z neighbor must be x * y voxels further on, not x + y.
+        let volume = Volume::new(size(4, 3, 2), 0u8);
+        let coord = Coord::new(1, 1, 0);
+        let above = volume.face_neighbor(coord, Face::PositiveZ).unwrap();
+        assert_eq!(above, Coord::new(1, 1, 1));
+        assert_eq!(volume.size.index(above) - volume.size.index(coord), 12);
+        assert_eq!(volume.face_neighbor(above, Face::PositiveZ), None);
+    }
+
+    #[test]
+    fn wrapped_face_neighbor_wraps_only_at_the_boundary() {
+        let volume = Volume::new(size(3, 2, 4), 0u8);
+        assert_eq!(volume.wrapped_face_neighbor(Coord::new(0, 0, 0), Face::NegativeX), Coord::new(2, 0, 0));
+        assert_eq!(volume.wrapped_face_neighbor(Coord::new(0, 1, 0), Face::PositiveY), Coord::new(0, 0, 0));
+        assert_eq!(volume.wrapped_face_neighbor(Coord::new(0, 0, 3), Face::PositiveZ), Coord::new(0, 0, 0));
+        assert_eq!(volume.wrapped_face_neighbor(Coord::new(1, 0, 1), Face::PositiveZ), Coord::new(1, 0, 2));
+    }
+
+    #[test]
+    fn neighbors_26_at_corners_edges_and_inside() {
+        let volume = Volume::new(size(3, 3, 3), 0u8);
+        assert_eq!(volume.neighbors_26(Coord::new(0, 0, 0)).count(), 7);
+        assert_eq!(volume.neighbors_26(Coord::new(1, 0, 0)).count(), 11);
+        assert_eq!(volume.neighbors_26(Coord::new(1, 1, 0)).count(), 17);
+        assert_eq!(volume.neighbors_26(Coord::new(1, 1, 1)).count(), 26);
+        let center = Coord::new(1, 1, 1);
+        assert!(volume.neighbors_26(center).all(|n| n != center));
+        //  a single voxel has no neighbors.
+        let single = Volume::new(size(1, 1, 1), 0u8);
+        assert_eq!(single.neighbors_26(Coord::new(0, 0, 0)).count(), 0);
+        assert_eq!(single.face_neighbors(Coord::new(0, 0, 0)).count(), 0);
+    }
+
+    #[test]
+    fn map_and_zip_map() {
+        let a = Volume::new(size(2, 2, 2), 3i32);
+        let b = a.map(|v| v as f32 * 0.5);
+        let c = a.zip_map(&b, |a, b| a as f32 + b);
+        assert!(c.data.iter().all(|&v| v == 4.5));
+    }
+
+    #[test]
+    fn regions_read_and_write_relative_to_min() {
+        let mut volume = Volume::new(size(4, 4, 4), 0u32);
+        for (coord, value) in volume.iter_mut_coords() {
+            *value = (coord.x + 10 * coord.y + 100 * coord.z) as u32;
+        }
+        let view = volume.region(Coord::new(1, 2, 3), size(2, 2, 1));
+        assert_eq!(view.get(0, 0, 0), 321);
+        assert_eq!(view.to_volume().data, vec![321, 322, 331, 332]);
+
+        volume.region_mut(Coord::new(2, 0, 0), size(2, 1, 1)).fill(7);
+        assert_eq!(volume.get(1, 0, 0), 1);
+        assert_eq!(volume.get(2, 0, 0), 7);
+        assert_eq!(volume.get(3, 0, 0), 7);
+        assert_eq!(volume.get(2, 1, 0), 12);
+    }
+
+    #[test]
+    #[should_panic]
+    fn region_outside_of_the_volume_panics() {
+        let volume = Volume::new(size(2, 2, 2), 0u8);
+        volume.region(Coord::new(1, 1, 1), size(2, 1, 1));
+    }
+
+    //  Volume no longer needs Display so it can hold enums.
+    #[test]
+    fn volume_of_enums() {
+        let mut faces = Volume::new(size(2, 1, 1), Face::NegativeX);
+        faces.set(1, 0, 0, Face::PositiveZ);
+        assert_eq!(faces.get(1, 0, 0), Face::PositiveZ);
+    }
+}
@@ -0,0 +1,560 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use bevy::math::IVec3;
+use bevy::utils::HashMap;
+use crate::physics::voxel_material_lookup::{UnknownMaterial, VoxelMaterialLookup};
+use crate::physics::*;
+
+//  Reading and writing MagicaVoxel .vox files.
+//  .vox files are z up and volumes are y up, so a .vox voxel (x, y, z) is volume voxel
+//  (x, z, -1 - y), a rotation about x which keeps both right handed.
+
+//  MagicaVoxel limits models to 256 voxels on a side.
+pub const MAX_MODEL_SIZE: usize = 256;
+
+const VERSION: i32 = 150;
+
+#[derive(Debug)]
+pub enum VoxError {
+    Io { path: PathBuf, error: std::io::Error },
+    NotVox,
+    Truncated,
+    InvalidChunk { id: String, reason: &'static str },
+    //  a palette index with no material in the table.
+    UnmappedIndex(u8),
+    //  a material with no palette index in the table.
+    UnmappedMaterial(MaterialId),
+    UnknownMaterial(UnknownMaterial),
+    TooLarge(Size),
+}
+
+impl fmt::Display for VoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VoxError::Io { path, error } => write!(f, "could not access {}: {}", path.display(), error),
+            VoxError::NotVox => write!(f, "not a .vox file"),
+            VoxError::Truncated => write!(f, ".vox data ends early"),
+            VoxError::InvalidChunk { id, reason } => write!(f, "invalid {} chunk, {}", id, reason),
+            VoxError::UnmappedIndex(index) => write!(f, "palette index {} has no material", index),
+            VoxError::UnmappedMaterial(id) => write!(f, "material {} has no palette index", id),
+            VoxError::UnknownMaterial(error) => write!(f, "{}", error),
+            VoxError::TooLarge(size) => {
+                write!(f, "{} x {} x {} is larger than the {} voxel model limit", size.x, size.y, size.z, MAX_MODEL_SIZE)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VoxError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VoxVoxel {
+    pub x: u8,
+    pub y: u8,
+    pub z: u8,
+    //  palette index, 1 to 255.
+    pub index: u8,
+}
+
+//  One model in .vox axes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxModel {
+    pub size: Size,
+    //  first voxel of the model in the scene, from the translations of the scene graph.
+    pub position: IVec3,
+    pub voxels: Vec<VoxVoxel>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxFile {
+    pub models: Vec<VoxModel>,
+    //  RGBA of palette index i at i - 1, None for MagicaVoxel's default palette.
+    pub palette: Option<[[u8; 4]; 256]>,
+}
+
+//  Maps palette indices to material ids and back.
+#[derive(Debug, Clone, Default)]
+pub struct VoxMaterialTable {
+    materials: HashMap<u8, MaterialId>,
+    indices: HashMap<MaterialId, u8>,
+}
+
+impl VoxMaterialTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //  A material mapped from several indices is written with the first of them.
+    //  Index 0 is empty in .vox files and cannot be mapped.
+    pub fn insert(&mut self, index: u8, id: MaterialId) {
+        assert!(index != 0, "palette index 0 is empty in .vox files");
+        self.materials.insert(index, id);
+        self.indices.entry(id).or_insert(index);
+    }
+
+    //  Table from (palette index, material name) pairs, index 0 is rejected as it is empty.
+    pub fn from_names(lookup: &VoxelMaterialLookup, names: &[(u8, &str)]) -> Result<Self, VoxError> {
+        let mut table = Self::new();
+        for &(index, name) in names {
+            if index == 0 {
+                return Err(VoxError::UnmappedIndex(0));
+            }
+            table.insert(index, lookup.try_id(name).map_err(VoxError::UnknownMaterial)?);
+        }
+        Ok(table)
+    }
+
+    //  Palette index id + 1 for each of the first 255 materials of the lookup.
+    pub fn by_id(lookup: &VoxelMaterialLookup) -> Self {
+        let mut table = Self::new();
+        for entry in lookup.iter().take(255) {
+            table.insert(entry.id as u8 + 1, entry.id);
+        }
+        table
+    }
+
+    pub fn material(&self, index: u8) -> Option<MaterialId> {
+        self.materials.get(&index).copied()
+    }
+
+    pub fn index(&self, id: MaterialId) -> Option<u8> {
+        self.indices.get(&id).copied()
+    }
+}
+
+fn extent(size: Size) -> IVec3 {
+    IVec3::new(size.x as i32, size.y as i32, size.z as i32)
+}
+
+impl VoxModel {
+    //  First voxel of the model in volume axes.
+    pub fn volume_position(&self) -> IVec3 {
+        IVec3::new(self.position.x, self.position.z, -self.position.y - self.size.y as i32)
+    }
+
+    pub fn volume_size(&self) -> Size {
+        Size { x: self.size.x, y: self.size.z, z: self.size.y }
+    }
+
+    fn write_to(&self, volume: &mut Volume<MaterialId>, offset: IVec3, table: &VoxMaterialTable) -> Result<(), VoxError> {
+        for v in self.voxels.iter() {
+            let id = table.material(v.index).ok_or(VoxError::UnmappedIndex(v.index))?;
+            let p = offset + IVec3::new(v.x as i32, v.z as i32, self.size.y as i32 - 1 - v.y as i32);
+            volume.set(p.x as usize, p.y as usize, p.z as usize, id);
+        }
+        Ok(())
+    }
+
+    pub fn to_volume(&self, table: &VoxMaterialTable, empty: MaterialId) -> Result<Volume<MaterialId>, VoxError> {
+        let mut volume = Volume::new(self.volume_size(), empty);
+        self.write_to(&mut volume, IVec3::ZERO, table)?;
+        Ok(volume)
+    }
+
+    //  Model of every voxel which is not empty, at position zero.
+    pub fn from_volume(volume: &Volume<MaterialId>, table: &VoxMaterialTable, empty: MaterialId) -> Result<Self, VoxError> {
+        let size = Size { x: volume.size.x, y: volume.size.z, z: volume.size.y };
+        if size.x > MAX_MODEL_SIZE || size.y > MAX_MODEL_SIZE || size.z > MAX_MODEL_SIZE {
+            return Err(VoxError::TooLarge(volume.size));
+        }
+        let mut voxels = Vec::new();
+        for (coord, &id) in volume.iter_coords() {
+            if id == empty {
+                continue;
+            }
+            let index = table.index(id).ok_or(VoxError::UnmappedMaterial(id))?;
+            voxels.push(VoxVoxel { x: coord.x as u8, y: (size.y - 1 - coord.z) as u8, z: coord.y as u8, index });
+        }
+        Ok(VoxModel { size, position: IVec3::ZERO, voxels })
+    }
+}
+
+impl VoxFile {
+    //  Every model in one volume and the position of its first voxel, later models
+    //  overwrite earlier ones where they overlap. None when there are no models.
+    pub fn to_volume(&self, table: &VoxMaterialTable, empty: MaterialId) -> Result<Option<(IVec3, Volume<MaterialId>)>, VoxError> {
+        let Some(first) = self.models.first() else {
+            return Ok(None);
+        };
+        let (mut min, mut max) = (first.volume_position(), first.volume_position() + extent(first.volume_size()));
+        for model in self.models.iter() {
+            min = min.min(model.volume_position());
+            max = max.max(model.volume_position() + extent(model.volume_size()));
+        }
+        let size = max - min;
+        let mut volume = Volume::new(Size { x: size.x as usize, y: size.y as usize, z: size.z as usize }, empty);
+        for model in self.models.iter() {
+            model.write_to(&mut volume, model.volume_position() - min, table)?;
+        }
+        Ok(Some((min, volume)))
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+struct Chunk<'a> {
+    id: [u8; 4],
+    content: &'a [u8],
+    children: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], VoxError> {
+        if self.bytes.len() < count {
+            return Err(VoxError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn i32(&mut self) -> Result<i32, VoxError> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    //  a count or size, which is never negative.
+    fn count(&mut self) -> Result<usize, VoxError> {
+        usize::try_from(self.i32()?).map_err(|_| VoxError::Truncated)
+    }
+
+    fn string(&mut self) -> Result<String, VoxError> {
+        let len = self.count()?;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+
+    fn dict(&mut self) -> Result<HashMap<String, String>, VoxError> {
+        (0 .. self.count()?).map(|_| Ok((self.string()?, self.string()?))).collect()
+    }
+
+    fn chunk(&mut self) -> Result<Chunk<'a>, VoxError> {
+        let id: [u8; 4] = self.take(4)?.try_into().unwrap();
+        let content = self.count()?;
+        let children = self.count()?;
+        Ok(Chunk { id, content: self.take(content)?, children: self.take(children)? })
+    }
+}
+
+fn invalid(id: &[u8; 4], reason: &'static str) -> VoxError {
+    VoxError::InvalidChunk { id: String::from_utf8_lossy(id).into_owned(), reason }
+}
+
+enum SceneNode {
+    Transform { child: i32, translation: IVec3 },
+    Group { children: Vec<i32> },
+    Shape { models: Vec<usize> },
+}
+
+fn parse_translation(text: &str) -> Option<IVec3> {
+    let mut parts = text.split_whitespace().map(|p| p.parse::<i32>().ok());
+    Some(IVec3::new(parts.next()??, parts.next()??, parts.next()??))
+}
+
+//  Adds up the translations from the root to each shape. Rotations are not supported.
+fn place_models(nodes: &HashMap<i32, SceneNode>, id: i32, translation: IVec3, depth: usize, models: &mut [VoxModel]) -> Result<(), VoxError> {
+    if depth > 64 {
+        return Err(invalid(b"nTRN", "the scene graph has a cycle"));
+    }
+    match nodes.get(&id) {
+        Some(SceneNode::Transform { child, translation: t }) => place_models(nodes, *child, translation + *t, depth + 1, models)?,
+        Some(SceneNode::Group { children }) => {
+            for &child in children {
+                place_models(nodes, child, translation, depth + 1, models)?;
+            }
+        }
+        Some(SceneNode::Shape { models: shapes }) => {
+            for &m in shapes {
+                let model = models.get_mut(m).ok_or_else(|| invalid(b"nSHP", "it names a missing model"))?;
+                //  translations are of the center voxel.
+                model.position = translation - extent(model.size) / 2;
+            }
+        }
+        None => return Err(invalid(b"nTRN", "it names a missing node")),
+    }
+    Ok(())
+}
+
+pub fn parse_vox(bytes: &[u8]) -> Result<VoxFile, VoxError> {
+    let mut reader = Reader { bytes };
+    if reader.take(4).map_err(|_| VoxError::NotVox)? != b"VOX " {
+        return Err(VoxError::NotVox);
+    }
+    reader.i32()?;
+    let main = reader.chunk()?;
+    if &main.id != b"MAIN" {
+        return Err(invalid(&main.id, "expected MAIN"));
+    }
+    let mut reader = Reader { bytes: main.children };
+    let mut models = Vec::new();
+    let mut palette = None;
+    let mut size = None;
+    let mut nodes = HashMap::new();
+    while !reader.bytes.is_empty() {
+        let Chunk { id, content, .. } = reader.chunk()?;
+        let mut content = Reader { bytes: content };
+        match &id {
+            b"SIZE" => {
+                let s = Size { x: content.count()?, y: content.count()?, z: content.count()? };
+                if s.x > MAX_MODEL_SIZE || s.y > MAX_MODEL_SIZE || s.z > MAX_MODEL_SIZE {
+                    return Err(VoxError::TooLarge(s));
+                }
+                size = Some(s);
+            }
+            b"XYZI" => {
+                let size = size.take().ok_or_else(|| invalid(&id, "it does not follow a SIZE chunk"))?;
+                let count = content.count()?;
+                let mut voxels = Vec::with_capacity(count.min(content.bytes.len() / 4));
+                for _ in 0 .. count {
+                    let v = content.take(4)?;
+                    if v[0] as usize >= size.x || v[1] as usize >= size.y || v[2] as usize >= size.z {
+                        return Err(invalid(&id, "a voxel is outside of the model"));
+                    }
+                    voxels.push(VoxVoxel { x: v[0], y: v[1], z: v[2], index: v[3] });
+                }
+                models.push(VoxModel { size, position: IVec3::ZERO, voxels });
+            }
+            b"RGBA" => {
+                let mut colors = [[0; 4]; 256];
+                for color in colors.iter_mut() {
+                    color.copy_from_slice(content.take(4)?);
+                }
+                palette = Some(colors);
+            }
+            b"nTRN" => {
+                let node = content.i32()?;
+                content.dict()?;
+                let child = content.i32()?;
+                content.i32()?;
+                content.i32()?;
+                let frames = content.count()?;
+                let translation = if frames > 0 {
+                    content.dict()?.get("_t").and_then(|t| parse_translation(t)).unwrap_or(IVec3::ZERO)
+                } else {
+                    IVec3::ZERO
+                };
+                nodes.insert(node, SceneNode::Transform { child, translation });
+            }
+            b"nGRP" => {
+                let node = content.i32()?;
+                content.dict()?;
+                let children = (0 .. content.count()?).map(|_| content.i32()).collect::<Result<_, _>>()?;
+                nodes.insert(node, SceneNode::Group { children });
+            }
+            b"nSHP" => {
+                let node = content.i32()?;
+                content.dict()?;
+                let mut shapes = Vec::new();
+                for _ in 0 .. content.count()? {
+                    shapes.push(content.count()?);
+                    content.dict()?;
+                }
+                nodes.insert(node, SceneNode::Shape { models: shapes });
+            }
+            //  materials, layers, cameras and the deprecated PACK are not needed.
+            _ => {}
+        }
+    }
+    if nodes.contains_key(&0) {
+        place_models(&nodes, 0, IVec3::ZERO, 0, &mut models)?;
+    }
+    Ok(VoxFile { models, palette })
+}
+
+pub fn load_vox(path: &Path) -> Result<VoxFile, VoxError> {
+    let bytes = std::fs::read(path).map_err(|error| VoxError::Io { path: path.to_owned(), error })?;
+    parse_vox(&bytes)
+}
+
+fn write_i32(out: &mut Vec<u8>, value: i32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_dict(out: &mut Vec<u8>, entries: &[(&str, &str)]) {
+    write_i32(out, entries.len() as i32);
+    for (key, value) in entries {
+        for text in [key, value] {
+            write_i32(out, text.len() as i32);
+            out.extend_from_slice(text.as_bytes());
+        }
+    }
+}
+
+fn write_chunk(out: &mut Vec<u8>, id: &[u8; 4], content: &[u8], children: &[u8]) {
+    out.extend_from_slice(id);
+    write_i32(out, content.len() as i32);
+    write_i32(out, children.len() as i32);
+    out.extend_from_slice(content);
+    out.extend_from_slice(children);
+}
+
+//  Writes the models with a scene graph of one group holding a translated shape per model,
+//  which is how MagicaVoxel lays out a scene.
+pub fn write_vox(file: &VoxFile) -> Result<Vec<u8>, VoxError> {
+    let mut children = Vec::new();
+    for model in file.models.iter() {
+        let s = model.size;
+        if s.x > MAX_MODEL_SIZE || s.y > MAX_MODEL_SIZE || s.z > MAX_MODEL_SIZE {
+            return Err(VoxError::TooLarge(s));
+        }
+        let mut content = Vec::new();
+        for n in [s.x, s.y, s.z] {
+            write_i32(&mut content, n as i32);
+        }
+        write_chunk(&mut children, b"SIZE", &content, &[]);
+        let mut content = Vec::new();
+        write_i32(&mut content, model.voxels.len() as i32);
+        for v in model.voxels.iter() {
+            content.extend_from_slice(&[v.x, v.y, v.z, v.index]);
+        }
+        write_chunk(&mut children, b"XYZI", &content, &[]);
+    }
+
+    //  node 0 is the root transform, 1 the group and each model a transform and a shape.
+    let transform = |out: &mut Vec<u8>, node: i32, child: i32, layer: i32, translation: Option<IVec3>| {
+        let mut content = Vec::new();
+        write_i32(&mut content, node);
+        write_dict(&mut content, &[]);
+        write_i32(&mut content, child);
+        write_i32(&mut content, -1);
+        write_i32(&mut content, layer);
+        write_i32(&mut content, 1);
+        match translation {
+            Some(t) => write_dict(&mut content, &[("_t", &format!("{} {} {}", t.x, t.y, t.z))]),
+            None => write_dict(&mut content, &[]),
+        }
+        write_chunk(out, b"nTRN", &content, &[]);
+    };
+    transform(&mut children, 0, 1, -1, None);
+    let mut content = Vec::new();
+    write_i32(&mut content, 1);
+    write_dict(&mut content, &[]);
+    write_i32(&mut content, file.models.len() as i32);
+    for i in 0 .. file.models.len() as i32 {
+        write_i32(&mut content, 2 + 2 * i);
+    }
+    write_chunk(&mut children, b"nGRP", &content, &[]);
+    for (i, model) in file.models.iter().enumerate() {
+        let node = 2 + 2 * i as i32;
+        transform(&mut children, node, node + 1, 0, Some(model.position + extent(model.size) / 2));
+        let mut content = Vec::new();
+        write_i32(&mut content, node + 1);
+        write_dict(&mut content, &[]);
+        write_i32(&mut content, 1);
+        write_i32(&mut content, i as i32);
+        write_dict(&mut content, &[]);
+        write_chunk(&mut children, b"nSHP", &content, &[]);
+    }
+
+    if let Some(palette) = &file.palette {
+        write_chunk(&mut children, b"RGBA", palette.concat().as_slice(), &[]);
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"VOX ");
+    write_i32(&mut out, VERSION);
+    write_chunk(&mut out, b"MAIN", &[], &children);
+    Ok(out)
+}
+
+pub fn save_vox(path: &Path, file: &VoxFile) -> Result<(), VoxError> {
+    let bytes = write_vox(file)?;
+    std::fs::write(path, bytes).map_err(|error| VoxError::Io { path: path.to_owned(), error })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup() -> VoxelMaterialLookup {
+        let mut lookup = VoxelMaterialLookup::new(0.1);
+        lookup.add(materials::AIR);
+        lookup.add(materials::ROCK);
+        lookup.add(materials::WATER);
+        lookup
+    }
+
+    //  A file holding one SIZE and XYZI chunk pair.
+    fn single_model(size: [i32; 3], count: i32, voxels: &[[u8; 4]]) -> Vec<u8> {
+        let mut children = Vec::new();
+        let mut content = Vec::new();
+        for n in size {
+            write_i32(&mut content, n);
+        }
+        write_chunk(&mut children, b"SIZE", &content, &[]);
+        let mut content = Vec::new();
+        write_i32(&mut content, count);
+        for v in voxels {
+            content.extend_from_slice(v);
+        }
+        write_chunk(&mut children, b"XYZI", &content, &[]);
+        let mut out = Vec::new();
+        out.extend_from_slice(b"VOX ");
+        write_i32(&mut out, VERSION);
+        write_chunk(&mut out, b"MAIN", &[], &children);
+        out
+    }
+
+    #[test]
+    fn files_round_trip() {
+        let mut palette = [[0; 4]; 256];
+        for (i, color) in palette.iter_mut().enumerate() {
+            *color = [i as u8, 255 - i as u8, (i * 7) as u8, 255];
+        }
+        let file = VoxFile {
+            models: vec![
+                VoxModel {
+                    size: Size { x: 4, y: 3, z: 2 },
+                    position: IVec3::new(-5, 7, 2),
+                    voxels: vec![VoxVoxel { x: 0, y: 0, z: 0, index: 1 }, VoxVoxel { x: 3, y: 2, z: 1, index: 200 }],
+                },
+                VoxModel {
+                    size: Size { x: 3, y: 3, z: 5 },
+                    position: IVec3::new(10, -3, 4),
+                    voxels: vec![VoxVoxel { x: 1, y: 1, z: 4, index: 5 }],
+                },
+            ],
+            palette: Some(palette),
+        };
+        assert_eq!(parse_vox(&write_vox(&file).unwrap()).unwrap(), file);
+    }
+
+    #[test]
+    fn volumes_round_trip_with_z_up() {
+        let lookup = lookup();
+        let table = VoxMaterialTable::from_names(&lookup, &[(1, "Rock"), (5, "Water")]).unwrap();
+        let (air, rock, water) = (lookup.id("Air"), lookup.id("Rock"), lookup.id("Water"));
+        let mut volume = Volume::new(Size { x: 5, y: 3, z: 4 }, air);
+        volume.set(0, 0, 0, rock);
+        volume.set(4, 2, 3, water);
+        volume.set(1, 2, 0, water);
+
+        let model = VoxModel::from_volume(&volume, &table, air).unwrap();
+        assert_eq!(model.size, Size { x: 5, y: 4, z: 3 });
+        //  volume y is .vox z and volume z runs along -y.
+        assert!(model.voxels.contains(&VoxVoxel { x: 0, y: 3, z: 0, index: 1 }));
+        assert!(model.voxels.contains(&VoxVoxel { x: 4, y: 0, z: 2, index: 5 }));
+        assert!(model.voxels.contains(&VoxVoxel { x: 1, y: 3, z: 2, index: 5 }));
+        assert_eq!(model.voxels.len(), 3);
+        assert_eq!(model.to_volume(&table, air).unwrap().data, volume.data);
+    }
+
+    #[test]
+    fn bad_voxel_data_is_rejected() {
+        let voxels = [[0, 0, 0, 1], [1, 1, 1, 1]];
+        assert!(parse_vox(&single_model([2, 2, 2], 2, &voxels)).is_ok());
+        assert!(matches!(parse_vox(&single_model([2, 2, 2], 3, &voxels)), Err(VoxError::Truncated)));
+        let outside = [[0, 0, 0, 1], [0, 2, 0, 1]];
+        assert!(matches!(parse_vox(&single_model([2, 2, 2], 2, &outside)), Err(VoxError::InvalidChunk { .. })));
+        let bytes = single_model([2, 2, 2], 2, &voxels);
+        assert!(matches!(parse_vox(&bytes[.. bytes.len() - 3]), Err(VoxError::Truncated)));
+        assert!(matches!(parse_vox(b"nope"), Err(VoxError::NotVox)));
+    }
+
+    #[test]
+    fn index_zero_cannot_be_mapped() {
+        let lookup = lookup();
+        assert!(matches!(VoxMaterialTable::from_names(&lookup, &[(0, "Rock")]), Err(VoxError::UnmappedIndex(0))));
+        assert!(matches!(VoxMaterialTable::from_names(&lookup, &[(2, "Lava")]), Err(VoxError::UnknownMaterial(_))));
+    }
+}
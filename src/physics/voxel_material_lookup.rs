@@ -1,22 +1,344 @@
+use std::fmt;
+use bevy::prelude::Resource;
 use bevy::utils::HashMap;
+use crate::physics::phase_change::PhaseChangeTable;
 use crate::physics::*;
 
+//  number of temperatures each property table is sampled at.
+const PROPERTY_TABLE_SIZE: usize = 256;
+
+//  Voxel properties from a material's property curves, sampled at evenly spaced
+//  temperatures so a lookup in the heat solver is an index and a lerp.
+//  Outside of the sampled range the first and last values are used.
+pub struct PropertyTable {
+    pub min: Temperature,
+    pub step: Temperature,
+    pub thermal_resistance: Vec<ThermalResistance>,
+    pub heat_capacity: Vec<HeatCapacity>,
+    pub mass: Vec<Mass>,
+    //  thermal energy ∫ heat_capacity dT from absolute zero at each sample.
+    pub energy: Vec<f64>,
+}
+
+impl PropertyTable {
+    pub fn new(mat: &PhysicsMaterial, length: Length) -> Option<Self> {
+        let (min, max) = mat.curves.range()?;
+        let count = if max > min { PROPERTY_TABLE_SIZE } else { 1 };
+        let step = if count > 1 { (max - min) / (count - 1) as Temperature } else { 0.0 };
+        let samples: Vec<VoxelMaterial> = (0 .. count)
+            .map(|i| mat.to_voxel_material_at(length, min + step * i as Temperature))
+            .collect();
+        let heat_capacity: Vec<HeatCapacity> = samples.iter().map(|m| m.heat_capacity).collect();
+        let mut energy = Vec::with_capacity(count);
+        energy.push(heat_capacity[0] as f64 * min as f64);
+        for i in 1 .. count {
+            let area = (heat_capacity[i - 1] + heat_capacity[i]) as f64 * 0.5 * step as f64;
+            energy.push(energy[i - 1] + area);
+        }
+        Some(PropertyTable {
+            min,
+            step,
+            thermal_resistance: samples.iter().map(|m| m.thermal_resistance).collect(),
+            heat_capacity,
+            mass: samples.iter().map(|m| m.mass).collect(),
+            energy,
+        })
+    }
+
+    //  index of the sample at or below temperature and the fraction towards the next one.
+    fn position(&self, temperature: Temperature) -> (usize, f32) {
+        let last = self.heat_capacity.len() - 1;
+        if last == 0 || temperature <= self.min {
+            return (0, 0.0);
+        }
+        let x = (temperature - self.min) / self.step;
+        if x >= last as f32 {
+            return (last, 0.0);
+        }
+        let i = x as usize;
+        (i, x - i as f32)
+    }
+
+    fn sample(&self, values: &[f32], temperature: Temperature) -> f32 {
+        let (i, f) = self.position(temperature);
+        if f == 0.0 { values[i] } else { values[i] + (values[i + 1] - values[i]) * f }
+    }
+
+    pub fn thermal_energy(&self, temperature: Temperature) -> f64 {
+        let last = self.heat_capacity.len() - 1;
+        if temperature <= self.min {
+            return self.heat_capacity[0] as f64 * temperature as f64;
+        }
+        let (i, _) = self.position(temperature);
+        let start = self.min + self.step * i as Temperature;
+        let end_capacity = if i == last { self.heat_capacity[last] } else { self.sample(&self.heat_capacity, temperature) };
+        self.energy[i] + (self.heat_capacity[i] + end_capacity) as f64 * 0.5 * (temperature - start) as f64
+    }
+
+    //  Inverse of thermal_energy, heat capacity is linear between samples so each
+    //  segment of energy is a quadratic in temperature.
+    pub fn temperature_at_energy(&self, energy: f64) -> Temperature {
+        let last = self.heat_capacity.len() - 1;
+        if energy <= self.energy[0] {
+            return (energy / self.heat_capacity[0] as f64) as Temperature;
+        }
+        let i = self.energy.partition_point(|&e| e <= energy) - 1;
+        let start = self.min as f64 + self.step as f64 * i as f64;
+        let c = self.heat_capacity[i] as f64;
+        let d = energy - self.energy[i];
+        if i == last {
+            return (start + d / c) as Temperature;
+        }
+        let slope = (self.heat_capacity[i + 1] - self.heat_capacity[i]) as f64 / self.step as f64;
+        //  d = c x + slope x² / 2, in the form which is stable as slope goes to zero.
+        (start + 2.0 * d / (c + (c * c + 2.0 * slope * d).max(0.0).sqrt())) as Temperature
+    }
+}
+
+//  A material name which is not in the lookup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownMaterial {
+    pub name: String,
+}
+
+impl fmt::Display for UnknownMaterial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown material \"{}\"", self.name)
+    }
+}
+
+impl std::error::Error for UnknownMaterial {}
+
+//  Everything the lookup holds for one material.
+#[derive(Debug, Clone, Copy)]
+pub struct MaterialEntry<'a> {
+    pub id: MaterialId,
+    pub name: &'static str,
+    pub physics: &'a PhysicsMaterial,
+    pub voxel: &'a VoxelMaterial,
+}
+
+#[derive(Resource)]
 pub struct VoxelMaterialLookup {
     pub length: Length,
     pub name_to_id: HashMap<&'static str, MaterialId>,
     pub materials: Vec<VoxelMaterial>,
+    //  the materials as they were added, indexed by id like materials.
+    pub physics: Vec<PhysicsMaterial>,
+    //  Some for materials with property curves.
+    pub tables: Vec<Option<PropertyTable>>,
+    //  added to the thermal energy of each material to give its enthalpy, see enthalpy.
+    pub enthalpy_offsets: Vec<f64>,
 }
 
 impl VoxelMaterialLookup {
     pub fn new(length: Length) -> VoxelMaterialLookup {
-        VoxelMaterialLookup { length, name_to_id: HashMap::new(), materials: Vec::new() }
+        VoxelMaterialLookup {
+            length,
+            name_to_id: HashMap::new(),
+            materials: Vec::new(),
+            physics: Vec::new(),
+            tables: Vec::new(),
+            enthalpy_offsets: Vec::new(),
+        }
+    }
+    //  Panics on an unknown name, use try_id for names which come from users or files.
+    pub fn id(&self, name: &str) -> MaterialId {
+        self.try_id(name).unwrap_or_else(|e| panic!("{}", e))
+    }
+    pub fn try_id(&self, name: &str) -> Result<MaterialId, UnknownMaterial> {
+        self.name_to_id.get(name).copied().ok_or_else(|| UnknownMaterial { name: name.to_owned() })
+    }
+    pub fn contains(&self, name: &str) -> bool {
+        self.name_to_id.contains_key(name)
+    }
+    pub fn name(&self, id: MaterialId) -> Option<&'static str> {
+        self.physics.get(id as usize).map(|mat| mat.name)
     }
-    pub fn id(&self, name: &'static str) -> MaterialId {
-        *self.name_to_id.get(name).unwrap()
+    pub fn physics_material(&self, id: MaterialId) -> Option<&PhysicsMaterial> {
+        self.physics.get(id as usize)
     }
+    pub fn entry(&self, id: MaterialId) -> Option<MaterialEntry<'_>> {
+        let physics = self.physics.get(id as usize)?;
+        Some(MaterialEntry { id, name: physics.name, physics, voxel: &self.materials[id as usize] })
+    }
+    //  Every material in id order.
+    pub fn iter(&self) -> impl Iterator<Item = MaterialEntry<'_>> {
+        self.physics.iter().zip(self.materials.iter()).enumerate()
+            .map(|(id, (physics, voxel))| MaterialEntry { id: id as MaterialId, name: physics.name, physics, voxel })
+    }
+    pub fn len(&self) -> usize {
+        self.materials.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.materials.is_empty()
+    }
+    //  Adds a material, replacing the name mapping of an earlier material with the same name.
     pub fn add(&mut self, mat: PhysicsMaterial) {
         let id = self.materials.len();
         self.name_to_id.insert(mat.name, id as MaterialId);
         self.materials.push(mat.to_voxel_material(self.length));
+        self.physics.push(mat);
+        self.tables.push(PropertyTable::new(&mat, self.length));
+        self.update_enthalpy_offsets();
+    }
+    //  Replaces the material with the same name, or adds it if there is none.
+    pub fn set(&mut self, mat: PhysicsMaterial) {
+        match self.name_to_id.get(mat.name) {
+            Some(&id) => {
+                self.materials[id as usize] = mat.to_voxel_material(self.length);
+                self.physics[id as usize] = mat;
+                self.tables[id as usize] = PropertyTable::new(&mat, self.length);
+                self.update_enthalpy_offsets();
+            }
+            None => self.add(mat),
+        }
+    }
+    //  Materials joined by phase transitions share a reference for their energy. Each
+    //  material's offset is chosen so that at a transition temperature the higher phase
+    //  holds exactly the latent heat more than the lower phase, the first material of
+    //  each group of connected materials is the reference for the group.
+    fn update_enthalpy_offsets(&mut self) {
+        let table = PhaseChangeTable::new(self);
+        //  (lower phase, higher phase, temperature, latent heat of a voxel)
+        let mut links = Vec::new();
+        for id in 0 .. self.materials.len() {
+            if let Some(up) = table.up[id] {
+                links.push((id, up.target as usize, up.temperature, up.latent_heat as f64));
+            }
+            if let Some(down) = table.down[id] {
+                links.push((down.target as usize, id, down.temperature, down.latent_heat as f64));
+            }
+        }
+        let mut offsets: Vec<Option<f64>> = vec![None; self.materials.len()];
+        for root in 0 .. offsets.len() {
+            if offsets[root].is_some() {
+                continue;
+            }
+            offsets[root] = Some(0.0);
+            let mut pending = vec![root];
+            while let Some(id) = pending.pop() {
+                let offset = offsets[id].unwrap();
+                for &(lower, upper, temperature, latent_heat) in links.iter() {
+                    //  enthalpy of upper at the transition = enthalpy of lower + latent heat.
+                    let gap = self.thermal_energy(lower as MaterialId, temperature) + latent_heat
+                        - self.thermal_energy(upper as MaterialId, temperature);
+                    let (other, other_offset) = if lower == id {
+                        (upper, offset + gap)
+                    } else if upper == id {
+                        (lower, offset - gap)
+                    } else {
+                        continue;
+                    };
+                    if offsets[other].is_none() && other_offset.is_finite() {
+                        offsets[other] = Some(other_offset);
+                        pending.push(other);
+                    }
+                }
+            }
+        }
+        self.enthalpy_offsets = offsets.into_iter().map(|offset| offset.unwrap_or(0.0)).collect();
+    }
+    //  True if any material has property curves, so properties must be looked up by temperature.
+    pub fn has_temperature_dependence(&self) -> bool {
+        self.tables.iter().any(|table| table.is_some())
+    }
+    //  The voxel material with its thermal resistance, heat capacity and mass at a temperature.
+    pub fn at_temperature(&self, id: MaterialId, temperature: Temperature) -> VoxelMaterial {
+        let mut mat = self.materials[id as usize];
+        if let Some(table) = &self.tables[id as usize] {
+            mat.thermal_resistance = table.sample(&table.thermal_resistance, temperature);
+            mat.heat_capacity = table.sample(&table.heat_capacity, temperature);
+            mat.mass = table.sample(&table.mass, temperature);
+        }
+        mat
+    }
+    pub fn heat_capacity(&self, id: MaterialId, temperature: Temperature) -> HeatCapacity {
+        match &self.tables[id as usize] {
+            Some(table) => table.sample(&table.heat_capacity, temperature),
+            None => self.materials[id as usize].heat_capacity,
+        }
+    }
+    //  Adds energy to a voxel at a temperature and returns its new temperature.
+    //  Materials with property curves integrate the heat capacity, so energy is conserved
+    //  however far the temperature moves.
+    pub fn add_energy(&self, id: MaterialId, temperature: Temperature, energy: f32) -> Temperature {
+        match &self.tables[id as usize] {
+            Some(table) => table.temperature_at_energy(table.thermal_energy(temperature) + energy as f64),
+            None => temperature + energy / self.materials[id as usize].heat_capacity,
+        }
+    }
+    //  Thermal energy of a voxel in Joules, heat_capacity * temperature without curves.
+    pub fn thermal_energy(&self, id: MaterialId, temperature: Temperature) -> f64 {
+        match &self.tables[id as usize] {
+            Some(table) => table.thermal_energy(temperature),
+            None => self.materials[id as usize].heat_capacity as f64 * temperature as f64,
+        }
+    }
+    //  Thermal energy plus the latent heat taken up reaching this phase, from a reference
+    //  shared by all the phases of a substance. Unlike thermal_energy it does not jump
+    //  when a voxel changes phase, so it is what energy accounting sums.
+    pub fn enthalpy(&self, id: MaterialId, temperature: Temperature) -> f64 {
+        self.thermal_energy(id, temperature) + self.enthalpy_offsets[id as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup() -> VoxelMaterialLookup {
+        let mut lookup = VoxelMaterialLookup::new(0.01);
+        lookup.add(materials::AIR);
+        lookup.add(materials::IRON);
+        lookup.add(materials::WATER);
+        lookup
+    }
+
+    #[test]
+    fn try_id_reports_unknown_names() {
+        let lookup = lookup();
+        assert_eq!(lookup.try_id("Iron"), Ok(1));
+        let error = lookup.try_id("Unobtainium").unwrap_err();
+        assert_eq!(error, UnknownMaterial { name: "Unobtainium".to_owned() });
+        assert_eq!(error.to_string(), "unknown material \"Unobtainium\"");
+        assert!(!lookup.contains("Unobtainium"));
+    }
+
+    #[test]
+    fn names_and_ids_round_trip() {
+        let lookup = lookup();
+        for name in ["Air", "Iron", "Water"] {
+            let id = lookup.id(name);
+            assert_eq!(lookup.name(id), Some(name));
+            let entry = lookup.entry(id).unwrap();
+            assert_eq!((entry.id, entry.name, entry.physics.name), (id, name, name));
+        }
+        assert_eq!(lookup.name(3), None);
+        assert!(lookup.entry(3).is_none());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn iter_is_in_id_order() {
+        let lookup = lookup();
+        let entries: Vec<_> = lookup.iter().map(|entry| (entry.id, entry.name)).collect();
+        assert_eq!(entries, vec![(0, "Air"), (1, "Iron"), (2, "Water")]);
+        assert_eq!(lookup.len(), 3);
+    }
+
+    #[test]
+    fn set_replaces_by_name_or_adds() {
+        let mut lookup = lookup();
+        let mut heavy = materials::IRON;
+        heavy.density = Density::from_grams_per_cubic_centimeter(10.0);
+        lookup.set(heavy);
+        assert_eq!(lookup.len(), 3);
+        assert_eq!(lookup.id("Iron"), 1);
+        assert_eq!(lookup.physics_material(1).unwrap().density, heavy.density);
+        assert_eq!(lookup.materials[1].mass, heavy.to_voxel_material(0.01).mass);
+
+        lookup.set(materials::ROCK);
+        assert_eq!(lookup.len(), 4);
+        assert_eq!(lookup.id("Rock"), 3);
+    }
+}
@@ -0,0 +1,344 @@
+use std::fmt::{self, Write as _};
+use std::io;
+use std::path::{Path, PathBuf};
+use bevy::math::Vec3;
+use crate::physics::*;
+
+//  Export of volumes for ParaView and other VTK readers.
+//  Voxels are written as cells of an image with the voxel length as spacing, so a field
+//  shows as blocks with sharp edges. Values are ASCII, which is slow to read for large
+//  volumes but is readable and needs no encoding.
+
+//  Volume values which can be written as VTK cell data.
+pub trait VtkValue: Copy {
+    const COMPONENTS: usize;
+    //  VTI type name.
+    const TYPE: &'static str;
+    //  legacy VTK type name.
+    const LEGACY_TYPE: &'static str;
+    fn write(&self, out: &mut String);
+    //  VTK readers parse ASCII values with C++ streams, which reject NaN and inf.
+    fn is_finite(&self) -> bool;
+}
+
+impl VtkValue for f32 {
+    const COMPONENTS: usize = 1;
+    const TYPE: &'static str = "Float32";
+    const LEGACY_TYPE: &'static str = "float";
+    fn write(&self, out: &mut String) {
+        write!(out, "{}", self).unwrap();
+    }
+    fn is_finite(&self) -> bool {
+        f32::is_finite(*self)
+    }
+}
+
+impl VtkValue for MaterialId {
+    const COMPONENTS: usize = 1;
+    const TYPE: &'static str = "UInt32";
+    const LEGACY_TYPE: &'static str = "unsigned_int";
+    fn write(&self, out: &mut String) {
+        write!(out, "{}", self).unwrap();
+    }
+    fn is_finite(&self) -> bool {
+        true
+    }
+}
+
+impl VtkValue for Vec3 {
+    const COMPONENTS: usize = 3;
+    const TYPE: &'static str = "Float32";
+    const LEGACY_TYPE: &'static str = "float";
+    fn write(&self, out: &mut String) {
+        write!(out, "{} {} {}", self.x, self.y, self.z).unwrap();
+    }
+    fn is_finite(&self) -> bool {
+        Vec3::is_finite(*self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VtkError {
+    //  names are written unquoted in legacy files and inside XML attributes, so only
+    //  letters, digits, '_', '-' and '.' are allowed.
+    InvalidName(String),
+    SizeMismatch { name: String, expected: Size, found: Size },
+    //  the index in the volume of the first NaN or infinite value.
+    NonFinite { name: String, index: usize },
+}
+
+impl fmt::Display for VtkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VtkError::InvalidName(name) => {
+                write!(f, "invalid field name \"{}\", use letters, digits, '_', '-' and '.'", name)
+            }
+            VtkError::SizeMismatch { name, expected, found } => write!(f,
+                "field {} is {} x {} x {}, the other fields are {} x {} x {}",
+                name, found.x, found.y, found.z, expected.x, expected.y, expected.z),
+            VtkError::NonFinite { name, index } => write!(f, "field {} has a non finite value at index {}", name, index),
+        }
+    }
+}
+
+impl std::error::Error for VtkError {}
+
+impl From<VtkError> for io::Error {
+    fn from(error: VtkError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VtkFormat {
+    //  .vtk STRUCTURED_POINTS, for older tools.
+    Legacy,
+    //  .vti XML ImageData, the only format a .pvd series can reference.
+    ImageData,
+}
+
+impl VtkFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            VtkFormat::Legacy => "vtk",
+            VtkFormat::ImageData => "vti",
+        }
+    }
+}
+
+struct Field {
+    name: String,
+    components: usize,
+    type_name: &'static str,
+    legacy_type: &'static str,
+    //  space separated values, x fastest.
+    values: String,
+}
+
+//  Named volumes of one size to be written to one file.
+pub struct VtkFields {
+    pub size: Size,
+    //  Meters
+    pub spacing: Length,
+    fields: Vec<Field>,
+}
+
+impl VtkFields {
+    pub fn new(size: Size, spacing: Length) -> Self {
+        VtkFields { size, spacing, fields: Vec::new() }
+    }
+
+    //  Checks the name, size and values first, so an error leaves the fields unchanged.
+    pub fn add<T: VtkValue>(&mut self, name: &str, volume: &Volume<T>) -> Result<&mut Self, VtkError> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.');
+        if name.is_empty() || !name.chars().all(valid) {
+            return Err(VtkError::InvalidName(name.to_owned()));
+        }
+        if volume.size != self.size {
+            return Err(VtkError::SizeMismatch { name: name.to_owned(), expected: self.size, found: volume.size });
+        }
+        if let Some(index) = volume.data.iter().position(|value| !value.is_finite()) {
+            return Err(VtkError::NonFinite { name: name.to_owned(), index });
+        }
+        let mut values = String::with_capacity(volume.data.len() * 8 * T::COMPONENTS);
+        for (i, value) in volume.data.iter().enumerate() {
+            if i > 0 {
+                values.push(' ');
+            }
+            value.write(&mut values);
+        }
+        self.fields.push(Field {
+            name: name.to_owned(),
+            components: T::COMPONENTS,
+            type_name: T::TYPE,
+            legacy_type: T::LEGACY_TYPE,
+            values,
+        });
+        Ok(self)
+    }
+
+    pub fn to_image_data(&self) -> String {
+        let Size { x, y, z } = self.size;
+        let s = self.spacing;
+        let mut out = String::new();
+        out.push_str("<?xml version=\"1.0\"?>\n");
+        out.push_str("<VTKFile type=\"ImageData\" version=\"0.1\" byte_order=\"LittleEndian\">\n");
+        writeln!(out, "  <ImageData WholeExtent=\"0 {} 0 {} 0 {}\" Origin=\"0 0 0\" Spacing=\"{} {} {}\">", x, y, z, s, s, s).unwrap();
+        writeln!(out, "    <Piece Extent=\"0 {} 0 {} 0 {}\">", x, y, z).unwrap();
+        out.push_str("      <CellData>\n");
+        for field in self.fields.iter() {
+            writeln!(out, "        <DataArray type=\"{}\" Name=\"{}\" NumberOfComponents=\"{}\" format=\"ascii\">",
+                field.type_name, field.name, field.components).unwrap();
+            writeln!(out, "          {}", field.values).unwrap();
+            out.push_str("        </DataArray>\n");
+        }
+        out.push_str("      </CellData>\n");
+        out.push_str("    </Piece>\n");
+        out.push_str("  </ImageData>\n");
+        out.push_str("</VTKFile>\n");
+        out
+    }
+
+    pub fn to_legacy(&self, title: &str) -> String {
+        let Size { x, y, z } = self.size;
+        let s = self.spacing;
+        let mut out = String::new();
+        out.push_str("# vtk DataFile Version 3.0\n");
+        //  the title is a single line.
+        writeln!(out, "{}", title.lines().next().unwrap_or("")).unwrap();
+        out.push_str("ASCII\nDATASET STRUCTURED_POINTS\n");
+        writeln!(out, "DIMENSIONS {} {} {}", x + 1, y + 1, z + 1).unwrap();
+        out.push_str("ORIGIN 0 0 0\n");
+        writeln!(out, "SPACING {} {} {}", s, s, s).unwrap();
+        writeln!(out, "CELL_DATA {}", self.size.product()).unwrap();
+        for field in self.fields.iter() {
+            if field.components == 3 {
+                writeln!(out, "VECTORS {} {}", field.name, field.legacy_type).unwrap();
+            } else {
+                writeln!(out, "SCALARS {} {} {}", field.name, field.legacy_type, field.components).unwrap();
+                out.push_str("LOOKUP_TABLE default\n");
+            }
+            writeln!(out, "{}", field.values).unwrap();
+        }
+        out
+    }
+
+    pub fn write(&self, path: &Path, format: VtkFormat) -> io::Result<()> {
+        let text = match format {
+            VtkFormat::Legacy => self.to_legacy("bevy_experiments volume"),
+            VtkFormat::ImageData => self.to_image_data(),
+        };
+        std::fs::write(path, text)
+    }
+}
+
+//  Numbered .vti files in a directory and a .pvd index of them which ParaView opens as
+//  one time series. The index is rewritten after every frame so a run which stops early
+//  can still be opened.
+pub struct VtkSeries {
+    pub directory: PathBuf,
+    pub name: String,
+    //  (time in seconds, file name) of each frame written.
+    pub frames: Vec<(f64, String)>,
+}
+
+impl VtkSeries {
+    //  Creates the directory if it does not exist.
+    pub fn new(directory: impl Into<PathBuf>, name: &str) -> io::Result<Self> {
+        let directory = directory.into();
+        std::fs::create_dir_all(&directory)?;
+        Ok(VtkSeries { directory, name: name.to_owned(), frames: Vec::new() })
+    }
+
+    pub fn index_path(&self) -> PathBuf {
+        self.directory.join(format!("{}.pvd", self.name))
+    }
+
+    //  Writes the next frame and returns its path.
+    pub fn write_frame(&mut self, time: f64, fields: &VtkFields) -> io::Result<PathBuf> {
+        let file_name = format!("{}_{:05}.{}", self.name, self.frames.len(), VtkFormat::ImageData.extension());
+        let path = self.directory.join(&file_name);
+        fields.write(&path, VtkFormat::ImageData)?;
+        self.frames.push((time, file_name));
+        std::fs::write(self.index_path(), self.to_pvd())?;
+        Ok(path)
+    }
+
+    pub fn to_pvd(&self) -> String {
+        let mut out = String::new();
+        out.push_str("<?xml version=\"1.0\"?>\n");
+        out.push_str("<VTKFile type=\"Collection\" version=\"0.1\" byte_order=\"LittleEndian\">\n");
+        out.push_str("  <Collection>\n");
+        for (time, file_name) in self.frames.iter() {
+            writeln!(out, "    <DataSet timestep=\"{}\" group=\"\" part=\"0\" file=\"{}\"/>", time, file_name).unwrap();
+        }
+        out.push_str("  </Collection>\n");
+        out.push_str("</VTKFile>\n");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> VtkFields {
+        let size = Size { x: 3, y: 2, z: 4 };
+        let mut fields = VtkFields::new(size, 0.5);
+        fields.add("temperature", &Volume::new(size, 300.0f32)).unwrap()
+            .add("material", &Volume::new(size, 2 as MaterialId)).unwrap()
+            .add("velocity", &Volume::new(size, Vec3::new(1.0, 0.0, -1.0))).unwrap();
+        fields
+    }
+
+    fn value_count(line: &str) -> usize {
+        line.split_whitespace().count()
+    }
+
+    #[test]
+    fn image_data_extents_and_components() {
+        let text = fields().to_image_data();
+        assert!(text.contains("<ImageData WholeExtent=\"0 3 0 2 0 4\" Origin=\"0 0 0\" Spacing=\"0.5 0.5 0.5\">"));
+        assert!(text.contains("<Piece Extent=\"0 3 0 2 0 4\">"));
+        assert!(text.contains("<DataArray type=\"Float32\" Name=\"temperature\" NumberOfComponents=\"1\" format=\"ascii\">"));
+        assert!(text.contains("<DataArray type=\"UInt32\" Name=\"material\" NumberOfComponents=\"1\" format=\"ascii\">"));
+        assert!(text.contains("<DataArray type=\"Float32\" Name=\"velocity\" NumberOfComponents=\"3\" format=\"ascii\">"));
+        let lines: Vec<&str> = text.lines().collect();
+        let values = |name: &str| {
+            let header = lines.iter().position(|line| line.contains(&format!("Name=\"{}\"", name))).unwrap();
+            value_count(lines[header + 1])
+        };
+        assert_eq!((values("temperature"), values("material"), values("velocity")), (24, 24, 72));
+    }
+
+    #[test]
+    fn legacy_dimensions_and_cell_data() {
+        let text = fields().to_legacy("first line\nsecond line");
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[1], "first line");
+        assert!(lines.contains(&"DIMENSIONS 4 3 5"));
+        assert!(lines.contains(&"CELL_DATA 24"));
+        let header = |start: &str| lines.iter().position(|line| line.starts_with(start)).unwrap();
+        assert_eq!(lines[header("SCALARS temperature")], "SCALARS temperature float 1");
+        assert_eq!(value_count(lines[header("SCALARS temperature") + 2]), 24);
+        assert_eq!(lines[header("SCALARS material")], "SCALARS material unsigned_int 1");
+        assert_eq!(lines[header("VECTORS")], "VECTORS velocity float");
+        assert_eq!(value_count(lines[header("VECTORS") + 1]), 72);
+    }
+
+    #[test]
+    fn add_rejects_what_would_corrupt_the_file() {
+        let size = Size { x: 2, y: 2, z: 2 };
+        let mut fields = VtkFields::new(size, 1.0);
+        let volume = Volume::new(size, 1.0f32);
+        for name in ["", "two words", "quote\"", "a<b", "tab\t"] {
+            assert_eq!(fields.add(name, &volume).err(), Some(VtkError::InvalidName(name.to_owned())));
+        }
+        let small = Size { x: 1, y: 2, z: 2 };
+        assert_eq!(fields.add("small", &Volume::new(small, 1.0f32)).err(),
+            Some(VtkError::SizeMismatch { name: "small".to_owned(), expected: size, found: small }));
+        for value in [f32::NAN, f32::INFINITY] {
+            let mut bad = volume.clone();
+            bad.set(1, 0, 1, value);
+            assert_eq!(fields.add("bad", &bad).err(), Some(VtkError::NonFinite { name: "bad".to_owned(), index: 5 }));
+        }
+        assert!(fields.add("heat_flux.x-1", &volume).is_ok());
+        assert_eq!(fields.to_legacy("").matches("SCALARS").count(), 1);
+    }
+
+    #[test]
+    fn series_index_lists_every_frame() {
+        let directory = std::env::temp_dir().join(format!("vtk_series_{}", std::process::id()));
+        let mut series = VtkSeries::new(&directory, "run").unwrap();
+        let fields = fields();
+        let first = series.write_frame(0.0, &fields).unwrap();
+        series.write_frame(2.5, &fields).unwrap();
+        assert_eq!(first, directory.join("run_00000.vti"));
+        let index = std::fs::read_to_string(series.index_path()).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(index, series.to_pvd());
+        assert!(index.contains("<DataSet timestep=\"0\" group=\"\" part=\"0\" file=\"run_00000.vti\"/>\n"));
+        assert!(index.contains("<DataSet timestep=\"2.5\" group=\"\" part=\"0\" file=\"run_00001.vti\"/>\n"));
+        assert_eq!(index.matches("<DataSet").count(), 2);
+    }
+}
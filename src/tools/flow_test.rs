@@ -1,12 +1,27 @@
 mod voxel_materials;
 
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
 use bevy_experiments::physics::*;
-use bevy_experiments::physics::heat_transfer::{apply_heat_to_volume, calculate_heat_transfer_volume};
-use bevy_experiments::physics::test::{fill_volume_with_test_material, fill_with_heat_source_and_sink};
+use bevy_experiments::physics::heat_simulation::HeatSimulation;
+use bevy_experiments::physics::implicit_heat_transfer::HeatIntegrator;
+use bevy_experiments::physics::radiation::Radiation;
+use bevy_experiments::physics::scenario::{load_scenario, Probe};
+use bevy_experiments::physics::test::{fill_volume_with_test_material, fill_with_heat_source, heat_sink_boundaries, heat_source_and_sink_boundaries};
+use bevy_experiments::physics::vtk::{VtkFields, VtkSeries};
+use bevy_experiments::physics::heatmap::*;
+use bevy_experiments::physics::save::{checkpoint, resume, Compression, SaveFile};
 use crate::voxel_materials::create_test_materials;
 
 // To automatically run and rerun this on changes:
 //  nodemon -w src -e rs -x "cargo run --bin flow_test"
+//  To write a ParaView time series and images every 1000 steps:
+//  cargo run --bin flow_test -- --format vtk,png --output output/flow_test --output-every 1000
+//  To run a scenario file instead of a built in scenario:
+//  cargo run --bin flow_test -- --scenario-file assets/scenarios/hot_iron_block.ron --format text,csv
+//  cargo run --bin flow_test -- --help lists every option.
 
 //  what are we going to simulate, something cool I hope.
 //  calculation of what fails/breaks where?
@@ -27,28 +42,458 @@ use crate::voxel_materials::create_test_materials;
 //  | X |   |   | X |   |   |   |   |   | X |
 //  -----------------------------------------
 
-fn main() {
-    let size = Size { x: 5, y: 4, z: 3 };
+const USAGE: &str = "\
+usage: flow_test [options]
+
+  --size X,Y,Z            voxels along each axis (5,4,3)
+  --length METERS         voxel edge length (4)
+  --dt SECONDS            time step (100)
+  --steps N               number of steps (10000), counted from the checkpoint with --resume
+  --end-time SECONDS      run until the simulation reaches this time, instead of --steps,
+                          with --resume this includes the time before the checkpoint
+  --scenario NAME         iron-wood: iron edges in wood between a cold -x and a hot +x face (default)
+                          source-sink: iron-wood with a tungsten source voxel and a cold -x face
+  --scenario-file PATH    a RON scenario, see assets/scenarios, which can not be combined with
+                          --scenario, --size or --length
+  --integrator NAME       explicit (default), backward-euler or crank-nicolson
+  --format LIST           comma separated outputs: text (default), csv, vtk, png
+  --output DIRECTORY      where csv, vtk and png outputs are written (output/flow_test)
+  --output-every N        steps between outputs, 0 for only the start and end (1000)
+  --checkpoint PATH       save the state at the end
+  --resume PATH           continue from a checkpoint of the same scenario, step numbers in the
+                          outputs start over from 0
+  --help                  show this message
+
+exit codes: 0 success, 1 the run failed, 2 invalid arguments";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scenario {
+    IronWood,
+    SourceSink,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stop {
+    Steps(usize),
+    EndTime(Time),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Formats {
+    text: bool,
+    csv: bool,
+    vtk: bool,
+    png: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Options {
+    size: Size,
+    length: Length,
+    time_delta: Time,
+    stop: Stop,
+    scenario: Scenario,
+    scenario_file: Option<PathBuf>,
+    integrator: HeatIntegrator,
+    formats: Formats,
+    output: PathBuf,
+    output_every: usize,
+    checkpoint: Option<PathBuf>,
+    resume: Option<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            size: Size { x: 5, y: 4, z: 3 },
+            length: 4.0,
+            time_delta: 100.0,
+            stop: Stop::Steps(10000),
+            scenario: Scenario::IronWood,
+            scenario_file: None,
+            integrator: HeatIntegrator::Explicit,
+            formats: Formats { text: true, ..Default::default() },
+            output: PathBuf::from("output/flow_test"),
+            output_every: 1000,
+            checkpoint: None,
+            resume: None,
+        }
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{} expects a number, got \"{}\"", name, value))
+}
+
+fn parse_positive(name: &str, value: &str) -> Result<f32, String> {
+    let number: f32 = parse_number(name, value)?;
+    if !(number.is_finite() && number > 0.0) {
+        return Err(format!("{} must be positive, got {}", name, value));
+    }
+    Ok(number)
+}
 
-    let lookup = create_test_materials();
-    let mut material: Volume<MaterialId> = Volume::new(size, 0);
-    let mut temperature: Volume<Temperature> = Volume::new(size, kelvin::ROOM_TEMPERATURE);
+//  None when --help was given.
+fn parse_args(args: &[String]) -> Result<Option<Options>, String> {
+    let mut options = Options::default();
+    let mut steps = None;
+    let mut end_time = None;
+    //  options which describe the built in scenario, a scenario file sets these itself.
+    let mut scenario_options = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--help" || arg == "-h" {
+            return Ok(None);
+        }
+        //  --name value or --name=value
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_owned())),
+            None => (arg.as_str(), None),
+        };
+        if !name.starts_with("--") {
+            return Err(format!("unexpected argument \"{}\"", arg));
+        }
+        //  a separate value which looks like an option means the value was left out.
+        let value = match inline {
+            Some(value) => value,
+            None => match args.next() {
+                Some(value) if !value.starts_with("--") => value.clone(),
+                _ => return Err(format!("{} expects a value", name)),
+            },
+        };
+        if matches!(name, "--size" | "--length" | "--scenario") {
+            scenario_options.push(name.to_owned());
+        }
+        match name {
+            "--size" => {
+                let parts = value.split(',').map(|p| parse_number::<usize>(name, p.trim())).collect::<Result<Vec<_>, _>>()?;
+                match parts[..] {
+                    [x, y, z] if x > 0 && y > 0 && z > 0 => options.size = Size { x, y, z },
+                    _ => return Err(format!("--size expects three positive numbers X,Y,Z, got \"{}\"", value)),
+                }
+            }
+            "--length" => options.length = parse_positive(name, &value)?,
+            "--dt" => options.time_delta = parse_positive(name, &value)?,
+            "--steps" => steps = Some(parse_number::<usize>(name, &value)?),
+            "--end-time" => end_time = Some(parse_positive(name, &value)?),
+            "--scenario" => {
+                options.scenario = match value.as_str() {
+                    "iron-wood" => Scenario::IronWood,
+                    "source-sink" => Scenario::SourceSink,
+                    _ => return Err(format!("unknown scenario \"{}\", expected iron-wood or source-sink", value)),
+                }
+            }
+            "--scenario-file" => options.scenario_file = Some(PathBuf::from(value)),
+            "--integrator" => {
+                options.integrator = match value.as_str() {
+                    "explicit" => HeatIntegrator::Explicit,
+                    "backward-euler" => HeatIntegrator::BackwardEuler,
+                    "crank-nicolson" => HeatIntegrator::CrankNicolson,
+                    _ => return Err(format!("unknown integrator \"{}\", expected explicit, backward-euler or crank-nicolson", value)),
+                }
+            }
+            "--format" => {
+                options.formats = Formats::default();
+                for format in value.split(',') {
+                    match format.trim() {
+                        "text" => options.formats.text = true,
+                        "csv" => options.formats.csv = true,
+                        "vtk" => options.formats.vtk = true,
+                        "png" => options.formats.png = true,
+                        other => return Err(format!("unknown format \"{}\", expected text, csv, vtk or png", other)),
+                    }
+                }
+            }
+            "--output" => options.output = PathBuf::from(value),
+            "--output-every" => options.output_every = parse_number(name, &value)?,
+            "--checkpoint" => options.checkpoint = Some(PathBuf::from(value)),
+            "--resume" => options.resume = Some(PathBuf::from(value)),
+            _ => return Err(format!("unknown option {}", name)),
+        }
+    }
+    if options.scenario_file.is_some() {
+        if let Some(name) = scenario_options.first() {
+            return Err(format!("{} can not be used with --scenario-file, the scenario file sets it", name));
+        }
+    }
+    options.stop = match (steps, end_time) {
+        (Some(_), Some(_)) => return Err("--steps and --end-time can not be used together".to_owned()),
+        (Some(steps), None) => Stop::Steps(steps),
+        (None, Some(time)) => Stop::EndTime(time),
+        (None, None) => options.stop,
+    };
+    Ok(Some(options))
+}
+
+//  The scenario file if one was given, otherwise the built in scenario.
+fn setup(options: &Options) -> Result<scenario::Scenario, Box<dyn std::error::Error>> {
+    if let Some(path) = &options.scenario_file {
+        return Ok(load_scenario(path)?);
+    }
+    let lookup = create_test_materials(options.length);
+    let mut material: Volume<MaterialId> = Volume::new(options.size, 0);
+    let mut temperature: Volume<Temperature> = Volume::new(options.size, kelvin::ROOM_TEMPERATURE);
     fill_volume_with_test_material(&mut material, &lookup);
-    fill_with_heat_source_and_sink(&mut material, &mut temperature, &lookup);
+    let boundaries = match options.scenario {
+        Scenario::IronWood => heat_source_and_sink_boundaries(),
+        Scenario::SourceSink => {
+            fill_with_heat_source(&mut material, &mut temperature, &lookup);
+            heat_sink_boundaries()
+        }
+    };
+    Ok(scenario::Scenario {
+        lookup,
+        material,
+        temperature,
+        boundaries,
+        radiation: Some(Radiation { ambient: kelvin::ROOM_TEMPERATURE }),
+        probes: Vec::new(),
+    })
+}
 
-    let mut heat: Volume<HeatTransferRate> = Volume::new(size, 0.0);
-    let time_delta = 100.0;
+fn vtk_fields(simulation: &HeatSimulation) -> std::io::Result<VtkFields> {
+    let mut fields = VtkFields::new(simulation.material.size, simulation.lookup.length);
+    fields.add("material", &simulation.material)?
+        .add("temperature", &simulation.temperature)?
+        .add("heat", &simulation.heat)?;
+    Ok(fields)
+}
 
-    for _i in 0 .. 10000 {
-        calculate_heat_transfer_volume(&material, &temperature, &mut heat, &lookup);
-        apply_heat_to_volume(&material, &mut temperature, &heat, &lookup, time_delta);
+fn write_images(simulation: &HeatSimulation, directory: &Path, frame: usize) -> std::io::Result<()> {
+    let z = simulation.material.size.z / 2;
+    let temperature = HeatmapOptions { colormap: Colormap::Inferno, ..Default::default() };
+    render_heatmap(&simulation.temperature, Axis::Z, z, &temperature)?
+        .save_png(&directory.join(format!("temperature_{:05}.png", frame)))?;
+    render_heatmap(&simulation.heat, Axis::Z, z, &HeatmapOptions::default())?
+        .save_png(&directory.join(format!("heat_{:05}.png", frame)))?;
+    render_materials(&simulation.material, Axis::Z, z, &MaterialColors::default(), 16, true)?
+        .save_png(&directory.join(format!("material_{:05}.png", frame)))
+}
+
+//  min, mean and max temperature.
+fn temperature_summary(temperature: &Volume<Temperature>) -> (Temperature, Temperature, Temperature) {
+    let (min, max, sum) = temperature.data.iter()
+        .fold((f32::INFINITY, f32::NEG_INFINITY, 0.0f64), |(min, max, sum), &t| (min.min(t), max.max(t), sum + t as f64));
+    (min, (sum / temperature.data.len() as f64) as Temperature, max)
+}
+
+struct Outputs {
+    formats: Formats,
+    directory: PathBuf,
+    csv: String,
+    vtk: Option<VtkSeries>,
+    probes: Vec<Probe>,
+    frames: usize,
+}
+
+impl Outputs {
+    fn new(options: &Options, probes: Vec<Probe>) -> std::io::Result<Self> {
+        let formats = options.formats;
+        if formats.csv || formats.vtk || formats.png {
+            std::fs::create_dir_all(&options.output)?;
+        }
+        let vtk = if formats.vtk { Some(VtkSeries::new(&options.output, "flow_test")?) } else { None };
+        let mut csv = "step,time,min_temperature,mean_temperature,max_temperature,total_energy".to_owned();
+        for probe in probes.iter() {
+            write!(csv, ",{}", probe.name).unwrap();
+        }
+        csv.push('\n');
+        Ok(Outputs { formats, directory: options.output.clone(), csv, vtk, probes, frames: 0 })
     }
 
-    println!("material\n");
-    material.print(10);
-    println!("temperature\n");
-    temperature.print(10);
-    println!("heat\n");
-    heat.print(10);
+    fn write(&mut self, step: usize, simulation: &HeatSimulation) -> std::io::Result<()> {
+        let (min, mean, max) = temperature_summary(&simulation.temperature);
+        if self.formats.text {
+            let mut line = format!("step {} time {}s temperature min {} mean {} max {}", step, simulation.time, min, mean, max);
+            for probe in self.probes.iter() {
+                write!(line, " {} {}", probe.name, probe.sample(&simulation.temperature)).unwrap();
+            }
+            println!("{}", line);
+        }
+        if self.formats.csv {
+            write!(self.csv, "{},{},{},{},{},{}", step, simulation.time, min, mean, max, simulation.total_energy()).unwrap();
+            for probe in self.probes.iter() {
+                write!(self.csv, ",{}", probe.sample(&simulation.temperature)).unwrap();
+            }
+            self.csv.push('\n');
+            std::fs::write(self.directory.join("flow_test.csv"), &self.csv)?;
+        }
+        if let Some(series) = &mut self.vtk {
+            series.write_frame(simulation.time, &vtk_fields(simulation)?)?;
+        }
+        if self.formats.png {
+            write_images(simulation, &self.directory, self.frames)?;
+        }
+        self.frames += 1;
+        Ok(())
+    }
+}
 
+fn run(options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    let scenario::Scenario { lookup, material, temperature, boundaries, radiation, probes } = setup(options)?;
+
+    //  the explicit integrator substeps automatically when time_delta is above the stability limit.
+    let mut simulation = HeatSimulation::new(material, temperature, &lookup, options.integrator, boundaries);
+    simulation.radiation = radiation;
+    simulation.enable_energy_diagnostics(1.0e-4);
+    if let Some(path) = &options.resume {
+        resume(&mut simulation, &SaveFile::load(path)?)?;
+        println!("resumed from {} at {}s", path.display(), simulation.time);
+    }
+    println!("max stable time step {}s", simulation.max_time_step());
+
+    let mut outputs = Outputs::new(options, probes)?;
+    outputs.write(0, &simulation)?;
+    let mut step = 0;
+    let mut substeps = 0;
+    let mut written = true;
+    loop {
+        let time = match options.stop {
+            Stop::Steps(steps) if step < steps => options.time_delta,
+            Stop::EndTime(end) if simulation.time < end as f64 => options.time_delta.min((end as f64 - simulation.time) as Time),
+            _ => break,
+        };
+        let report = simulation.step(time)?;
+        if let Some(warning) = report.warning {
+            eprintln!("flow_test: warning: {}", warning);
+        }
+        substeps += report.substeps;
+        step += 1;
+        written = options.output_every > 0 && step % options.output_every == 0;
+        if written {
+            outputs.write(step, &simulation)?;
+        }
+    }
+    if !written {
+        outputs.write(step, &simulation)?;
+    }
+    println!("{} steps reaching {}s took {} substeps", step, simulation.time, substeps);
+    if let Some(report) = simulation.energy_report() {
+        println!("{}\n", report);
+    }
+    if let Some(series) = &outputs.vtk {
+        println!("wrote {} frames to {}", series.frames.len(), series.index_path().display());
+    }
+    if let Some(path) = &options.checkpoint {
+        checkpoint(&simulation).save(path, Compression::Zstd(3))?;
+        println!("saved checkpoint to {}", path.display());
+    }
+
+    if options.formats.text {
+        let HeatSimulation { material, temperature, heat, .. } = simulation;
+        println!("material\n");
+        material.print(10);
+        println!("temperature\n");
+        temperature.print(10);
+        println!("heat\n");
+        heat.print(10);
+    }
+    Ok(())
+}
+
+const EXIT_INVALID_ARGUMENTS: u8 = 2;
+
+//  The options to run with, or the message to print and the exit code when there is nothing to run.
+fn options_or_exit(args: &[String]) -> Result<Options, (String, u8)> {
+    match parse_args(args) {
+        Ok(Some(options)) => Ok(options),
+        Ok(None) => Err((USAGE.to_owned(), 0)),
+        Err(message) => Err((format!("flow_test: {}\n\n{}", message, USAGE), EXIT_INVALID_ARGUMENTS)),
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = match options_or_exit(&args) {
+        Ok(options) => options,
+        Err((message, 0)) => {
+            println!("{}", message);
+            return ExitCode::SUCCESS;
+        }
+        Err((message, code)) => {
+            eprintln!("{}", message);
+            return ExitCode::from(code);
+        }
+    };
+    match run(&options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("flow_test: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_owned).collect()
+    }
+
+    fn error(line: &str) -> String {
+        parse_args(&args(line)).unwrap_err()
+    }
+
+    #[test]
+    fn good_arguments() {
+        let options = parse_args(&args("--size 6,5,4 --length=0.5 --dt 10 --end-time 3600 --scenario source-sink \
+            --integrator crank-nicolson --format csv,vtk --output out --output-every 0 --checkpoint end.sav"))
+            .unwrap().unwrap();
+        assert_eq!(options.size, Size { x: 6, y: 5, z: 4 });
+        assert_eq!((options.length, options.time_delta, options.stop), (0.5, 10.0, Stop::EndTime(3600.0)));
+        assert_eq!(options.scenario, Scenario::SourceSink);
+        assert_eq!(options.integrator, HeatIntegrator::CrankNicolson);
+        let Formats { text, csv, vtk, png } = options.formats;
+        assert_eq!((text, csv, vtk, png), (false, true, true, false));
+        assert_eq!((options.output, options.output_every), (PathBuf::from("out"), 0));
+        assert_eq!(options.checkpoint, Some(PathBuf::from("end.sav")));
+        assert_eq!(parse_args(&args("--steps 5")).unwrap().unwrap().stop, Stop::Steps(5));
+        assert!(parse_args(&args("")).unwrap().is_some());
+        assert!(parse_args(&args("--steps 5 --help")).unwrap().is_none());
+    }
+
+    #[test]
+    fn missing_values() {
+        assert_eq!(error("--steps"), "--steps expects a value");
+        assert_eq!(error("--output --help"), "--output expects a value");
+        assert_eq!(error("--dt --steps 5"), "--dt expects a value");
+        //  an inline value is taken as given.
+        assert_eq!(parse_args(&args("--output=--help")).unwrap().unwrap().output, PathBuf::from("--help"));
+    }
+
+    #[test]
+    fn unknown_and_conflicting_options() {
+        assert_eq!(error("--sise 1,2,3"), "unknown option --sise");
+        assert_eq!(error("steps"), "unexpected argument \"steps\"");
+        assert!(error("--scenario lava").starts_with("unknown scenario"));
+        assert!(error("--integrator euler").starts_with("unknown integrator"));
+        assert!(error("--format text,gif").starts_with("unknown format \"gif\""));
+        assert!(error("--steps 5 --end-time 10").contains("can not be used together"));
+        assert!(error("--scenario-file a.ron --size 1,2,3").contains("--scenario-file"));
+    }
+
+    #[test]
+    fn bad_numbers() {
+        assert_eq!(error("--steps ten"), "--steps expects a number, got \"ten\"");
+        assert_eq!(error("--steps -1"), "--steps expects a number, got \"-1\"");
+        assert_eq!(error("--dt 0"), "--dt must be positive, got 0");
+        assert_eq!(error("--length NaN"), "--length must be positive, got NaN");
+        assert!(error("--end-time -5").contains("must be positive"));
+        assert!(error("--size 1,2").starts_with("--size expects three positive numbers"));
+        assert!(error("--size 1,0,2").starts_with("--size expects three positive numbers"));
+    }
+
+    #[test]
+    fn exit_codes() {
+        assert!(options_or_exit(&args("--steps 5")).is_ok());
+        let (usage, code) = options_or_exit(&args("--help")).unwrap_err();
+        assert_eq!((usage.as_str(), code), (USAGE, 0));
+        for line in ["--steps", "--nope 1", "--dt x", "--output --help"] {
+            let (message, code) = options_or_exit(&args(line)).unwrap_err();
+            assert_eq!(code, EXIT_INVALID_ARGUMENTS, "{}", line);
+            assert!(message.starts_with("flow_test: ") && message.ends_with(USAGE));
+        }
+    }
 }
@@ -0,0 +1,64 @@
+mod voxel_materials;
+
+use std::time::Instant;
+use bevy_experiments::physics::*;
+use bevy_experiments::physics::heat_simulation::max_stable_time_step;
+use bevy_experiments::physics::heat_transfer::{apply_heat_to_volume, calculate_heat_transfer_volume};
+use bevy_experiments::physics::heat_transfer_kernel::HeatTransferKernel;
+use bevy_experiments::physics::test::{fill_volume_with_test_material, heat_source_and_sink_boundaries};
+use crate::voxel_materials::create_test_materials;
+
+// Compares the speed of the scalar heat transfer functions against the parallel kernel.
+//  cargo run --release --bin heat_benchmark
+//  heat_transfer_kernel's tests check that the two give the same results.
+
+const STEPS: usize = 10;
+
+fn main() {
+    for length in [32, 64, 128, 256] {
+        let size = Size { x: length, y: length, z: length };
+
+        let lookup = create_test_materials(4.0);
+        let mut material: Volume<MaterialId> = Volume::new(size, 0);
+        let temperature: Volume<Temperature> = Volume::new(size, kelvin::ROOM_TEMPERATURE);
+        fill_volume_with_test_material(&mut material, &lookup);
+        let boundaries = heat_source_and_sink_boundaries();
+        //  a stable step so the comparison is between two sensible results.
+        let time_delta = max_stable_time_step(&material, &lookup, &boundaries) * 0.9;
+
+        let mut scalar_temperature = temperature.clone();
+        let mut scalar_heat: Volume<HeatTransferRate> = Volume::new(size, 0.0);
+        let start = Instant::now();
+        for _i in 0 .. STEPS {
+            calculate_heat_transfer_volume(&material, &scalar_temperature, &mut scalar_heat, &lookup, &boundaries, None);
+            apply_heat_to_volume(&material, &mut scalar_temperature, &scalar_heat, &lookup, time_delta);
+        }
+        let scalar_time = start.elapsed();
+
+        let mut kernel_temperature = temperature.clone();
+        let mut kernel_heat: Volume<HeatTransferRate> = Volume::new(size, 0.0);
+        let start = Instant::now();
+        let kernel = HeatTransferKernel::new(&material, &lookup, &boundaries);
+        for _i in 0 .. STEPS {
+            kernel.step(&mut kernel_temperature, &mut kernel_heat, time_delta);
+        }
+        let kernel_time = start.elapsed();
+
+        let max_difference = scalar_temperature.data.iter()
+            .zip(kernel_temperature.data.iter())
+            .map(|(a, b)| (a - b).abs())
+            //  f32::max would drop a NaN.
+            .fold(0.0, |max: f32, difference| if difference.is_nan() || max.is_nan() { f32::NAN } else { max.max(difference) });
+
+        println!(
+            "{}^3 x {} steps of {}s: scalar {:?}, kernel {:?}, speedup {:.1}x, max difference {}",
+            length,
+            STEPS,
+            time_delta,
+            scalar_time,
+            kernel_time,
+            scalar_time.as_secs_f64() / kernel_time.as_secs_f64(),
+            max_difference,
+        );
+    }
+}
@@ -1,4 +1,5 @@
 pub mod string_utils;
 pub mod sparse_volume;
+pub mod octree;
 pub mod fps_display;
 pub mod mesh_builder;
@@ -0,0 +1,463 @@
+use bevy::math::{IVec3, Vec3};
+use crate::physics::{Face, Size, Volume};
+use super::sparse_volume::{self, SparseVolume};
+
+#[derive(Debug, Clone)]
+pub enum OctreeNode<T> {
+    //  every voxel of the node has this value.
+    Leaf(T),
+    //  children indexed by x | y << 1 | z << 2 of their half of the node.
+    Branch { children: Box<[OctreeNode<T>; 8]>, dominant: T },
+}
+
+impl<T: Copy> OctreeNode<T> {
+    //  The leaf value, or the most common value of a branch's voxels.
+    pub fn value(&self) -> T {
+        match self {
+            OctreeNode::Leaf(value) => *value,
+            OctreeNode::Branch { dominant, .. } => *dominant,
+        }
+    }
+
+    fn count(&self) -> usize {
+        match self {
+            OctreeNode::Leaf(_) => 1,
+            OctreeNode::Branch { children, .. } => 1 + children.iter().map(|c| c.count()).sum::<usize>(),
+        }
+    }
+}
+
+fn child_offset(index: usize, half: i32) -> IVec3 {
+    IVec3::new(index as i32 & 1, (index as i32 >> 1) & 1, (index as i32 >> 2) & 1) * half
+}
+
+//  Number of voxels of each value, in order of first appearance.
+type Histogram<T> = Vec<(T, u64)>;
+
+fn merge<T: PartialEq>(into: &mut Histogram<T>, from: Histogram<T>) {
+    for (value, count) in from {
+        match into.iter_mut().find(|(v, _)| *v == value) {
+            Some(entry) => entry.1 += count,
+            None => into.push((value, count)),
+        }
+    }
+}
+
+//  uniform returns the value of a region when it is known to be all one value,
+//  and must do so for single voxels.
+fn build<T: Copy + PartialEq>(min: IVec3, width: i32, uniform: &impl Fn(IVec3, i32) -> Option<T>) -> (OctreeNode<T>, Histogram<T>) {
+    if let Some(value) = uniform(min, width) {
+        return (OctreeNode::Leaf(value), vec![(value, (width as u64).pow(3))]);
+    }
+    let half = width / 2;
+    let mut histogram = Vec::new();
+    let children: [OctreeNode<T>; 8] = std::array::from_fn(|i| {
+        let (child, child_histogram) = build(min + child_offset(i, half), half, uniform);
+        merge(&mut histogram, child_histogram);
+        child
+    });
+    if let OctreeNode::Leaf(first) = children[0] {
+        if children.iter().all(|c| matches!(c, OctreeNode::Leaf(v) if *v == first)) {
+            return (OctreeNode::Leaf(first), histogram);
+        }
+    }
+    //  ties go to the value seen first.
+    let dominant = histogram.iter().fold(histogram[0], |best, &entry| if entry.1 > best.1 { entry } else { best }).0;
+    (OctreeNode::Branch { children: Box::new(children), dominant }, histogram)
+}
+
+fn depth_for(extent: i32) -> u32 {
+    let mut depth = 0;
+    while (1 << depth) < extent {
+        depth += 1;
+    }
+    depth
+}
+
+//  A ray hitting a node.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RayHit<T> {
+    //  first voxel of the hit node and its width in voxels, 1 unless the ray hit a larger
+    //  leaf or a level of detail above zero.
+    pub position: IVec3,
+    pub width: i32,
+    pub value: T,
+    //  along the normalized direction, zero when the ray starts inside the node.
+    pub distance: f32,
+    //  the face the ray entered through, None when it starts inside the node.
+    pub face: Option<Face>,
+}
+
+struct Ray {
+    origin: Vec3,
+    direction: Vec3,
+    inverse: Vec3,
+    max_distance: f32,
+}
+
+impl Ray {
+    //  entry and exit distance of the box and the axis it is entered through.
+    fn intersect(&self, min: IVec3, width: i32) -> Option<(f32, f32, usize)> {
+        let near = (min.as_vec3() - self.origin) * self.inverse;
+        let far = ((min + IVec3::splat(width)).as_vec3() - self.origin) * self.inverse;
+        let (mut enter, mut exit, mut axis) = (f32::NEG_INFINITY, f32::INFINITY, 0);
+        for a in 0 .. 3 {
+            //  min and max ignore the NaN of a ray parallel to and on a plane.
+            let (t0, t1) = (near[a].min(far[a]), near[a].max(far[a]));
+            if t0 > enter {
+                enter = t0;
+                axis = a;
+            }
+            exit = exit.min(t1);
+        }
+        (enter <= exit && exit >= 0.0 && enter <= self.max_distance).then_some((enter, exit, axis))
+    }
+}
+
+//  A cube of 2^depth voxels on a side where regions of one value are single leaves.
+//  Every branch also keeps the most common value below it, so coarser levels of detail
+//  can be read without visiting the leaves. Level of detail n treats nodes 2^n voxels
+//  wide as leaves, 0 is full resolution.
+#[derive(Debug, Clone)]
+pub struct Octree<T> {
+    pub root: OctreeNode<T>,
+    //  world position of the first voxel.
+    pub origin: IVec3,
+    pub depth: u32,
+    //  the value outside of the octree, also used to pad sources which are not cubes.
+    pub empty: T,
+}
+
+impl<T: Copy + PartialEq> Octree<T> {
+    pub fn width(&self) -> i32 {
+        1 << self.depth
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.root.count()
+    }
+
+    //  Octree of a dense volume with its first voxel at origin.
+    pub fn from_volume(volume: &Volume<T>, origin: IVec3, empty: T) -> Self {
+        let size = volume.size;
+        let extent = IVec3::new(size.x as i32, size.y as i32, size.z as i32);
+        let depth = depth_for(extent.max_element());
+        let root = build(IVec3::ZERO, 1 << depth, &|min: IVec3, width: i32| {
+            if width == 1 {
+                let inside = min.cmplt(extent).all();
+                Some(if inside { volume.get(min.x as usize, min.y as usize, min.z as usize) } else { empty })
+            } else if min.cmpge(extent).any() {
+                Some(empty)
+            } else {
+                None
+            }
+        }).0;
+        Octree { root, origin, depth, empty }
+    }
+
+    //  Octree of the occupied chunks, aligned to the chunk grid so chunks which are missing
+    //  or hold one value become single leaves.
+    pub fn from_sparse(sparse: &SparseVolume<T>) -> Self {
+        let empty = sparse.empty;
+        let chunk_width = sparse_volume::SIZE as i32;
+        let Some((min, max)) = sparse.bounds() else {
+            return Octree { root: OctreeNode::Leaf(empty), origin: IVec3::ZERO, depth: 0, empty };
+        };
+        let first = min.div_euclid(IVec3::splat(chunk_width));
+        let last = max.div_euclid(IVec3::splat(chunk_width));
+        let chunks = (last - first + IVec3::ONE).max_element();
+        let depth = depth_for(chunks) + depth_for(chunk_width);
+        let origin = first * chunk_width;
+        let root = build(origin, 1 << depth, &|min: IVec3, width: i32| {
+            if width == 1 {
+                return Some(sparse.get(min));
+            }
+            if width < chunk_width {
+                return None;
+            }
+            let key = min.div_euclid(IVec3::splat(chunk_width));
+            if width == chunk_width {
+                return sparse.chunks.get(&key).map_or(Some(empty), |chunk| chunk.uniform());
+            }
+            let count = width / chunk_width;
+            for z in 0 .. count {
+                for y in 0 .. count {
+                    for x in 0 .. count {
+                        if sparse.chunks.contains_key(&(key + IVec3::new(x, y, z))) {
+                            return None;
+                        }
+                    }
+                }
+            }
+            Some(empty)
+        }).0;
+        Octree { root, origin, depth, empty }
+    }
+
+    //  Value of the voxel at a world position, empty outside of the octree.
+    pub fn get(&self, position: IVec3) -> T {
+        self.get_lod(position, 0)
+    }
+
+    //  Value of the node at a level of detail containing a world position, the most common
+    //  value of its voxels when it is not a single leaf.
+    pub fn get_lod(&self, position: IVec3, lod: u32) -> T {
+        let local = position - self.origin;
+        let width = self.width();
+        if local.cmplt(IVec3::ZERO).any() || local.cmpge(IVec3::splat(width)).any() {
+            return self.empty;
+        }
+        let mut node = &self.root;
+        let mut min = IVec3::ZERO;
+        let mut width = width;
+        while let OctreeNode::Branch { children, dominant } = node {
+            if width <= 1 << lod {
+                return *dominant;
+            }
+            let half = width / 2;
+            let step = (local - min).cmpge(IVec3::splat(half));
+            let index = step.x as usize | (step.y as usize) << 1 | (step.z as usize) << 2;
+            min += child_offset(index, half);
+            width = half;
+            node = &children[index];
+        }
+        node.value()
+    }
+
+    //  Calls f with the first world position, width and value of every node at a level of detail.
+    pub fn for_each_node(&self, lod: u32, mut f: impl FnMut(IVec3, i32, T)) {
+        fn visit<T: Copy>(node: &OctreeNode<T>, min: IVec3, width: i32, lod: u32, f: &mut impl FnMut(IVec3, i32, T)) {
+            match node {
+                OctreeNode::Branch { children, .. } if width > 1 << lod => {
+                    let half = width / 2;
+                    for (i, child) in children.iter().enumerate() {
+                        visit(child, min + child_offset(i, half), half, lod, f);
+                    }
+                }
+                _ => f(min, width, node.value()),
+            }
+        }
+        visit(&self.root, self.origin, self.width(), lod, &mut f);
+    }
+
+    //  Dense copy of the region starting at min.
+    pub fn to_volume(&self, min: IVec3, size: Size) -> Volume<T> {
+        let mut volume = Volume::new(size, self.empty);
+        let extent = IVec3::new(size.x as i32, size.y as i32, size.z as i32);
+        self.for_each_node(0, |node_min, width, value| {
+            let start = (node_min - min).max(IVec3::ZERO);
+            let end = (node_min - min + IVec3::splat(width)).min(extent);
+            for z in start.z .. end.z {
+                for y in start.y .. end.y {
+                    for x in start.x .. end.x {
+                        volume.set(x as usize, y as usize, z as usize, value);
+                    }
+                }
+            }
+        });
+        volume
+    }
+
+    //  The whole octree as a dense volume with one voxel per node 2^lod voxels wide,
+    //  for rendering or solving at a coarser resolution.
+    pub fn to_volume_lod(&self, lod: u32) -> Volume<T> {
+        let lod = lod.min(self.depth);
+        let cells = (self.width() >> lod) as usize;
+        let mut volume = Volume::new(Size { x: cells, y: cells, z: cells }, self.empty);
+        self.for_each_node(lod, |node_min, width, value| {
+            let start = (node_min - self.origin) >> lod as i32;
+            let count = (width >> lod).max(1);
+            for z in start.z .. start.z + count {
+                for y in start.y .. start.y + count {
+                    for x in start.x .. start.x + count {
+                        volume.set(x as usize, y as usize, z as usize, value);
+                    }
+                }
+            }
+        });
+        volume
+    }
+
+    pub fn to_sparse(&self) -> SparseVolume<T> {
+        let mut sparse = SparseVolume::new(self.empty);
+        self.for_each_node(0, |min, width, value| {
+            if value == self.empty {
+                return;
+            }
+            for z in 0 .. width {
+                for y in 0 .. width {
+                    for x in 0 .. width {
+                        sparse.set(min + IVec3::new(x, y, z), value);
+                    }
+                }
+            }
+        });
+        sparse.compact();
+        sparse
+    }
+
+    //  First node at a level of detail whose value hit accepts along a ray in world voxel
+    //  units, where voxel p spans p to p + 1. Children are visited nearest first so whole
+    //  leaves of rejected values are skipped in one step.
+    pub fn raycast(&self, origin: Vec3, direction: Vec3, max_distance: f32, lod: u32, hit: impl Fn(T) -> bool) -> Option<RayHit<T>> {
+        let direction = direction.try_normalize()?;
+        let ray = Ray { origin, direction, inverse: direction.recip(), max_distance };
+        self.trace(&self.root, self.origin, self.width(), lod, &ray, &hit)
+    }
+
+    fn trace(&self, node: &OctreeNode<T>, min: IVec3, width: i32, lod: u32, ray: &Ray, hit: &impl Fn(T) -> bool) -> Option<RayHit<T>> {
+        let (enter, _, axis) = ray.intersect(min, width)?;
+        match node {
+            OctreeNode::Branch { children, .. } if width > 1 << lod => {
+                let half = width / 2;
+                let mut order: Vec<(f32, usize)> = (0 .. 8)
+                    .filter_map(|i| ray.intersect(min + child_offset(i, half), half).map(|(t, _, _)| (t, i)))
+                    .collect();
+                order.sort_by(|a, b| a.0.total_cmp(&b.0));
+                order.into_iter().find_map(|(_, i)| self.trace(&children[i], min + child_offset(i, half), half, lod, ray, hit))
+            }
+            _ => {
+                let value = node.value();
+                if !hit(value) {
+                    return None;
+                }
+                let face = (enter > 0.0).then(|| {
+                    let mut normal = Vec3::ZERO;
+                    normal[axis] = -ray.direction[axis];
+                    Face::from_direction(normal.x, normal.y, normal.z)
+                });
+                Some(RayHit { position: min, width, value, distance: enter.max(0.0), face })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cube(width: usize, value: u8) -> Volume<u8> {
+        Volume::new(Size { x: width, y: width, z: width }, value)
+    }
+
+    fn sorted(sparse: &SparseVolume<u8>) -> Vec<(IVec3, u8)> {
+        let mut voxels: Vec<_> = sparse.iter().collect();
+        voxels.sort_by_key(|&(p, _)| (p.z, p.y, p.x));
+        voxels
+    }
+
+    #[test]
+    fn volume_round_trip() {
+        let size = Size { x: 5, y: 3, z: 7 };
+        let mut volume = Volume::new(size, 0u8);
+        for (coord, value) in volume.iter_mut_coords() {
+            *value = ((coord.x + 2 * coord.y + 3 * coord.z) % 4) as u8;
+        }
+        let origin = IVec3::new(-9, 4, -2);
+        let octree = Octree::from_volume(&volume, origin, 0);
+        assert_eq!((octree.depth, octree.origin), (3, origin));
+        assert_eq!(octree.to_volume(origin, size).data, volume.data);
+        assert_eq!(octree.get(origin + IVec3::new(4, 2, 6)), volume.get(4, 2, 6));
+        assert_eq!(octree.get(origin - IVec3::ONE), 0);
+        assert_eq!(octree.get(origin + IVec3::new(5, 0, 0)), 0);
+    }
+
+    #[test]
+    fn sparse_round_trip() {
+        let mut sparse = SparseVolume::new(0u8);
+        for i in 0 .. 50 {
+            sparse.set(IVec3::new(i - 30, (i * 7) % 11 - 5, -(i % 3) * 9), i as u8 % 5 + 1);
+        }
+        let octree = Octree::from_sparse(&sparse);
+        assert_eq!(octree.origin, IVec3::new(-32, -16, -32));
+        assert_eq!(octree.origin % sparse_volume::SIZE as i32, IVec3::ZERO);
+        assert_eq!(sorted(&octree.to_sparse()), sorted(&sparse));
+        assert_eq!(Octree::from_sparse(&SparseVolume::new(0u8)).node_count(), 1);
+    }
+
+    #[test]
+    fn get_lod_returns_the_dominant_value() {
+        //  5 voxels of 1 and 3 of 2.
+        let mut volume = cube(2, 1);
+        for (x, y, z) in [(1, 0, 0), (0, 1, 0), (1, 1, 1)] {
+            volume.set(x, y, z, 2);
+        }
+        let octree = Octree::from_volume(&volume, IVec3::ZERO, 0);
+        assert_eq!(octree.get_lod(IVec3::ZERO, 0), 1);
+        assert_eq!(octree.get_lod(IVec3::new(1, 0, 0), 0), 2);
+        assert_eq!(octree.get_lod(IVec3::new(1, 0, 0), 1), 1);
+
+        //  a tie goes to the value of the first child.
+        for first in [1, 2] {
+            let mut volume = cube(2, 3 - first);
+            for y in 0 .. 2 {
+                for z in 0 .. 2 {
+                    volume.set(0, y, z, first);
+                }
+            }
+            let octree = Octree::from_volume(&volume, IVec3::ZERO, 0);
+            assert_eq!(octree.get_lod(IVec3::ONE, 1), first);
+        }
+    }
+
+    #[test]
+    fn uniform_regions_collapse() {
+        assert_eq!(Octree::from_volume(&cube(8, 3), IVec3::ZERO, 0).node_count(), 1);
+        //  one voxel differs, so one branch on each of the three levels.
+        let mut volume = cube(8, 3);
+        volume.set(5, 2, 3, 4);
+        assert_eq!(Octree::from_volume(&volume, IVec3::ZERO, 0).node_count(), 1 + 8 * 3);
+        //  padding 8x8x4 to a cube makes the upper octants single empty leaves.
+        let slab = Octree::from_volume(&Volume::new(Size { x: 8, y: 8, z: 4 }, 3u8), IVec3::ZERO, 0);
+        assert_eq!((slab.depth, slab.node_count()), (3, 9));
+        assert_eq!(slab.get(IVec3::new(7, 7, 4)), 0);
+    }
+
+    #[test]
+    fn to_volume_lod_halves_the_width() {
+        let octree = Octree::from_volume(&cube(6, 1), IVec3::ZERO, 0);
+        for (lod, cells) in [(0, 8), (1, 4), (2, 2), (3, 1), (9, 1)] {
+            assert_eq!(octree.to_volume_lod(lod).size, Size { x: cells, y: cells, z: cells });
+        }
+        let coarse = octree.to_volume_lod(1);
+        assert_eq!(coarse.get(0, 0, 0), 1);
+        assert_eq!(coarse.get(3, 3, 3), 0);
+    }
+
+    #[test]
+    fn raycast_hits() {
+        let origin = IVec3::new(-4, 0, 0);
+        let mut volume = cube(8, 0);
+        volume.set(5, 2, 3, 1);
+        volume.set(3, 3, 0, 2);
+        let octree = Octree::from_volume(&volume, origin, 0);
+        let solid = |value: u8| value != 0;
+
+        //  along +x through the middle of voxel (1, 2, 3) in world space.
+        let hit = octree.raycast(Vec3::new(-10.0, 2.5, 3.5), Vec3::X, 100.0, 0, solid).unwrap();
+        assert_eq!((hit.position, hit.width, hit.value, hit.face), (IVec3::new(1, 2, 3), 1, 1, Some(Face::NegativeX)));
+        assert!((hit.distance - 11.0).abs() < 1e-5);
+        //  and back along -x.
+        let hit = octree.raycast(Vec3::new(10.0, 2.5, 3.5), -Vec3::X, 100.0, 0, solid).unwrap();
+        assert_eq!((hit.position, hit.face), (IVec3::new(1, 2, 3), Some(Face::PositiveX)));
+        assert!((hit.distance - 8.0).abs() < 1e-5);
+
+        //  diagonally in the xy plane, the ray crosses x = 3 after y = 3 so it enters through -x.
+        let hit = octree.raycast(Vec3::new(-3.5, 0.7, 0.5), Vec3::new(1.0, 1.0, 0.0), 100.0, 0, solid).unwrap();
+        assert_eq!((hit.position, hit.value, hit.face), (IVec3::new(-1, 3, 0), 2, Some(Face::NegativeX)));
+        assert!((hit.distance - 2.5 * 2f32.sqrt()).abs() < 1e-5);
+
+        //  starting inside the voxel.
+        let hit = octree.raycast(Vec3::new(1.5, 2.5, 3.5), Vec3::Y, 100.0, 0, solid).unwrap();
+        assert_eq!((hit.position, hit.distance, hit.face), (IVec3::new(1, 2, 3), 0.0, None));
+        //  starting inside a large empty leaf which the ray accepts.
+        let hit = octree.raycast(Vec3::new(-3.5, 6.5, 6.5), Vec3::X, 100.0, 0, |_| true).unwrap();
+        assert_eq!((hit.position, hit.width, hit.face), (IVec3::new(-4, 4, 4), 4, None));
+
+        //  misses: past the voxel, pointing away, and stopping short.
+        assert_eq!(octree.raycast(Vec3::new(-10.0, 2.5, 4.5), Vec3::X, 100.0, 0, solid), None);
+        assert_eq!(octree.raycast(Vec3::new(-10.0, 2.5, 3.5), -Vec3::X, 100.0, 0, solid), None);
+        assert_eq!(octree.raycast(Vec3::new(-10.0, 2.5, 3.5), Vec3::X, 10.0, 0, solid), None);
+        assert_eq!(octree.raycast(Vec3::new(-10.0, 2.5, 3.5), Vec3::ZERO, 100.0, 0, solid), None);
+    }
+}
@@ -1,11 +1,729 @@
 use std::collections::HashMap;
+use std::fmt;
+use bevy::math::IVec3;
+use crate::physics::{Coord, Size, Volume};
 
 pub const SIZE: usize = 16;
+const CHUNK_VOLUME: usize = SIZE * SIZE * SIZE;
 
-pub struct SparseVolumeChunk {
-    pub data: [u8; SIZE * SIZE * SIZE],
+//  Packed palette indices, bits is 1, 2, 4, 8 or 16 so an index never straddles two words.
+#[derive(Debug, Clone)]
+struct PaletteData<T> {
+    palette: Vec<T>,
+    //  voxels using each palette entry, entries with a zero count are free for reuse.
+    counts: Vec<u16>,
+    bits: u32,
+    words: Vec<u64>,
 }
 
-pub struct SparseVolume {
-    pub chunks: HashMap<(u32, u32, u32), SparseVolumeChunk>,
+impl<T: Copy + PartialEq> PaletteData<T> {
+    fn new(palette: Vec<T>, counts: Vec<u16>, bits: u32) -> Self {
+        PaletteData { palette, counts, bits, words: vec![0; CHUNK_VOLUME * bits as usize / 64] }
+    }
+
+    //  smallest supported width which can index count entries.
+    fn bits_for(count: usize) -> u32 {
+        let mut bits = 1;
+        while (1 << bits) < count {
+            bits *= 2;
+        }
+        bits
+    }
+
+    fn index(&self, i: usize) -> usize {
+        let per_word = 64 / self.bits as usize;
+        let shift = (i % per_word) as u32 * self.bits;
+        ((self.words[i / per_word] >> shift) & ((1 << self.bits) - 1)) as usize
+    }
+
+    fn set_index(&mut self, i: usize, p: usize) {
+        let per_word = 64 / self.bits as usize;
+        let shift = (i % per_word) as u32 * self.bits;
+        let mask = ((1u64 << self.bits) - 1) << shift;
+        let word = &mut self.words[i / per_word];
+        *word = (*word & !mask) | ((p as u64) << shift);
+    }
+
+    fn repack(&mut self, bits: u32) {
+        let mut packed = PaletteData::<T>::new(Vec::new(), Vec::new(), bits);
+        for i in 0 .. CHUNK_VOLUME {
+            packed.set_index(i, self.index(i));
+        }
+        self.words = packed.words;
+        self.bits = bits;
+    }
+
+    fn find_or_insert(&mut self, value: T) -> usize {
+        if let Some(p) = self.palette.iter().zip(self.counts.iter()).position(|(&v, &c)| c > 0 && v == value) {
+            return p;
+        }
+        if let Some(p) = self.counts.iter().position(|&c| c == 0) {
+            self.palette[p] = value;
+            return p;
+        }
+        self.palette.push(value);
+        self.counts.push(0);
+        if self.palette.len() > 1 << self.bits {
+            self.repack(self.bits * 2);
+        }
+        self.palette.len() - 1
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ChunkStorage<T> {
+    //  every voxel has the same value so nothing is stored per voxel.
+    Uniform(T),
+    Palette(PaletteData<T>),
+}
+
+//  Memory used by one chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkMemory {
+    pub bytes: usize,
+    //  distinct values, 1 for a uniform chunk.
+    pub palette_length: usize,
+    //  bits per voxel, 0 for a uniform chunk.
+    pub bits: u32,
+}
+
+//  A SIZE³ block of voxels, x fastest like physics::Volume.
+//  Voxels are stored as indices into a palette of the distinct values with as few bits
+//  as the palette needs, and a chunk holding a single value stores only that value.
+#[derive(Debug, Clone)]
+pub struct SparseVolumeChunk<T> {
+    storage: ChunkStorage<T>,
+    //  number of voxels which are not the empty value.
+    occupied: usize,
+}
+
+impl<T: Copy + PartialEq> SparseVolumeChunk<T> {
+    pub fn new(empty: T) -> Self {
+        SparseVolumeChunk { storage: ChunkStorage::Uniform(empty), occupied: 0 }
+    }
+
+    pub fn index(x: usize, y: usize, z: usize) -> usize {
+        (z * SIZE + y) * SIZE + x
+    }
+
+    pub fn get(&self, index: usize) -> T {
+        match &self.storage {
+            ChunkStorage::Uniform(value) => *value,
+            ChunkStorage::Palette(data) => data.palette[data.index(index)],
+        }
+    }
+
+    //  Returns the previous value.
+    pub fn set(&mut self, index: usize, value: T, empty: T) -> T {
+        let previous = match &mut self.storage {
+            ChunkStorage::Uniform(uniform) => {
+                let uniform = *uniform;
+                if uniform != value {
+                    let mut data = PaletteData::new(vec![uniform, value], vec![CHUNK_VOLUME as u16 - 1, 1], 1);
+                    data.set_index(index, 1);
+                    self.storage = ChunkStorage::Palette(data);
+                }
+                uniform
+            }
+            ChunkStorage::Palette(data) => {
+                let old = data.index(index);
+                let previous = data.palette[old];
+                if previous != value {
+                    data.counts[old] -= 1;
+                    let new = data.find_or_insert(value);
+                    data.counts[new] += 1;
+                    data.set_index(index, new);
+                    if data.counts[new] as usize == CHUNK_VOLUME {
+                        self.storage = ChunkStorage::Uniform(value);
+                    }
+                }
+                previous
+            }
+        };
+        match (previous == empty, value == empty) {
+            (true, false) => self.occupied += 1,
+            (false, true) => self.occupied -= 1,
+            _ => {}
+        }
+        previous
+    }
+
+    pub fn occupied(&self) -> usize {
+        self.occupied
+    }
+
+    //  The value of every voxel when they are all the same.
+    pub fn uniform(&self) -> Option<T> {
+        match self.storage {
+            ChunkStorage::Uniform(value) => Some(value),
+            ChunkStorage::Palette(_) => None,
+        }
+    }
+
+    //  Every voxel value in index order.
+    pub fn values(&self) -> impl Iterator<Item = T> + '_ {
+        (0 .. CHUNK_VOLUME).map(move |i| self.get(i))
+    }
+
+    //  Drops unused palette entries and narrows the indices to match.
+    //  The palette only grows while editing, so call this after large edits.
+    pub fn compact(&mut self) {
+        let ChunkStorage::Palette(data) = &self.storage else {
+            return;
+        };
+        let live: Vec<usize> = (0 .. data.palette.len()).filter(|&p| data.counts[p] > 0).collect();
+        if live.len() == 1 {
+            self.storage = ChunkStorage::Uniform(data.palette[live[0]]);
+            return;
+        }
+        if live.len() == data.palette.len() && PaletteData::<T>::bits_for(live.len()) == data.bits {
+            return;
+        }
+        let mut remap = vec![0; data.palette.len()];
+        for (new, &old) in live.iter().enumerate() {
+            remap[old] = new;
+        }
+        let mut compacted = PaletteData::new(
+            live.iter().map(|&p| data.palette[p]).collect(),
+            live.iter().map(|&p| data.counts[p]).collect(),
+            PaletteData::<T>::bits_for(live.len()),
+        );
+        for i in 0 .. CHUNK_VOLUME {
+            compacted.set_index(i, remap[data.index(i)]);
+        }
+        self.storage = ChunkStorage::Palette(compacted);
+    }
+
+    pub fn memory(&self) -> ChunkMemory {
+        let own = std::mem::size_of::<Self>();
+        match &self.storage {
+            ChunkStorage::Uniform(_) => ChunkMemory { bytes: own, palette_length: 1, bits: 0 },
+            ChunkStorage::Palette(data) => ChunkMemory {
+                bytes: own
+                    + data.palette.capacity() * std::mem::size_of::<T>()
+                    + data.counts.capacity() * std::mem::size_of::<u16>()
+                    + data.words.capacity() * std::mem::size_of::<u64>(),
+                palette_length: data.counts.iter().filter(|&&c| c > 0).count(),
+                bits: data.bits,
+            },
+        }
+    }
+}
+
+//  Voxel values which can be written to the run length format, little endian.
+pub trait RleValue: Copy + PartialEq {
+    const BYTES: usize;
+    fn write(self, out: &mut Vec<u8>);
+    fn read(bytes: &[u8]) -> Self;
+}
+
+macro_rules! rle_value {
+    ($t:ty) => {
+        impl RleValue for $t {
+            const BYTES: usize = std::mem::size_of::<$t>();
+            fn write(self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+            fn read(bytes: &[u8]) -> Self {
+                <$t>::from_le_bytes(bytes.try_into().unwrap())
+            }
+        }
+    };
+}
+
+rle_value!(u8);
+rle_value!(u16);
+rle_value!(u32);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RleError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    //  the file was written with values of a different size.
+    ValueSize { expected: usize, found: usize },
+    Truncated,
+    //  runs which do not cover a chunk exactly or name a missing palette entry.
+    InvalidChunk(IVec3),
+    //  bytes left over after the last chunk.
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for RleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RleError::BadMagic => write!(f, "not a sparse volume, the magic bytes do not match"),
+            RleError::UnsupportedVersion(version) => write!(f, "unsupported sparse volume version {}", version),
+            RleError::ValueSize { expected, found } => write!(f, "expected {} byte voxel values, found {}", expected, found),
+            RleError::Truncated => write!(f, "sparse volume data ends early"),
+            RleError::InvalidChunk(key) => write!(f, "chunk {} has invalid runs", key),
+            RleError::TrailingBytes(count) => write!(f, "{} bytes after the last chunk", count),
+        }
+    }
+}
+
+impl std::error::Error for RleError {}
+
+const RLE_MAGIC: &[u8; 4] = b"SVRL";
+const RLE_VERSION: u8 = 1;
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], RleError> {
+        if self.bytes.len() < count {
+            return Err(RleError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, RleError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, RleError> {
+        Ok(u16::read(self.take(2)?))
+    }
+
+    fn u32(&mut self) -> Result<u32, RleError> {
+        Ok(u32::read(self.take(4)?))
+    }
+
+    fn i32(&mut self) -> Result<i32, RleError> {
+        Ok(self.u32()? as i32)
+    }
+
+    fn value<T: RleValue>(&mut self) -> Result<T, RleError> {
+        Ok(T::read(self.take(T::BYTES)?))
+    }
+}
+
+//  An unbounded voxel store made of SIZE³ chunks which only exist where a voxel is
+//  not the empty value. Coordinates are signed world voxel coordinates.
+#[derive(Debug, Clone)]
+pub struct SparseVolume<T> {
+    pub chunks: HashMap<IVec3, SparseVolumeChunk<T>>,
+    //  the value of every voxel outside of a chunk.
+    pub empty: T,
+}
+
+impl<T: Copy + PartialEq + Default> Default for SparseVolume<T> {
+    fn default() -> Self {
+        SparseVolume::new(T::default())
+    }
+}
+
+impl<T: Copy + PartialEq> SparseVolume<T> {
+    pub fn new(empty: T) -> Self {
+        SparseVolume { chunks: HashMap::new(), empty }
+    }
+
+    //  chunk coordinate and index within the chunk of a world position.
+    pub fn chunk_position(position: IVec3) -> (IVec3, usize) {
+        let size = SIZE as i32;
+        let chunk = position.div_euclid(IVec3::splat(size));
+        let local = position.rem_euclid(IVec3::splat(size));
+        (chunk, SparseVolumeChunk::<T>::index(local.x as usize, local.y as usize, local.z as usize))
+    }
+
+    pub fn get(&self, position: IVec3) -> T {
+        let (chunk, index) = Self::chunk_position(position);
+        self.chunks.get(&chunk).map_or(self.empty, |c| c.get(index))
+    }
+
+    //  Allocates the chunk on the first non empty voxel and frees it when its last one is emptied.
+    //  Returns the previous value.
+    pub fn set(&mut self, position: IVec3, value: T) -> T {
+        let (key, index) = Self::chunk_position(position);
+        let empty = self.empty;
+        match self.chunks.get_mut(&key) {
+            Some(chunk) => {
+                let previous = chunk.set(index, value, empty);
+                if chunk.occupied() == 0 {
+                    self.chunks.remove(&key);
+                }
+                previous
+            }
+            None if value == empty => empty,
+            None => {
+                let mut chunk = SparseVolumeChunk::new(empty);
+                chunk.set(index, value, empty);
+                self.chunks.insert(key, chunk);
+                empty
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    //  number of voxels which are not empty.
+    pub fn occupied(&self) -> usize {
+        self.chunks.values().map(|c| c.occupied()).sum()
+    }
+
+    //  Every voxel which is not empty, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (IVec3, T)> + '_ {
+        let empty = self.empty;
+        self.chunks.iter().flat_map(move |(key, chunk)| {
+            let origin = *key * SIZE as i32;
+            chunk.values().enumerate()
+                .filter(move |&(_, value)| value != empty)
+                .map(move |(index, value)| {
+                    let x = index % SIZE;
+                    let y = (index / SIZE) % SIZE;
+                    let z = index / (SIZE * SIZE);
+                    (origin + IVec3::new(x as i32, y as i32, z as i32), value)
+                })
+        })
+    }
+
+    //  Smallest and largest occupied positions, None when empty.
+    pub fn bounds(&self) -> Option<(IVec3, IVec3)> {
+        self.iter().fold(None, |bounds, (position, _)| match bounds {
+            None => Some((position, position)),
+            Some((min, max)) => Some((min.min(position), max.max(position))),
+        })
+    }
+
+    //  Copies a dense volume in with its first voxel at origin, empty voxels are skipped so
+    //  they leave what is already there.
+    pub fn insert_volume(&mut self, volume: &Volume<T>, origin: IVec3) {
+        for (coord, &value) in volume.iter_coords() {
+            if value != self.empty {
+                self.set(world_position(origin, coord), value);
+            }
+        }
+    }
+
+    pub fn from_volume(volume: &Volume<T>, origin: IVec3, empty: T) -> Self {
+        let mut sparse = SparseVolume::new(empty);
+        sparse.insert_volume(volume, origin);
+        sparse.compact();
+        sparse
+    }
+
+    //  Dense copy of the region starting at min.
+    pub fn to_volume(&self, min: IVec3, size: Size) -> Volume<T> {
+        let mut volume = Volume::new(size, self.empty);
+        for (coord, value) in volume.iter_mut_coords() {
+            *value = self.get(world_position(min, coord));
+        }
+        volume
+    }
+
+    //  Dense copy of the occupied bounds and the position of its first voxel, None when empty.
+    pub fn to_bounded_volume(&self) -> Option<(IVec3, Volume<T>)> {
+        let (min, max) = self.bounds()?;
+        let extent = max - min + IVec3::ONE;
+        let size = Size { x: extent.x as usize, y: extent.y as usize, z: extent.z as usize };
+        Some((min, self.to_volume(min, size)))
+    }
+
+    pub fn compact(&mut self) {
+        for chunk in self.chunks.values_mut() {
+            chunk.compact();
+        }
+    }
+
+    //  Memory of each chunk, not counting the hash map itself.
+    pub fn memory(&self) -> impl Iterator<Item = (IVec3, ChunkMemory)> + '_ {
+        self.chunks.iter().map(|(key, chunk)| (*key, chunk.memory()))
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        self.memory().map(|(_, memory)| memory.bytes).sum()
+    }
+}
+
+//  Run length format, all numbers little endian:
+//      "SVRL", version u8, value size u8, empty value, chunk count u32, then per chunk
+//      x y z i32, palette length u16, palette values, run count u16,
+//      runs of (length u16, palette index u8 or u16 when the palette has over 256 entries).
+//  Runs follow the chunk's x fastest voxel order and cover all of it.
+impl<T: RleValue> SparseVolume<T> {
+    pub fn to_rle_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(RLE_MAGIC);
+        out.push(RLE_VERSION);
+        out.push(T::BYTES as u8);
+        self.empty.write(&mut out);
+        (self.chunks.len() as u32).write(&mut out);
+        //  sorted so the same volume always writes the same bytes.
+        let mut keys: Vec<IVec3> = self.chunks.keys().copied().collect();
+        keys.sort_by_key(|k| (k.z, k.y, k.x));
+        for key in keys {
+            for c in [key.x, key.y, key.z] {
+                (c as u32).write(&mut out);
+            }
+            let mut palette: Vec<T> = Vec::new();
+            let mut runs: Vec<(u16, u16)> = Vec::new();
+            for value in self.chunks[&key].values() {
+                let p = match palette.iter().position(|&v| v == value) {
+                    Some(p) => p,
+                    None => {
+                        palette.push(value);
+                        palette.len() - 1
+                    }
+                } as u16;
+                match runs.last_mut() {
+                    Some((length, index)) if *index == p => *length += 1,
+                    _ => runs.push((1, p)),
+                }
+            }
+            (palette.len() as u16).write(&mut out);
+            for value in palette.iter() {
+                value.write(&mut out);
+            }
+            (runs.len() as u16).write(&mut out);
+            for (length, index) in runs {
+                length.write(&mut out);
+                if palette.len() > 256 { index.write(&mut out) } else { (index as u8).write(&mut out) }
+            }
+        }
+        out
+    }
+
+    pub fn from_rle_bytes(bytes: &[u8]) -> Result<Self, RleError> {
+        let mut reader = Reader { bytes };
+        if reader.take(4)? != RLE_MAGIC {
+            return Err(RleError::BadMagic);
+        }
+        let version = reader.u8()?;
+        if version != RLE_VERSION {
+            return Err(RleError::UnsupportedVersion(version));
+        }
+        let value_size = reader.u8()? as usize;
+        if value_size != T::BYTES {
+            return Err(RleError::ValueSize { expected: T::BYTES, found: value_size });
+        }
+        let mut volume = SparseVolume::new(reader.value::<T>()?);
+        let empty = volume.empty;
+        for _ in 0 .. reader.u32()? {
+            let key = IVec3::new(reader.i32()?, reader.i32()?, reader.i32()?);
+            let palette_length = reader.u16()? as usize;
+            let palette = (0 .. palette_length).map(|_| reader.value::<T>()).collect::<Result<Vec<T>, _>>()?;
+            let mut chunk = SparseVolumeChunk::new(empty);
+            let mut index = 0;
+            for _ in 0 .. reader.u16()? {
+                let length = reader.u16()? as usize;
+                let p = if palette_length > 256 { reader.u16()? as usize } else { reader.u8()? as usize };
+                if p >= palette_length || index + length > CHUNK_VOLUME {
+                    return Err(RleError::InvalidChunk(key));
+                }
+                for i in index .. index + length {
+                    chunk.set(i, palette[p], empty);
+                }
+                index += length;
+            }
+            if index != CHUNK_VOLUME {
+                return Err(RleError::InvalidChunk(key));
+            }
+            if chunk.occupied() > 0 {
+                chunk.compact();
+                volume.chunks.insert(key, chunk);
+            }
+        }
+        if !reader.bytes.is_empty() {
+            return Err(RleError::TrailingBytes(reader.bytes.len()));
+        }
+        Ok(volume)
+    }
+}
+
+//  World position of a voxel coordinate in a dense volume placed at origin.
+pub fn world_position(origin: IVec3, coord: Coord) -> IVec3 {
+    origin + IVec3::new(coord.x as i32, coord.y as i32, coord.z as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negative_positions_across_chunk_borders() {
+        let mut volume = SparseVolume::new(0u8);
+        let positions = [IVec3::new(-1, -1, -1), IVec3::new(0, 0, 0), IVec3::new(-16, 15, -17), IVec3::new(-17, 16, 0)];
+        for (i, &position) in positions.iter().enumerate() {
+            assert_eq!(volume.set(position, i as u8 + 1), 0);
+        }
+        for (i, &position) in positions.iter().enumerate() {
+            assert_eq!(volume.get(position), i as u8 + 1);
+        }
+        assert_eq!(SparseVolume::<u8>::chunk_position(IVec3::new(-1, -16, -17)).0, IVec3::new(-1, -1, -2));
+        assert_eq!(volume.chunks.len(), 4);
+        assert_eq!(volume.occupied(), 4);
+        assert_eq!(volume.get(IVec3::new(-2, -1, -1)), 0);
+        assert_eq!(volume.get(IVec3::new(1000, -1000, 0)), 0);
+    }
+
+    #[test]
+    fn bounds_cover_the_occupied_voxels() {
+        let mut volume = SparseVolume::new(0u8);
+        assert_eq!(volume.bounds(), None);
+        volume.set(IVec3::new(-20, 3, 5), 1);
+        volume.set(IVec3::new(4, -7, 40), 2);
+        assert_eq!(volume.bounds(), Some((IVec3::new(-20, -7, 5), IVec3::new(4, 3, 40))));
+        let (min, dense) = volume.to_bounded_volume().unwrap();
+        assert_eq!(min, IVec3::new(-20, -7, 5));
+        assert_eq!(dense.size, Size { x: 25, y: 11, z: 36 });
+        assert_eq!(dense.get(0, 10, 0), 1);
+        assert_eq!(dense.get(24, 0, 35), 2);
+    }
+
+    #[test]
+    fn insert_volume_skips_empty_voxels() {
+        let mut volume = SparseVolume::new(0u8);
+        volume.set(IVec3::new(-5, 0, 0), 7);
+        volume.set(IVec3::new(-4, 0, 0), 7);
+        let mut dense = Volume::new(Size { x: 2, y: 1, z: 1 }, 0u8);
+        dense.set(1, 0, 0, 3);
+        volume.insert_volume(&dense, IVec3::new(-5, 0, 0));
+        assert_eq!(volume.get(IVec3::new(-5, 0, 0)), 7);
+        assert_eq!(volume.get(IVec3::new(-4, 0, 0)), 3);
+        assert_eq!(volume.occupied(), 2);
+    }
+
+    #[test]
+    fn chunks_are_freed_when_emptied() {
+        let mut volume = SparseVolume::new(0u8);
+        volume.set(IVec3::new(-1, 0, 0), 1);
+        volume.set(IVec3::new(-2, 0, 0), 2);
+        volume.set(IVec3::new(16, 0, 0), 3);
+        assert_eq!(volume.chunks.len(), 2);
+        assert_eq!(volume.set(IVec3::new(-1, 0, 0), 0), 1);
+        assert_eq!(volume.chunks.len(), 2);
+        assert_eq!(volume.set(IVec3::new(-2, 0, 0), 0), 2);
+        assert_eq!(volume.chunks.len(), 1);
+        assert!(!volume.chunks.contains_key(&IVec3::new(-1, 0, 0)));
+        //  setting an empty voxel to empty does not allocate.
+        volume.set(IVec3::new(-100, 0, 0), 0);
+        assert_eq!(volume.chunks.len(), 1);
+        volume.set(IVec3::new(16, 0, 0), 0);
+        assert!(volume.is_empty());
+    }
+
+    #[test]
+    fn palette_width_grows_and_compacts() {
+        let mut chunk = SparseVolumeChunk::new(0u16);
+        assert_eq!(chunk.memory(), ChunkMemory { bytes: chunk.memory().bytes, palette_length: 1, bits: 0 });
+        let mut widths = Vec::new();
+        for i in 0 .. CHUNK_VOLUME {
+            chunk.set(i, (i % 300) as u16 + 1, 0);
+            widths.push(chunk.memory().bits);
+        }
+        widths.dedup();
+        assert_eq!(widths, vec![1, 2, 4, 8, 16]);
+        for i in 0 .. CHUNK_VOLUME {
+            assert_eq!(chunk.get(i), (i % 300) as u16 + 1);
+        }
+        //  down to three values, compacting narrows the indices without changing them.
+        for i in 0 .. CHUNK_VOLUME {
+            chunk.set(i, (i % 3) as u16, 0);
+        }
+        assert_eq!(chunk.memory().bits, 16);
+        chunk.compact();
+        assert_eq!(chunk.memory(), ChunkMemory { bytes: chunk.memory().bytes, palette_length: 3, bits: 2 });
+        assert!(chunk.values().enumerate().all(|(i, value)| value == (i % 3) as u16));
+        assert_eq!(chunk.occupied(), CHUNK_VOLUME - CHUNK_VOLUME.div_ceil(3));
+    }
+
+    #[test]
+    fn uniform_chunks_store_one_value() {
+        let mut chunk = SparseVolumeChunk::new(0u8);
+        chunk.set(0, 5, 0);
+        assert_eq!(chunk.uniform(), None);
+        for i in 0 .. CHUNK_VOLUME {
+            chunk.set(i, 5, 0);
+        }
+        assert_eq!(chunk.uniform(), Some(5));
+        assert_eq!(chunk.memory().bits, 0);
+        assert_eq!(chunk.occupied(), CHUNK_VOLUME);
+
+        //  a full chunk is a single run.
+        let mut volume = SparseVolume::new(0u8);
+        volume.chunks.insert(IVec3::new(-1, 0, 2), chunk);
+        let bytes = volume.to_rle_bytes();
+        let header = 4 + 1 + 1 + 1 + 4;
+        assert_eq!(bytes.len(), header + 12 + 2 + 1 + 2 + 3);
+        let read = SparseVolume::<u8>::from_rle_bytes(&bytes).unwrap();
+        assert_eq!(read.chunks[&IVec3::new(-1, 0, 2)].uniform(), Some(5));
+    }
+
+    fn sample_volume() -> SparseVolume<u16> {
+        let mut volume = SparseVolume::new(0u16);
+        for i in 0 .. 400 {
+            volume.set(IVec3::new(i - 200, (i * 7) % 33 - 16, -i / 3), i as u16 + 1);
+        }
+        for i in 0 .. CHUNK_VOLUME as i32 {
+            volume.set(IVec3::new(i % 16, (i / 16) % 16, i / 256) + IVec3::new(64, 0, 0), 9);
+        }
+        volume
+    }
+
+    #[test]
+    fn rle_round_trip() {
+        let volume = sample_volume();
+        let bytes = volume.to_rle_bytes();
+        let read = SparseVolume::<u16>::from_rle_bytes(&bytes).unwrap();
+        assert_eq!(read.empty, 0);
+        assert_eq!(read.occupied(), volume.occupied());
+        let mut expected: Vec<_> = volume.iter().collect();
+        let mut found: Vec<_> = read.iter().collect();
+        expected.sort_by_key(|&(p, _)| (p.z, p.y, p.x));
+        found.sort_by_key(|&(p, _)| (p.z, p.y, p.x));
+        assert_eq!(found, expected);
+        assert_eq!(read.to_rle_bytes(), bytes);
+    }
+
+    #[test]
+    fn rle_rejects_bad_input() {
+        let mut small = SparseVolume::new(0u16);
+        small.set(IVec3::new(-1, 2, 3), 300);
+        small.set(IVec3::new(20, 2, 3), 7);
+        let bytes = small.to_rle_bytes();
+        for length in 0 .. bytes.len() {
+            assert!(SparseVolume::<u16>::from_rle_bytes(&bytes[.. length]).is_err(), "prefix of {} bytes", length);
+        }
+        let mut trailing = bytes.clone();
+        trailing.extend_from_slice(&[0, 0]);
+        assert_eq!(SparseVolume::<u16>::from_rle_bytes(&trailing).unwrap_err(), RleError::TrailingBytes(2));
+
+        let mut magic = bytes.clone();
+        magic[0] = b'X';
+        assert_eq!(SparseVolume::<u16>::from_rle_bytes(&magic).unwrap_err(), RleError::BadMagic);
+        let mut version = bytes.clone();
+        version[4] = 9;
+        assert_eq!(SparseVolume::<u16>::from_rle_bytes(&version).unwrap_err(), RleError::UnsupportedVersion(9));
+        assert_eq!(SparseVolume::<u8>::from_rle_bytes(&bytes).unwrap_err(), RleError::ValueSize { expected: 1, found: 2 });
+
+        //  one chunk at the origin holding a palette of one value.
+        let chunk = |runs: &[(u16, u8)]| {
+            let mut out = Vec::new();
+            out.extend_from_slice(RLE_MAGIC);
+            out.extend_from_slice(&[RLE_VERSION, 1, 0]);
+            1u32.write(&mut out);
+            out.extend_from_slice(&[0; 12]);
+            1u16.write(&mut out);
+            out.push(4);
+            (runs.len() as u16).write(&mut out);
+            for &(length, index) in runs {
+                length.write(&mut out);
+                out.push(index);
+            }
+            SparseVolume::<u8>::from_rle_bytes(&out)
+        };
+        assert_eq!(chunk(&[(4096, 0)]).unwrap().occupied(), CHUNK_VOLUME);
+        assert_eq!(chunk(&[(4096, 1)]).unwrap_err(), RleError::InvalidChunk(IVec3::ZERO));
+        assert_eq!(chunk(&[(4000, 0)]).unwrap_err(), RleError::InvalidChunk(IVec3::ZERO));
+        assert_eq!(chunk(&[(4000, 0), (200, 0)]).unwrap_err(), RleError::InvalidChunk(IVec3::ZERO));
+    }
 }